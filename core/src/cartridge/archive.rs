@@ -0,0 +1,164 @@
+// Reading of ROMs stored in ZIP, 7z and gzip archives.
+
+use flate2::read::GzDecoder;
+use std::fs::File;
+use std::io::{self, Cursor, Read};
+use std::path::Path;
+
+type Result<T> = std::result::Result<T, Box<dyn std::error::Error>>;
+
+const ROM_EXTENSIONS: [&str; 3] = ["gb", "gbc", "sgb"];
+
+// The largest size a cartridge header can declare
+const MAX_ROM_SIZE: u64 = 8 * 1024 * 1024;
+
+/// Reads a ROM file, uncompressing it if it is an archive. Archives holding
+/// several files use the named entry, or the first Game Boy ROM.
+pub fn read_rom(path: &Path, entry: Option<&str>) -> Result<Vec<u8>> {
+    let mut data = Vec::new();
+    File::open(path)?.read_to_end(&mut data)?;
+
+    uncompress(data, entry)
+}
+
+// Archives are detected from their signature, anything else is a plain ROM
+pub fn uncompress(data: Vec<u8>, entry: Option<&str>) -> Result<Vec<u8>> {
+    if data.starts_with(b"PK\x03\x04") {
+        read_zip(data, entry)
+    } else if data.starts_with(b"7z\xBC\xAF\x27\x1C") {
+        read_7z(data, entry)
+    } else if data.starts_with(&[0x1F, 0x8B]) {
+        let mut rom = Vec::new();
+        GzDecoder::new(&data[..]).read_to_end(&mut rom)?;
+        Ok(rom)
+    } else {
+        Ok(data)
+    }
+}
+
+fn is_rom(name: &str) -> bool {
+    Path::new(name)
+        .extension()
+        .and_then(|ext| ext.to_str())
+        .is_some_and(|ext| ROM_EXTENSIONS.iter().any(|e| ext.eq_ignore_ascii_case(e)))
+}
+
+fn wanted(name: &str, entry: Option<&str>) -> bool {
+    match entry {
+        Some(entry) => name == entry,
+        None => is_rom(name),
+    }
+}
+
+fn check_size(name: &str, size: u64) -> Result<()> {
+    if size > MAX_ROM_SIZE {
+        return Err(format!("'{}' is {} bytes, larger than any Game Boy ROM", name, size).into());
+    }
+
+    Ok(())
+}
+
+fn not_found(names: Vec<String>, entry: Option<&str>) -> Box<dyn std::error::Error> {
+    match entry {
+        Some(entry) => format!("No entry '{}' in the archive, it holds: {}", entry, names.join(", ")).into(),
+        None => "No Game Boy ROM in the archive".into(),
+    }
+}
+
+fn read_zip(data: Vec<u8>, entry: Option<&str>) -> Result<Vec<u8>> {
+    let mut archive = zip::ZipArchive::new(Cursor::new(data))?;
+    let mut names = vec![];
+
+    for i in 0..archive.len() {
+        let mut file = archive.by_index(i)?;
+
+        if file.is_dir() {
+            continue;
+        }
+
+        if wanted(file.name(), entry) {
+            check_size(file.name(), file.size())?;
+            let mut rom = Vec::with_capacity(file.size() as usize);
+            file.read_to_end(&mut rom)?;
+            return Ok(rom);
+        }
+
+        names.push(file.name().to_string());
+    }
+
+    Err(not_found(names, entry))
+}
+
+fn read_7z(data: Vec<u8>, entry: Option<&str>) -> Result<Vec<u8>> {
+    let len = data.len() as u64;
+    let mut archive = sevenz_rust::SevenZReader::new(Cursor::new(data), len, sevenz_rust::Password::empty())?;
+    let mut names = vec![];
+    let mut rom = None;
+
+    // Entries share compressed streams, so skipped ones still have to be read
+    archive.for_each_entries(|file, reader| {
+        if rom.is_none() && !file.is_directory() && wanted(file.name(), entry) {
+            check_size(file.name(), file.size()).map_err(|e| sevenz_rust::Error::other(e.to_string()))?;
+            let mut data = Vec::with_capacity(file.size() as usize);
+            reader.read_to_end(&mut data)?;
+            rom = Some(data);
+            return Ok(false);
+        }
+
+        names.push(file.name().to_string());
+        io::copy(reader, &mut io::sink())?;
+        Ok(true)
+    })?;
+
+    rom.ok_or_else(|| not_found(names, entry))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use flate2::write::GzEncoder;
+    use flate2::Compression;
+    use std::io::Write;
+
+    #[test]
+    fn plain_rom() {
+        assert_eq!(uncompress(vec![1, 2, 3], None).unwrap(), vec![1, 2, 3]);
+    }
+
+    #[test]
+    fn gzip_rom() {
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(&[0xCE, 0xED, 0x66, 0x66]).unwrap();
+        let data = encoder.finish().unwrap();
+
+        assert_eq!(uncompress(data, None).unwrap(), vec![0xCE, 0xED, 0x66, 0x66]);
+    }
+
+    #[test]
+    fn zip_entries() {
+        let mut writer = zip::ZipWriter::new(Cursor::new(Vec::new()));
+        let options = zip::write::FileOptions::default();
+
+        writer.start_file("readme.txt", options).unwrap();
+        writer.write_all(b"Not a ROM").unwrap();
+        writer.start_file("game.GB", options).unwrap();
+        writer.write_all(&[1, 2]).unwrap();
+        writer.start_file("hack.gbc", options).unwrap();
+        writer.write_all(&[3, 4]).unwrap();
+        let data = writer.finish().unwrap().into_inner();
+
+        assert_eq!(uncompress(data.clone(), None).unwrap(), vec![1, 2]);
+        assert_eq!(uncompress(data.clone(), Some("hack.gbc")).unwrap(), vec![3, 4]);
+        assert!(uncompress(data, Some("other.gb")).is_err());
+    }
+
+    #[test]
+    fn oversized_entries_are_rejected() {
+        let mut writer = zip::ZipWriter::new(Cursor::new(Vec::new()));
+        writer.start_file("huge.gb", zip::write::FileOptions::default()).unwrap();
+        writer.write_all(&vec![0; MAX_ROM_SIZE as usize + 1]).unwrap();
+        let data = writer.finish().unwrap().into_inner();
+
+        assert!(uncompress(data, None).is_err());
+    }
+}
@@ -5,3 +5,14 @@ mod rom_only;
 pub use self::rom_only::RomOnly;
 pub use self::mbc1::MBC1;
 pub use self::mbc3::MBC3;
+
+use crate::memory::Memory;
+use crate::state::Snapshot;
+
+// Save states hold the registers and RAM of the controllers. Cartridges
+// are Send so frontends can load them before starting the emulation thread.
+pub trait Mbc: Memory + Snapshot + Send {
+    // Back to the registers at power-on. The RAM is battery backed on most
+    // cartridges, so it's only cleared when asked.
+    fn reset(&mut self, clear_ram: bool);
+}
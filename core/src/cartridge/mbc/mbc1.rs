@@ -1,5 +1,7 @@
 use crate::memory::Memory;
 use crate::cartridge::Header;
+use crate::cartridge::mbc::Mbc;
+use crate::state::{Snapshot, StateReader, StateWriter};
 
 enum BankMode {
     Rom2MbRam8Kb,
@@ -103,3 +105,37 @@ impl Memory for MBC1 {
         }
     }
 }
+
+impl Mbc for MBC1 {
+    fn reset(&mut self, clear_ram: bool) {
+        if clear_ram {
+            self.ram.iter_mut().for_each(|b| *b = 0);
+        }
+        self.bank_mode = BankMode::Rom2MbRam8Kb;
+        self.bank = 1;
+        self.ram_enabled = false;
+    }
+}
+
+impl Snapshot for MBC1 {
+    fn save_state(&self, w: &mut StateWriter) {
+        w.bytes(&self.ram);
+        w.bool(match self.bank_mode {
+            BankMode::Rom2MbRam8Kb => false,
+            BankMode::Rom512KbRam32Kb => true,
+        });
+        w.u8(self.bank);
+        w.bool(self.ram_enabled);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> Result<(), Box<dyn std::error::Error>> {
+        r.bytes_into(&mut self.ram)?;
+        self.bank_mode = match r.bool()? {
+            false => BankMode::Rom2MbRam8Kb,
+            true => BankMode::Rom512KbRam32Kb,
+        };
+        self.bank = r.u8()?;
+        self.ram_enabled = r.bool()?;
+        Ok(())
+    }
+}
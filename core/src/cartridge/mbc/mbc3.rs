@@ -1,5 +1,7 @@
 use crate::memory::Memory;
 use crate::cartridge::Header;
+use crate::cartridge::mbc::Mbc;
+use crate::state::{Snapshot, StateReader, StateWriter};
 
 pub struct MBC3 {
     rom: Vec<u8>,
@@ -89,3 +91,31 @@ impl Memory for MBC3 {
         }
     }
 }
+
+impl Mbc for MBC3 {
+    fn reset(&mut self, clear_ram: bool) {
+        if clear_ram {
+            self.ram.iter_mut().for_each(|b| *b = 0);
+        }
+        self.rom_bank = 1;
+        self.ram_bank = 1;
+        self.ram_enabled = false;
+    }
+}
+
+impl Snapshot for MBC3 {
+    fn save_state(&self, w: &mut StateWriter) {
+        w.bytes(&self.ram);
+        w.u8(self.rom_bank as u8);
+        w.u8(self.ram_bank as u8);
+        w.bool(self.ram_enabled);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> Result<(), Box<dyn std::error::Error>> {
+        r.bytes_into(&mut self.ram)?;
+        self.rom_bank = r.u8()? as usize & 0x7F;
+        self.ram_bank = r.u8()? as usize & 0x0F;
+        self.ram_enabled = r.bool()?;
+        Ok(())
+    }
+}
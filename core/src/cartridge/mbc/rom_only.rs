@@ -1,5 +1,7 @@
 use crate::memory::Memory;
 use crate::cartridge::Header;
+use crate::cartridge::mbc::Mbc;
+use crate::state::{Snapshot, StateReader, StateWriter};
 
 pub struct RomOnly {
     rom: Vec<u8>,
@@ -18,3 +20,15 @@ impl Memory for RomOnly {
 
     fn write(&mut self, _addr: u16, _v: u8) { }
 }
+
+impl Mbc for RomOnly {
+    fn reset(&mut self, _clear_ram: bool) { }
+}
+
+impl Snapshot for RomOnly {
+    fn save_state(&self, _w: &mut StateWriter) {}
+
+    fn load_state(&mut self, _r: &mut StateReader) -> Result<(), Box<dyn std::error::Error>> {
+        Ok(())
+    }
+}
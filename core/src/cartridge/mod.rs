@@ -1,52 +1,116 @@
+pub mod archive;
 pub mod mbc;
+pub mod patch;
 
 use self::mbc::RomOnly;
 use self::mbc::MBC1;
 use self::mbc::MBC3;
+use self::mbc::Mbc;
 use super::memory::Memory;
-use std::fs::File;
-use std::io::Read;
+use crate::cheats::RomPatch;
+use crate::state::{Snapshot, StateReader, StateWriter};
 use std::path::Path;
 
 const KB: usize = 1024;
 const MB: usize = 1024 * 1024;
 
 pub struct Cartridge {
-    mbc: Box<dyn Memory>,
-    header: Header
+    mbc: Box<dyn Mbc>,
+    header: Header,
+    // Game Genie codes
+    patches: Vec<RomPatch>,
 }
 
 impl Cartridge {
+    /// Loads a ROM, applying the IPS, UPS or BPS patch next to it if any
     pub fn from_path(rom_path: &Path) -> Result<Self, Box<dyn std::error::Error>> {
-        let mut file = File::open(rom_path)?;
-        let mut rom_data = Vec::new();
-        file.read_to_end(&mut rom_data)?;
+        let patch_path = patch::find_patch(rom_path);
+        Cartridge::open(rom_path, None, patch_path.as_deref())
+    }
+
+    pub fn from_path_with_patch(rom_path: &Path, patch_path: Option<&Path>) -> Result<Self, Box<dyn std::error::Error>> {
+        Cartridge::open(rom_path, None, patch_path)
+    }
+
+    /// Loads a ROM file or archive. `entry` picks the file of an archive,
+    /// which defaults to the first Game Boy ROM in it.
+    pub fn open(rom_path: &Path, entry: Option<&str>, patch_path: Option<&Path>) -> Result<Self, Box<dyn std::error::Error>> {
+        let mut rom_data = archive::read_rom(rom_path, entry)?;
+
+        if let Some(patch_path) = patch_path {
+            rom_data = patch::apply_file(rom_data, patch_path)?;
+        }
+
+        Cartridge::from_bytes(rom_data)
+    }
 
-        let header = Header::read(&rom_data);
+    pub fn from_bytes(rom_data: Vec<u8>) -> Result<Self, Box<dyn std::error::Error>> {
+        if rom_data.len() < 0x150 {
+            return Err("The ROM is too small to hold a header".into());
+        }
+
+        let header = Header::read(&rom_data)?;
+
+        if rom_data.len() < header.rom_size {
+            return Err(format!("The ROM is {} bytes, but its header declares {}", rom_data.len(), header.rom_size).into());
+        }
 
-        let mbc: Box<dyn Memory> = match header.mbc_type {
+        let mbc: Box<dyn Mbc> = match header.mbc_type {
             0x00 => Box::new(RomOnly::new(&header, rom_data)),
             0x01 => Box::new(MBC1::new(&header, rom_data)),
             0x13 => Box::new(MBC3::new(&header, rom_data)),
-            t => panic!("Unsupported cartridge type: 0x{:02x}", t),
+            t => return Err(format!("Unsupported cartridge type: 0x{:02x}", t).into()),
         };
 
         Ok(Self {
             mbc: mbc,
             header: header,
+            patches: vec![],
         })
     }
 
     pub fn get_header(&self) -> Header {
         self.header.clone()
     }
+
+    pub fn set_patches(&mut self, patches: Vec<RomPatch>) {
+        self.patches = patches;
+    }
+
+    // As when powered on again, the ROM and patches stay
+    pub fn reset(&mut self, clear_ram: bool) {
+        self.mbc.reset(clear_ram);
+    }
 }
 
 impl Memory for Cartridge {
-    fn read(&self, addr: u16) -> u8 { self.mbc.read(addr) }
+    fn read(&self, addr: u16) -> u8 {
+        let v = self.mbc.read(addr);
+
+        if addr < 0x8000 {
+            for patch in self.patches.iter() {
+                if let Some(v) = patch.apply(addr, v) {
+                    return v;
+                }
+            }
+        }
+
+        v
+    }
+
     fn write(&mut self, addr: u16, v: u8) { self.mbc.write(addr, v); }
 }
 
+impl Snapshot for Cartridge {
+    fn save_state(&self, w: &mut StateWriter) {
+        self.mbc.save_state(w);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> Result<(), Box<dyn std::error::Error>> {
+        self.mbc.load_state(r)
+    }
+}
+
 
 #[derive(Debug, Clone, Copy)]
 enum CGB {
@@ -58,6 +122,13 @@ enum CGB {
 #[derive(Debug, Clone)]
 pub struct Header {
     title: String,
+    title_hash: u8,
+    title_letter: u8,
+    checksum: u8,
+    // CRC32 of the whole ROM, after patching
+    crc32: u32,
+    licensee: u8,
+    new_licensee: [u8; 2],
     cgb: CGB,
     sgb: bool,
     mbc_type: u8,
@@ -68,13 +139,19 @@ pub struct Header {
 }
 
 impl Header {
-    pub fn read(rom_data: &Vec<u8>) -> Self {
-        let (ram_size, ram_banks) = Header::read_ram_size(rom_data);
-        let (rom_size, rom_banks) = Header::read_rom_size(rom_data);
+    pub fn read(rom_data: &Vec<u8>) -> Result<Self, Box<dyn std::error::Error>> {
+        let (ram_size, ram_banks) = Header::read_ram_size(rom_data)?;
+        let (rom_size, rom_banks) = Header::read_rom_size(rom_data)?;
         let cgb = Header::read_cgb(rom_data);
 
-        Self {
+        Ok(Self {
             title: Header::read_title(rom_data),
+            title_hash: Header::read_title_hash(rom_data),
+            title_letter: rom_data[0x137],
+            checksum: rom_data[0x14D],
+            crc32: patch::crc32(rom_data),
+            licensee: rom_data[0x14B],
+            new_licensee: [rom_data[0x144], rom_data[0x145]],
             cgb: cgb,
             sgb: Header::read_sgb(rom_data),
             mbc_type: rom_data[0x147],
@@ -82,6 +159,44 @@ impl Header {
             rom_banks: rom_banks,
             ram_size: ram_size,
             ram_banks: ram_banks,
+        })
+    }
+
+    pub fn title(&self) -> &str {
+        &self.title
+    }
+
+    pub fn title_hash(&self) -> u8 {
+        self.title_hash
+    }
+
+    pub fn title_letter(&self) -> u8 {
+        self.title_letter
+    }
+
+    pub fn checksum(&self) -> u8 {
+        self.checksum
+    }
+
+    pub fn crc32(&self) -> u32 {
+        self.crc32
+    }
+
+    pub fn dmg_only(&self) -> bool {
+        matches!(self.cgb, CGB::None)
+    }
+
+    // The SGB only enables its features when the header asks for them and
+    // uses the new licensee code
+    pub fn sgb(&self) -> bool {
+        self.sgb && self.licensee == 0x33
+    }
+
+    pub fn nintendo_licensed(&self) -> bool {
+        match self.licensee {
+            0x01 => true,
+            0x33 => &self.new_licensee == b"01",
+            _ => false,
         }
     }
 
@@ -105,6 +220,11 @@ impl Header {
 
     }
 
+    // Sum of the 16 title bytes, used by the CGB boot ROM to identify games
+    fn read_title_hash(rom_data: &[u8]) -> u8 {
+        rom_data[0x134..=0x143].iter().fold(0, |acc: u8, v| acc.wrapping_add(*v))
+    }
+
     fn read_sgb(rom_data: &Vec<u8>) -> bool {
         rom_data[0x146] == 0x03
     }
@@ -117,8 +237,8 @@ impl Header {
         }
     }
 
-    fn read_rom_size(rom_data: &Vec<u8>) -> (usize, usize) {
-        match rom_data[0x0148] {
+    fn read_rom_size(rom_data: &Vec<u8>) -> Result<(usize, usize), Box<dyn std::error::Error>> {
+        let size = match rom_data[0x0148] {
             0x00 => (32 * KB, 0),
             0x01 => (64 * KB, 4),
             0x02 => (128 * KB, 8),
@@ -131,20 +251,24 @@ impl Header {
             0x52 => (72 * 16 * KB, 72),
             0x53 => (80 * 16 * KB, 72),
             0x54 => (96 * 16 * KB, 72),
-            n => panic!("Invalid rom size: 0x{:02x}", n),
-        }
+            n => return Err(format!("Invalid rom size: 0x{:02x}", n).into()),
+        };
+
+        Ok(size)
     }
 
-    fn read_ram_size(rom_data: &Vec<u8>) -> (usize, usize) {
-        match rom_data[0x0149] {
+    fn read_ram_size(rom_data: &Vec<u8>) -> Result<(usize, usize), Box<dyn std::error::Error>> {
+        let size = match rom_data[0x0149] {
             0x00 => (0, 0),
             0x01 => (2 * KB, 1),
             0x02 => (8 * KB, 1),
             0x03 => (32 * KB, 4),
             0x04 => (128 * KB, 16),
             0x05 => (64 * KB, 8),
-            n => panic!("Invalid ram size: 0x{:02x}", n),
-        }
+            n => return Err(format!("Invalid ram size: 0x{:02x}", n).into()),
+        };
+
+        Ok(size)
     }
 }
 
@@ -165,4 +289,39 @@ mod tests {
 
         assert_eq!(header.mbc_type, 1);
     }
+
+    #[test]
+    fn sibling_patch_is_applied() {
+        let dir = std::env::temp_dir().join("gamebrust-patch-test");
+        std::fs::create_dir_all(&dir).unwrap();
+
+        let rom_path = dir.join("game.gb");
+        std::fs::write(&rom_path, vec![0; 0x8000]).unwrap();
+
+        // Renames the game through an IPS patch
+        let mut ips = b"PATCH".to_vec();
+        ips.extend_from_slice(&[0x00, 0x01, 0x34, 0x00, 0x04]);
+        ips.extend_from_slice(b"TEST");
+        ips.extend_from_slice(b"EOF");
+        std::fs::write(dir.join("game.ips"), ips).unwrap();
+
+        let cartridge = Cartridge::from_path(&rom_path).unwrap();
+        assert_eq!(cartridge.get_header().title(), "TEST");
+    }
+
+    #[test]
+    fn bad_headers_are_rejected() {
+        let mut rom = vec![0; 0x8000];
+        rom[0x148] = 0x0A;
+        assert!(Cartridge::from_bytes(rom.clone()).is_err());
+
+        rom[0x148] = 0x00;
+        rom[0x149] = 0x0A;
+        assert!(Cartridge::from_bytes(rom.clone()).is_err());
+
+        // Declares 64 KB
+        rom[0x148] = 0x01;
+        rom[0x149] = 0x00;
+        assert!(Cartridge::from_bytes(rom).is_err());
+    }
 }
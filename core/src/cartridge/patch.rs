@@ -0,0 +1,344 @@
+// Soft-patching of ROM images with IPS, UPS and BPS patches.
+
+use std::fs;
+use std::path::{Path, PathBuf};
+
+type Result<T> = std::result::Result<T, Box<dyn std::error::Error>>;
+
+const EXTENSIONS: [&str; 3] = ["ips", "ups", "bps"];
+
+// Patch next to the ROM with the same name, like "game.gb" and "game.ips"
+pub fn find_patch(rom_path: &Path) -> Option<PathBuf> {
+    EXTENSIONS.iter()
+        .map(|ext| rom_path.with_extension(ext))
+        .find(|path| path.is_file())
+}
+
+pub fn apply_file(rom: Vec<u8>, patch_path: &Path) -> Result<Vec<u8>> {
+    let patch = fs::read(patch_path)?;
+    apply(rom, &patch).map_err(|e| format!("{}: {}", patch_path.display(), e).into())
+}
+
+// The format is detected from the patch header
+pub fn apply(rom: Vec<u8>, patch: &[u8]) -> Result<Vec<u8>> {
+    if patch.starts_with(b"PATCH") {
+        apply_ips(rom, patch)
+    } else if patch.starts_with(b"UPS1") {
+        apply_ups(&rom, patch)
+    } else if patch.starts_with(b"BPS1") {
+        apply_bps(&rom, patch)
+    } else {
+        Err("Unknown patch format".into())
+    }
+}
+
+struct Reader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn new(data: &'a [u8], pos: usize) -> Self {
+        Self { data, pos }
+    }
+
+    fn byte(&mut self) -> Result<u8> {
+        let v = *self.data.get(self.pos).ok_or("Truncated patch")?;
+        self.pos += 1;
+        Ok(v)
+    }
+
+    fn bytes(&mut self, count: usize) -> Result<&'a [u8]> {
+        let bytes = self.data.get(self.pos..self.pos + count).ok_or("Truncated patch")?;
+        self.pos += count;
+        Ok(bytes)
+    }
+
+    // Big endian integer
+    fn be(&mut self, count: usize) -> Result<usize> {
+        Ok(self.bytes(count)?.iter().fold(0, |acc, b| acc << 8 | *b as usize))
+    }
+
+    // Variable length integer used by UPS and BPS
+    fn varint(&mut self) -> Result<usize> {
+        let mut value: usize = 0;
+        let mut shift: usize = 1;
+
+        loop {
+            let x = self.byte()?;
+            value = value.checked_add((x & 0x7F) as usize * shift).ok_or("Invalid patch")?;
+
+            if x & 0x80 != 0 {
+                return Ok(value);
+            }
+
+            shift = shift.checked_shl(7).ok_or("Invalid patch")?;
+            value += shift;
+        }
+    }
+}
+
+fn apply_ips(mut rom: Vec<u8>, patch: &[u8]) -> Result<Vec<u8>> {
+    let mut reader = Reader::new(patch, 5);
+
+    loop {
+        if reader.bytes(3).ok() == Some(b"EOF") {
+            // Optional size to truncate the ROM to
+            if let Ok(size) = reader.be(3) {
+                rom.truncate(size);
+            }
+            return Ok(rom);
+        }
+
+        reader.pos -= 3;
+        let offset = reader.be(3)?;
+        let size = reader.be(2)?;
+
+        let (size, fill) = if size == 0 {
+            let size = reader.be(2)?;
+            (size, Some(reader.byte()?))
+        } else {
+            (size, None)
+        };
+
+        if rom.len() < offset + size {
+            rom.resize(offset + size, 0);
+        }
+
+        match fill {
+            Some(v) => rom[offset..offset + size].iter_mut().for_each(|b| *b = v),
+            None => rom[offset..offset + size].copy_from_slice(reader.bytes(size)?),
+        }
+    }
+}
+
+// Checks the CRC32 footer shared by UPS and BPS
+fn check_footer(source: &[u8], patch: &[u8]) -> Result<(u32, u32)> {
+    if patch.len() < 16 {
+        return Err("Truncated patch".into());
+    }
+
+    let footer = &patch[patch.len() - 12..];
+    let crc = |i: usize| u32::from_le_bytes([footer[i], footer[i + 1], footer[i + 2], footer[i + 3]]);
+
+    if crc32(&patch[..patch.len() - 4]) != crc(8) {
+        return Err("Patch checksum mismatch".into());
+    }
+
+    if crc32(source) != crc(0) {
+        return Err("The patch is not meant for this ROM".into());
+    }
+
+    Ok((crc(0), crc(4)))
+}
+
+fn apply_ups(source: &[u8], patch: &[u8]) -> Result<Vec<u8>> {
+    let (_, target_crc) = check_footer(source, patch)?;
+    let end = patch.len() - 12;
+
+    let mut reader = Reader::new(patch, 4);
+    let _source_size = reader.varint()?;
+    let target_size = reader.varint()?;
+
+    let mut target = source.to_vec();
+    target.resize(target_size, 0);
+
+    let mut offset = 0;
+
+    while reader.pos < end {
+        offset += reader.varint()?;
+
+        // XOR bytes up to a 0, which also counts as a byte
+        loop {
+            let x = reader.byte()?;
+
+            if offset < target.len() {
+                target[offset] ^= x;
+            }
+            offset += 1;
+
+            if x == 0 {
+                break;
+            }
+        }
+    }
+
+    if crc32(&target) != target_crc {
+        return Err("Patched ROM checksum mismatch".into());
+    }
+
+    Ok(target)
+}
+
+fn apply_bps(source: &[u8], patch: &[u8]) -> Result<Vec<u8>> {
+    let (_, target_crc) = check_footer(source, patch)?;
+    let end = patch.len() - 12;
+
+    let mut reader = Reader::new(patch, 4);
+    let _source_size = reader.varint()?;
+    let target_size = reader.varint()?;
+    let metadata_size = reader.varint()?;
+    reader.bytes(metadata_size)?;
+
+    let mut target: Vec<u8> = Vec::with_capacity(target_size);
+    let mut source_offset: isize = 0;
+    let mut target_offset: isize = 0;
+
+    // Offsets of the copy actions are relative and signed
+    let relative = |reader: &mut Reader, offset: &mut isize| -> Result<usize> {
+        let data = reader.varint()?;
+        let delta = (data >> 1) as isize;
+        *offset += if data & 1 != 0 { -delta } else { delta };
+
+        if *offset < 0 { Err("Invalid patch".into()) } else { Ok(*offset as usize) }
+    };
+
+    while reader.pos < end {
+        let data = reader.varint()?;
+        let length = (data >> 2) + 1;
+
+        match data & 3 {
+            // SourceRead
+            0 => {
+                let from = target.len();
+                let bytes = source.get(from..from + length).ok_or("Invalid patch")?;
+                target.extend_from_slice(bytes);
+            }
+            // TargetRead
+            1 => target.extend_from_slice(reader.bytes(length)?),
+            // SourceCopy
+            2 => {
+                let from = relative(&mut reader, &mut source_offset)?;
+                let bytes = source.get(from..from + length).ok_or("Invalid patch")?;
+                target.extend_from_slice(bytes);
+                source_offset += length as isize;
+            }
+            // TargetCopy, may overlap the bytes being written
+            _ => {
+                let from = relative(&mut reader, &mut target_offset)?;
+
+                for i in 0..length {
+                    let v = *target.get(from + i).ok_or("Invalid patch")?;
+                    target.push(v);
+                }
+                target_offset += length as isize;
+            }
+        }
+    }
+
+    if target.len() != target_size || crc32(&target) != target_crc {
+        return Err("Patched ROM checksum mismatch".into());
+    }
+
+    Ok(target)
+}
+
+pub fn crc32(data: &[u8]) -> u32 {
+    let mut crc = 0xFFFF_FFFF_u32;
+
+    for byte in data {
+        crc ^= *byte as u32;
+
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 { (crc >> 1) ^ 0xEDB8_8320 } else { crc >> 1 };
+        }
+    }
+
+    !crc
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn varint(mut value: usize, out: &mut Vec<u8>) {
+        loop {
+            let x = (value & 0x7F) as u8;
+            value >>= 7;
+
+            if value == 0 {
+                out.push(0x80 | x);
+                return;
+            }
+
+            out.push(x);
+            value -= 1;
+        }
+    }
+
+    fn footer(source: &[u8], target: &[u8], mut patch: Vec<u8>) -> Vec<u8> {
+        patch.extend_from_slice(&crc32(source).to_le_bytes());
+        patch.extend_from_slice(&crc32(target).to_le_bytes());
+        let crc = crc32(&patch);
+        patch.extend_from_slice(&crc.to_le_bytes());
+        patch
+    }
+
+    #[test]
+    fn crc32_check_value() {
+        assert_eq!(crc32(b"123456789"), 0xCBF4_3926);
+    }
+
+    #[test]
+    fn ips_records() {
+        let mut patch = b"PATCH".to_vec();
+        patch.extend_from_slice(&[0x00, 0x00, 0x01, 0x00, 0x02, 0xAA, 0xBB]);
+        // RLE record past the end of the ROM
+        patch.extend_from_slice(&[0x00, 0x00, 0x06, 0x00, 0x00, 0x00, 0x03, 0xCC]);
+        patch.extend_from_slice(b"EOF");
+
+        let rom = apply(vec![0; 4], &patch).unwrap();
+        assert_eq!(rom, vec![0, 0xAA, 0xBB, 0, 0, 0, 0xCC, 0xCC, 0xCC]);
+
+        // Truncation
+        patch.extend_from_slice(&[0x00, 0x00, 0x02]);
+        assert_eq!(apply(vec![0; 4], &patch).unwrap(), vec![0, 0xAA]);
+    }
+
+    #[test]
+    fn ups_xor_hunks() {
+        let source = vec![1, 2, 3, 4, 5];
+        let target = vec![1, 9, 3, 4, 5, 7];
+
+        let mut patch = b"UPS1".to_vec();
+        varint(source.len(), &mut patch);
+        varint(target.len(), &mut patch);
+        varint(1, &mut patch);
+        patch.extend_from_slice(&[2 ^ 9, 0]);
+        varint(2, &mut patch);
+        patch.extend_from_slice(&[7, 0]);
+        let patch = footer(&source, &target, patch);
+
+        assert_eq!(apply(source.clone(), &patch).unwrap(), target);
+        assert!(apply(vec![1, 2, 3, 4, 6], &patch).is_err());
+    }
+
+    #[test]
+    fn bps_actions() {
+        let source = vec![1, 2, 3, 4];
+        let target = vec![1, 2, 8, 8, 8, 3, 4];
+
+        let mut patch = b"BPS1".to_vec();
+        varint(source.len(), &mut patch);
+        varint(target.len(), &mut patch);
+        varint(0, &mut patch);
+        // SourceRead 2 bytes
+        varint((2 - 1) << 2, &mut patch);
+        // TargetRead 1 byte
+        varint(1, &mut patch);
+        patch.push(8);
+        // TargetCopy 2 bytes from target offset 2
+        varint((2 - 1) << 2 | 3, &mut patch);
+        varint(2 << 1, &mut patch);
+        // SourceCopy 2 bytes from source offset 2
+        varint((2 - 1) << 2 | 2, &mut patch);
+        varint(2 << 1, &mut patch);
+        let good = footer(&source, &target, patch);
+
+        assert_eq!(apply(source.clone(), &good).unwrap(), target);
+
+        let mut corrupted = good.clone();
+        corrupted[6] ^= 0xFF;
+        assert!(apply(source.clone(), &corrupted).is_err());
+    }
+}
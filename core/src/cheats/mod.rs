@@ -0,0 +1,326 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// A decoded cheat code.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum CheatCode {
+    /// `TTVVAAAA`: writes VV to the RAM address AAAA (little endian) every
+    /// frame. TT selects the RAM bank, only 00 and 01, the unbanked code
+    /// types, are supported.
+    GameShark { bank: u8, value: u8, addr: u16 },
+    /// `VVA-AAA-CCC` or `VVA-AAA`: replaces ROM reads of an address, only
+    /// when the original byte matches the compare value if there is one.
+    GameGenie { addr: u16, value: u8, compare: Option<u8> },
+}
+
+impl CheatCode {
+    pub fn parse(code: &str) -> Result<Self, Box<dyn std::error::Error>> {
+        let code = code.trim().to_ascii_uppercase();
+
+        if code.contains('-') {
+            CheatCode::parse_game_genie(&code)
+        } else {
+            CheatCode::parse_game_shark(&code)
+        }
+    }
+
+    fn parse_game_shark(code: &str) -> Result<Self, Box<dyn std::error::Error>> {
+        let digits = hex_digits(code, 8)
+            .ok_or_else(|| format!("Invalid GameShark code '{}'", code))?;
+
+        let byte = |i: usize| digits[i] << 4 | digits[i + 1];
+        let addr = (byte(6) as u16) << 8 | byte(4) as u16;
+
+        // Only RAM can be written: WRAM, its echo, HRAM or cartridge RAM
+        match addr {
+            0xA000..=0xBFFF | 0xC000..=0xFDFF | 0xFF80..=0xFFFE => {}
+            _ => return Err(format!("GameShark code '{}' doesn't target RAM", code).into()),
+        }
+
+        // Writing to whichever bank the game has mapped would corrupt saves
+        if byte(0) > 0x01 {
+            return Err(format!("GameShark code '{}' targets a RAM bank, which isn't supported", code).into());
+        }
+
+        Ok(CheatCode::GameShark {
+            bank: byte(0),
+            value: byte(2),
+            addr,
+        })
+    }
+
+    fn parse_game_genie(code: &str) -> Result<Self, Box<dyn std::error::Error>> {
+        let groups: Vec<&str> = code.split('-').collect();
+        let invalid = || format!("Invalid Game Genie code '{}'", code);
+
+        if (groups.len() != 2 && groups.len() != 3) || groups.iter().any(|g| g.len() != 3) {
+            return Err(invalid().into());
+        }
+
+        let digits = hex_digits(&groups.concat(), groups.len() * 3).ok_or_else(invalid)?;
+        let d = |i: usize| digits[i] as u16;
+
+        let value = (digits[0] << 4) | digits[1];
+        let addr = ((d(5) ^ 0xF) << 12) | (d(2) << 8) | (d(3) << 4) | d(4);
+
+        if addr >= 0x8000 {
+            return Err(format!("Game Genie code '{}' doesn't target ROM", code).into());
+        }
+
+        // The 8th digit is not used
+        let compare = if digits.len() == 9 {
+            let c = (digits[6] << 4) | digits[8];
+            Some(c.rotate_right(2) ^ 0xBA)
+        } else {
+            None
+        };
+
+        Ok(CheatCode::GameGenie { addr, value, compare })
+    }
+}
+
+fn hex_digits(text: &str, count: usize) -> Option<Vec<u8>> {
+    if text.len() != count {
+        return None;
+    }
+
+    text.chars().map(|c| c.to_digit(16).map(|d| d as u8)).collect()
+}
+
+/// ROM read replaced by a Game Genie code.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct RomPatch {
+    pub addr: u16,
+    pub value: u8,
+    pub compare: Option<u8>,
+}
+
+impl RomPatch {
+    pub fn apply(&self, addr: u16, v: u8) -> Option<u8> {
+        if addr != self.addr {
+            return None;
+        }
+
+        match self.compare {
+            Some(compare) if compare != v => None,
+            _ => Some(self.value),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct Cheat {
+    pub code: String,
+    pub name: String,
+    pub enabled: bool,
+    decoded: CheatCode,
+}
+
+impl Cheat {
+    pub fn decoded(&self) -> CheatCode {
+        self.decoded
+    }
+}
+
+/// Cheat list of a game. Files hold one code per line, followed by an
+/// optional name. Disabled codes are prefixed with '-':
+///
+/// ```text
+/// # Infinite lives
+/// 010438C0 Lives
+/// -00A-17B-C49 Jump higher
+/// ```
+#[derive(Debug, PartialEq, Clone, Default)]
+pub struct Cheats {
+    cheats: Vec<Cheat>,
+}
+
+impl Cheats {
+    pub fn new() -> Self {
+        Self { cheats: vec![] }
+    }
+
+    /// Cheat file of a ROM, named after its title
+    pub fn path_for(dir: &Path, title: &str) -> PathBuf {
+        let name: String = title.trim()
+            .chars()
+            .map(|c| if c.is_ascii_alphanumeric() { c } else { '_' })
+            .collect();
+
+        dir.join(format!("{}.cht", if name.is_empty() { "untitled" } else { &name }))
+    }
+
+    pub fn from_path(path: &Path) -> Result<Self, Box<dyn std::error::Error>> {
+        Cheats::parse(&fs::read_to_string(path)?)
+    }
+
+    pub fn parse(text: &str) -> Result<Self, Box<dyn std::error::Error>> {
+        let mut cheats = Cheats::new();
+
+        for (n, line) in text.lines().enumerate() {
+            let line = line.trim();
+
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let (enabled, line) = match line.strip_prefix('-') {
+                Some(line) => (false, line),
+                None => (true, line.strip_prefix('+').unwrap_or(line)),
+            };
+
+            let mut parts = line.splitn(2, char::is_whitespace);
+            let code = parts.next().unwrap_or("");
+            let name = parts.next().unwrap_or("").trim();
+
+            let i = cheats.add(code, name).map_err(|e| format!("line {}: {}", n + 1, e))?;
+            cheats.set_enabled(i, enabled);
+        }
+
+        Ok(cheats)
+    }
+
+    pub fn save(&self, path: &Path) -> Result<(), Box<dyn std::error::Error>> {
+        let mut text = String::new();
+
+        for cheat in self.cheats.iter() {
+            let prefix = if cheat.enabled { "" } else { "-" };
+            text.push_str(format!("{}{} {}", prefix, cheat.code, cheat.name).trim_end());
+            text.push('\n');
+        }
+
+        fs::write(path, text)?;
+        Ok(())
+    }
+
+    /// Adds an enabled code and returns its index
+    pub fn add(&mut self, code: &str, name: &str) -> Result<usize, Box<dyn std::error::Error>> {
+        let decoded = CheatCode::parse(code)?;
+
+        self.cheats.push(Cheat {
+            code: code.trim().to_ascii_uppercase(),
+            name: name.to_string(),
+            enabled: true,
+            decoded,
+        });
+
+        Ok(self.cheats.len() - 1)
+    }
+
+    pub fn remove(&mut self, index: usize) {
+        if index < self.cheats.len() {
+            self.cheats.remove(index);
+        }
+    }
+
+    pub fn set_enabled(&mut self, index: usize, enabled: bool) {
+        if let Some(cheat) = self.cheats.get_mut(index) {
+            cheat.enabled = enabled;
+        }
+    }
+
+    pub fn list(&self) -> &[Cheat] {
+        &self.cheats
+    }
+
+    pub(crate) fn rom_patches(&self) -> Vec<RomPatch> {
+        self.enabled()
+            .filter_map(|code| match code {
+                CheatCode::GameGenie { addr, value, compare } => Some(RomPatch { addr, value, compare }),
+                _ => None,
+            })
+            .collect()
+    }
+
+    // GameShark writes as (address, value)
+    pub(crate) fn ram_writes(&self) -> Vec<(u16, u8)> {
+        self.enabled()
+            .filter_map(|code| match code {
+                CheatCode::GameShark { value, addr, .. } => Some((addr, value)),
+                _ => None,
+            })
+            .collect()
+    }
+
+    fn enabled(&self) -> impl Iterator<Item = CheatCode> + '_ {
+        self.cheats.iter().filter(|cheat| cheat.enabled).map(|cheat| cheat.decoded)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_game_shark() {
+        assert_eq!(
+            CheatCode::parse("010438C0").unwrap(),
+            CheatCode::GameShark { bank: 0x01, value: 0x04, addr: 0xC038 }
+        );
+
+        assert!(CheatCode::parse("0104380").is_err());
+        assert!(CheatCode::parse("0104G8C0").is_err());
+        // ROM can't be written
+        assert!(CheatCode::parse("01040040").is_err());
+        // Banked codes
+        assert!(CheatCode::parse("810400A0").is_err());
+        assert!(CheatCode::parse("900438D0").is_err());
+        assert!(CheatCode::parse("000438C0").is_ok());
+    }
+
+    #[test]
+    fn parse_game_genie() {
+        assert_eq!(
+            CheatCode::parse("00A-17B-C49").unwrap(),
+            CheatCode::GameGenie { addr: 0x4A17, value: 0x00, compare: Some(0xC8) }
+        );
+
+        assert_eq!(
+            CheatCode::parse("3e1-29f").unwrap(),
+            CheatCode::GameGenie { addr: 0x0129, value: 0x3E, compare: None }
+        );
+
+        assert!(CheatCode::parse("00A-17B-C4").is_err());
+        assert!(CheatCode::parse("00A-17-BC49").is_err());
+        // Addresses above 0x7FFF are not ROM
+        assert!(CheatCode::parse("00A-170-C49").is_err());
+    }
+
+    #[test]
+    fn rom_patch_compare() {
+        let patch = RomPatch { addr: 0x4A17, value: 0x00, compare: Some(0xC8) };
+
+        assert_eq!(patch.apply(0x4A17, 0xC8), Some(0x00));
+        assert_eq!(patch.apply(0x4A17, 0xC9), None);
+        assert_eq!(patch.apply(0x4A18, 0xC8), None);
+    }
+
+    #[test]
+    fn cheat_file_round_trip() {
+        let mut cheats = Cheats::parse("
+            # Comment
+            010438C0 Infinite lives
+            -00A-17B-C49
+        ").unwrap();
+
+        assert_eq!(cheats.list().len(), 2);
+        assert_eq!(cheats.list()[0].name, "Infinite lives");
+        assert_eq!(cheats.ram_writes(), vec![(0xC038, 0x04)]);
+        assert!(cheats.rom_patches().is_empty());
+
+        cheats.set_enabled(1, true);
+        assert_eq!(cheats.rom_patches().len(), 1);
+
+        let path = std::env::temp_dir().join("gamebrust-cheats-test.cht");
+        cheats.save(&path).unwrap();
+        assert_eq!(Cheats::from_path(&path).unwrap(), cheats);
+
+        assert!(Cheats::parse("0104G8C0 Broken").is_err());
+    }
+
+    #[test]
+    fn path_for_title() {
+        let path = Cheats::path_for(Path::new("saves"), "POKEMON RED");
+        assert_eq!(path, Path::new("saves").join("POKEMON_RED.cht"));
+    }
+}
@@ -8,6 +8,8 @@ mod tests;
 
 use self::opcodes::*;
 use super::memory::Memory;
+use crate::model::Model;
+use crate::state::{Snapshot, StateReader, StateWriter};
 use registers::Registers;
 use registers::R16;
 
@@ -28,12 +30,15 @@ impl CPU {
         }
     }
 
-    pub fn armed() -> Self {
+    // State left by the boot ROM of the given model
+    pub fn armed(model: Model, header_checksum: u8) -> Self {
+        let [af, bc, de, hl] = model.post_boot_registers(header_checksum);
+
         let mut cpu = CPU::new();
-        cpu.reg.set_r16(R16::AF, 0x01B0);
-        cpu.reg.set_r16(R16::BC, 0x0013);
-        cpu.reg.set_r16(R16::DE, 0x00D8);
-        cpu.reg.set_r16(R16::HL, 0x014D);
+        cpu.reg.set_r16(R16::AF, af);
+        cpu.reg.set_r16(R16::BC, bc);
+        cpu.reg.set_r16(R16::DE, de);
+        cpu.reg.set_r16(R16::HL, hl);
         cpu.reg.pc = 0x100;
         cpu.reg.sp = 0xFFFE;
 
@@ -857,3 +862,35 @@ impl CPU {
         ((a as u32 as i32) + b as i32) as u16
     }
 }
+
+impl Snapshot for CPU {
+    fn save_state(&self, w: &mut StateWriter) {
+        let reg = &self.reg;
+        for v in [reg.a, reg.b, reg.c, reg.d, reg.e, reg.h, reg.l, reg.flags.to_u8()].iter() {
+            w.u8(*v);
+        }
+        w.u16(reg.sp);
+        w.u16(reg.pc);
+        w.bool(self.halted);
+        w.bool(self.ime);
+        w.bool(self.ime_next);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> Result<(), Box<dyn std::error::Error>> {
+        let reg = &mut self.reg;
+        reg.a = r.u8()?;
+        reg.b = r.u8()?;
+        reg.c = r.u8()?;
+        reg.d = r.u8()?;
+        reg.e = r.u8()?;
+        reg.h = r.u8()?;
+        reg.l = r.u8()?;
+        reg.flags.set(r.u8()?);
+        reg.sp = r.u16()?;
+        reg.pc = r.u16()?;
+        self.halted = r.bool()?;
+        self.ime = r.bool()?;
+        self.ime_next = r.bool()?;
+        Ok(())
+    }
+}
@@ -0,0 +1,194 @@
+use crate::io::joypad::JoypadKey;
+
+fn key_from_name(name: &str) -> Option<JoypadKey> {
+    match name.to_lowercase().as_str() {
+        "up" => Some(JoypadKey::Up),
+        "down" => Some(JoypadKey::Down),
+        "left" => Some(JoypadKey::Left),
+        "right" => Some(JoypadKey::Right),
+        "a" => Some(JoypadKey::A),
+        "b" => Some(JoypadKey::B),
+        "start" => Some(JoypadKey::Start),
+        "select" => Some(JoypadKey::Select),
+        _ => None,
+    }
+}
+
+/// Named sequence of keys, each held for some frames. Sequences are written
+/// as `keys:frames` steps, with keys joined by '+' and "wait" for none:
+///
+/// ```text
+/// start:2 wait:30 down+a:4 a
+/// ```
+#[derive(Debug, PartialEq, Clone)]
+pub struct Macro {
+    pub name: String,
+    // Keys, one bit per JoypadKey, and the frames they are held for
+    steps: Vec<(u8, u32)>,
+}
+
+impl Macro {
+    pub fn parse(name: &str, text: &str) -> Result<Self, Box<dyn std::error::Error>> {
+        let mut steps = vec![];
+
+        for step in text.split_whitespace() {
+            let error = || format!("Invalid step '{}' in macro '{}'", step, name);
+            let mut parts = step.splitn(2, ':');
+            let keys = parts.next().unwrap_or("");
+
+            let frames = match parts.next() {
+                Some(frames) => frames.parse().map_err(|_| error())?,
+                None => 1,
+            };
+
+            let mut mask = 0;
+
+            if keys != "wait" {
+                for key in keys.split('+') {
+                    mask |= 1 << key_from_name(key).ok_or_else(error)? as u8;
+                }
+            }
+
+            if frames > 0 {
+                steps.push((mask, frames));
+            }
+        }
+
+        if steps.is_empty() {
+            return Err(format!("The macro '{}' has no steps", name).into());
+        }
+
+        Ok(Self {
+            name: name.to_string(),
+            steps,
+        })
+    }
+
+    // Length in frames
+    pub fn len(&self) -> u32 {
+        self.steps.iter().map(|(_, frames)| frames).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    // Keys held on a frame of the macro, none after its end
+    fn keys(&self, frame: u32) -> Option<u8> {
+        let mut start = 0;
+
+        for (keys, frames) in self.steps.iter() {
+            if frame < start + frames {
+                return Some(*keys);
+            }
+            start += frames;
+        }
+
+        None
+    }
+}
+
+/// Input added on top of the keys held by the player, computed once per
+/// frame before the keys reach the joypad. Turbo keys alternate between
+/// pressed and released every `rate` frames while held, and macros press
+/// their keys along with the player's until they end.
+pub struct InputLayer {
+    rate: u32,
+    turbo: u8,
+    turbo_frames: u32,
+    // Macros being played, with the frame they are at
+    playing: Vec<(Macro, u32)>,
+}
+
+impl InputLayer {
+    pub fn new(rate: u32) -> Self {
+        Self {
+            rate: rate.max(1),
+            turbo: 0,
+            turbo_frames: 0,
+            playing: vec![],
+        }
+    }
+
+    // Turbo keys held, one bit per JoypadKey
+    pub fn set_turbo(&mut self, keys: u8) {
+        if keys == 0 {
+            self.turbo_frames = 0;
+        }
+        self.turbo = keys;
+    }
+
+    pub fn play(&mut self, m: Macro) {
+        self.playing.push((m, 0));
+    }
+
+    pub fn is_playing(&self) -> bool {
+        !self.playing.is_empty()
+    }
+
+    // Keys to hold for the next frame, given the ones held by the player
+    pub fn frame(&mut self, keys: u8) -> u8 {
+        let mut keys = keys;
+
+        if self.turbo != 0 {
+            if (self.turbo_frames / self.rate) & 1 == 0 {
+                keys |= self.turbo;
+            }
+            self.turbo_frames += 1;
+        }
+
+        for (m, frame) in self.playing.iter_mut() {
+            keys |= m.keys(*frame).unwrap_or(0);
+            *frame += 1;
+        }
+
+        self.playing.retain(|(m, frame)| *frame < m.len());
+        keys
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const A: u8 = 1 << JoypadKey::A as u8;
+    const B: u8 = 1 << JoypadKey::B as u8;
+    const START: u8 = 1 << JoypadKey::Start as u8;
+    const DOWN: u8 = 1 << JoypadKey::Down as u8;
+
+    #[test]
+    fn parse_macros() {
+        let m = Macro::parse("menu", "start:2 wait:3 down+a a").unwrap();
+        assert_eq!(m.steps, vec![(START, 2), (0, 3), (DOWN | A, 1), (A, 1)]);
+        assert_eq!(m.len(), 7);
+
+        assert!(Macro::parse("bad", "").is_err());
+        assert!(Macro::parse("bad", "c:2").is_err());
+        assert!(Macro::parse("bad", "a:x").is_err());
+    }
+
+    #[test]
+    fn turbo_alternates() {
+        let mut input = InputLayer::new(2);
+        input.set_turbo(A);
+
+        let frames: Vec<_> = (0..6).map(|_| input.frame(B)).collect();
+        assert_eq!(frames, vec![A | B, A | B, B, B, A | B, A | B]);
+
+        // Turbo starts pressed again after being released
+        input.set_turbo(0);
+        assert_eq!(input.frame(0), 0);
+        input.set_turbo(A);
+        assert_eq!(input.frame(0), A);
+    }
+
+    #[test]
+    fn macros_play_on_top_of_the_player() {
+        let mut input = InputLayer::new(1);
+        input.play(Macro::parse("menu", "start wait a:2").unwrap());
+
+        let frames: Vec<_> = (0..5).map(|_| input.frame(B)).collect();
+        assert_eq!(frames, vec![START | B, B, A | B, A | B, B]);
+        assert!(!input.is_playing());
+    }
+}
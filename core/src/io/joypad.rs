@@ -1,6 +1,7 @@
 use crate::io;
+use crate::state::{Snapshot, StateReader, StateWriter};
 
-#[derive(PartialEq, Clone, Copy)]
+#[derive(Debug, PartialEq, Clone, Copy)]
 pub enum JoypadKey {
     Up = 0,
     Down = 1,
@@ -12,21 +13,48 @@ pub enum JoypadKey {
     Select = 7,
 }
 
+impl JoypadKey {
+    pub const ALL: [JoypadKey; 8] = [
+        JoypadKey::Up,
+        JoypadKey::Down,
+        JoypadKey::Left,
+        JoypadKey::Right,
+        JoypadKey::A,
+        JoypadKey::B,
+        JoypadKey::Start,
+        JoypadKey::Select,
+    ];
+}
+
 enum Mode {
     Buttons,
     Directions,
     Invalid,
 }
 
+pub const MAX_PLAYERS: usize = 4;
+
 pub struct Joypad {
     mode: Mode,
     intfs: u8,
-    keys: [bool; 8],
+    keys: [[bool; 8]; MAX_PLAYERS],
+    // Controllers multiplexed by the SGB after a MLT_REQ command
+    players: u8,
+    player: u8,
+    p15: bool,
 }
 
 pub trait JoypadAdapter {
-    fn pressed(&mut self, key: JoypadKey);
-    fn released(&mut self, key: JoypadKey);
+    fn pressed(&mut self, key: JoypadKey) {
+        self.player_pressed(0, key);
+    }
+
+    fn released(&mut self, key: JoypadKey) {
+        self.player_released(0, key);
+    }
+
+    fn player_pressed(&mut self, player: usize, key: JoypadKey);
+    fn player_released(&mut self, player: usize, key: JoypadKey);
 }
 
 impl Joypad {
@@ -34,10 +62,28 @@ impl Joypad {
         Self {
             intfs: 0,
             mode: Mode::Buttons,
-            keys: [false; 8],
+            keys: [[false; 8]; MAX_PLAYERS],
+            players: 1,
+            player: 0,
+            p15: true,
+        }
+    }
+
+    // Sets the amount of controllers (1, 2 or 4) read through the register
+    pub fn set_players(&mut self, players: u8) {
+        if players != self.players {
+            self.players = players;
+            self.player = 0;
         }
     }
 
+    // Keys held by a controller, one bit per JoypadKey
+    pub fn keys(&self, player: usize) -> u8 {
+        self.keys[player].iter()
+            .enumerate()
+            .fold(0, |acc, (i, held)| if *held { acc | 1 << i } else { acc })
+    }
+
     pub fn step(&mut self) -> u8 {
         let intfs = self.intfs;
         self.intfs = 0;
@@ -45,6 +91,13 @@ impl Joypad {
     }
 
     pub fn write(&mut self, v: u8) {
+        // The next controller is selected each time P15 goes high
+        let p15 = v & (1 << 5) != 0;
+        if p15 && !self.p15 && self.players > 1 {
+            self.player = (self.player + 1) % self.players;
+        }
+        self.p15 = p15;
+
         self.mode = if v & (1 << 4) == 0 {
             Mode::Directions
         } else if v & (1 << 5) == 0 {
@@ -58,42 +111,138 @@ impl Joypad {
         match self.mode {
             Mode::Directions => self.get_directions() | (1 << 5),
             Mode::Buttons => self.get_buttons() | (1 << 4),
-            _ => 0xFF,
+            // With multiple controllers the low bits hold the current one
+            _ => 0xFF - self.player,
         }
     }
 
     fn get_directions(&self) -> u8 {
         use JoypadKey::*;
-        !(((if self.keys[Right as usize] { 1 } else { 0 })
-            | (if self.keys[Left as usize] { 1 << 1 } else { 0 })
-            | (if self.keys[Up as usize] { 1 << 2 } else { 0 })
-            | (if self.keys[Down as usize] { 1 << 3 } else { 0 })) as u8) & 0x0F
+        let keys = &self.keys[self.player as usize];
+        !(((if keys[Right as usize] { 1 } else { 0 })
+            | (if keys[Left as usize] { 1 << 1 } else { 0 })
+            | (if keys[Up as usize] { 1 << 2 } else { 0 })
+            | (if keys[Down as usize] { 1 << 3 } else { 0 })) as u8) & 0x0F
     }
 
     fn get_buttons(&self) -> u8 {
         use JoypadKey::*;
+        let keys = &self.keys[self.player as usize];
         !(
-            ((if self.keys[A as usize] { 1 } else { 0 }) | 
-             (if self.keys[B as usize] { 1 << 1 } else { 0 }) | 
-             (if self.keys[Select as usize] { 1 << 2 } else { 0 }) | 
-             (if self.keys[Start as usize] { 1 << 3 } else { 0 })) as u8
+            ((if keys[A as usize] { 1 } else { 0 }) | 
+             (if keys[B as usize] { 1 << 1 } else { 0 }) | 
+             (if keys[Select as usize] { 1 << 2 } else { 0 }) | 
+             (if keys[Start as usize] { 1 << 3 } else { 0 })) as u8
          ) & 0x0F
     }
 
 }
 
 impl JoypadAdapter for Joypad {
-    fn pressed(&mut self, key: JoypadKey) {
-        if self.keys[key as usize] == false {
-            self.keys[key as usize] = true;
+    fn player_pressed(&mut self, player: usize, key: JoypadKey) {
+        if player >= MAX_PLAYERS { return; }
+
+        if !self.keys[player][key as usize] {
+            self.keys[player][key as usize] = true;
             self.intfs = io::intf_raise(0, io::Flag::Joypad);
         }
     }
 
-    fn released(&mut self, key: JoypadKey) {
-        if self.keys[key as usize] == true {
-            self.keys[key as usize] = false;
+    fn player_released(&mut self, player: usize, key: JoypadKey) {
+        if player >= MAX_PLAYERS { return; }
+
+        if self.keys[player][key as usize] {
+            self.keys[player][key as usize] = false;
             self.intfs = io::intf_raise(0, io::Flag::Joypad);
         }
     }
 }
+
+impl Snapshot for Joypad {
+    fn save_state(&self, w: &mut StateWriter) {
+        w.u8(match self.mode {
+            Mode::Buttons => 0,
+            Mode::Directions => 1,
+            Mode::Invalid => 2,
+        });
+        w.u8(self.intfs);
+        for player in 0..MAX_PLAYERS {
+            w.u8(self.keys(player));
+        }
+        w.u8(self.players);
+        w.u8(self.player);
+        w.bool(self.p15);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> Result<(), Box<dyn std::error::Error>> {
+        self.mode = match r.u8()? {
+            0 => Mode::Buttons,
+            1 => Mode::Directions,
+            _ => Mode::Invalid,
+        };
+        self.intfs = r.u8()?;
+        for player in 0..MAX_PLAYERS {
+            let keys = r.u8()?;
+            for (i, held) in self.keys[player].iter_mut().enumerate() {
+                *held = keys & (1 << i) != 0;
+            }
+        }
+        let players = r.u8()?;
+        let player = r.u8()?;
+
+        if !matches!(players, 1 | 2 | 4) || player >= players {
+            return Err("Invalid state".into());
+        }
+
+        self.players = players;
+        self.player = player;
+        self.p15 = r.bool()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn multiplexed_players() {
+        let mut joypad = Joypad::new();
+        joypad.set_players(2);
+        joypad.player_pressed(1, JoypadKey::A);
+
+        joypad.write(0x30);
+        assert_eq!(joypad.read(), 0xFF);
+        joypad.write(0x10);
+        assert_eq!(joypad.read() & 0x0F, 0x0F);
+
+        // Raising P15 selects the second controller
+        joypad.write(0x30);
+        assert_eq!(joypad.read(), 0xFE);
+        joypad.write(0x10);
+        assert_eq!(joypad.read() & 0x0F, 0x0E);
+
+        joypad.write(0x30);
+        assert_eq!(joypad.read(), 0xFF);
+    }
+
+    #[test]
+    fn invalid_player_state() {
+        let mut joypad = Joypad::new();
+        joypad.set_players(4);
+
+        let mut w = StateWriter::new();
+        joypad.save_state(&mut w);
+        let state = w.into_bytes();
+        let players = state.len() - 3;
+
+        assert!(joypad.load_state(&mut StateReader::new(&state)).is_ok());
+
+        // 3 players, or a player past the last one
+        for (i, v) in [(players, 3), (players + 1, 4)].iter() {
+            let mut state = state.clone();
+            state[*i] = *v;
+            assert!(joypad.load_state(&mut StateReader::new(&state)).is_err());
+        }
+    }
+}
@@ -1,5 +1,6 @@
 pub mod timer;
 pub mod joypad;
+pub mod input;
 
 #[allow(dead_code)]
 pub enum Flag {
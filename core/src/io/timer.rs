@@ -1,4 +1,5 @@
 use crate::io;
+use crate::state::{Snapshot, StateReader, StateWriter};
 
 #[derive(Debug)]
 pub enum Divider {
@@ -84,6 +85,12 @@ impl Timer {
         }
     }
 
+    // Sets the internal divider counter, DIV being its upper byte
+    pub fn set_counter(&mut self, v: u16) {
+        self.div = (v >> 8) as u8;
+        self.div_clock = (v & 0xFF) as u32;
+    }
+
     pub fn set_div(&mut self, _: u8) {
         self.div = 0;
     }
@@ -137,6 +144,32 @@ impl Timer {
     }
 }
 
+impl Snapshot for Timer {
+    fn save_state(&self, w: &mut StateWriter) {
+        w.u32(self.div_clock);
+        w.u8(self.div);
+        w.u32(self.tma_clock);
+        w.u8(self.tima);
+        w.u8(self.tma);
+        w.u32(self.period);
+        w.bool(self.enabled);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> Result<(), Box<dyn std::error::Error>> {
+        self.div_clock = r.u32()?;
+        self.div = r.u8()?;
+        self.tma_clock = r.u32()?;
+        self.tima = r.u8()?;
+        self.tma = r.u8()?;
+        self.period = match r.u32()? {
+            p @ 16 | p @ 64 | p @ 256 | p @ 1024 => p,
+            _ => return Err("Invalid timer state".into()),
+        };
+        self.enabled = r.bool()?;
+        Ok(())
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -1,62 +1,261 @@
 mod cpu;
 pub mod io;
 pub mod cartridge;
+pub mod cheats;
+pub mod movie;
 mod memory;
 mod ppu;
+mod sgb;
+mod model;
+mod rewind;
+mod state;
+#[cfg(test)]
+mod test_utils;
 
 use cpu::CPU;
 use memory::MMU;
-use cartridge::Cartridge;
-use crate::io::joypad::JoypadAdapter;
+pub use memory::BootRom;
+pub use model::Model;
+pub use rewind::Rewind;
+use cartridge::{Cartridge, Header};
+use crate::io::joypad::{JoypadAdapter, JoypadKey};
+use state::{Snapshot, StateReader, StateWriter};
 use std::path::Path;
 
-// pub const CLOCK_FREQUENCY: u32 = 4_194_304;
+pub use ppu::Renderer;
+pub use ppu::colors::{ColorScheme, PalettePreset, Shades};
+pub use ppu::colorize::{Combo, TitleTable};
+pub use sgb::{SGB_SCREEN_W, SGB_SCREEN_H};
+
+pub const CLOCK_FREQUENCY: u32 = 4_194_304;
 // pub const BATCH_TIME: u32 = 1;
 // pub const BATCH_TICKS: u32 = (BATCH_TIME as f64 / (1000_f64 / CLOCK_FREQUENCY as f64)) as u32;
 
+const STATE_MAGIC: [u8; 4] = *b"GBRS";
+const STATE_VERSION: u8 = 1;
+
+// Clock ticks of a frame: 154 lines of 456 ticks
+pub const FRAME_TICKS: u32 = 70224;
+
+// About 59.73 frames per second
+pub const FRAME_RATE: f64 = CLOCK_FREQUENCY as f64 / FRAME_TICKS as f64;
+
+pub type LogHook = Box<dyn Fn(&str)>;
+// Receives each byte sent through the serial port
+pub type SerialHook = Box<dyn FnMut(u8)>;
+
 pub trait Display {
     fn update(&mut self, _framebuffer: &Vec<u32>) { }
 }
 
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum ResetKind {
+    // Like a quick power cycle: the RAM of the cartridge and the WRAM keep
+    // their contents
+    Soft,
+    // Every component starts over, with the RAM cleared
+    Hard,
+}
+
 pub struct System {
     cpu: CPU,
     mmu: MMU,
+    model: Model,
+    // The MMU drops its boot ROM once it's unmapped, this one runs again
+    // on resets
+    bootrom: Option<BootRom>,
 }
 
 #[allow(dead_code)]
 impl System {
-    pub fn new(cartridge: Cartridge, display: Box<dyn Display>, bootroom: bool) -> Self {
-        let cpu = 
-            match bootroom {
-                true => CPU::new(),
-                false => CPU::armed(),
-            };
+    // Without a boot ROM the system starts in the state the boot ROM of the
+    // model would leave it.
+    pub fn new(cartridge: Cartridge, display: Box<dyn Display>, model: Model, bootrom: Option<BootRom>) -> Self {
+        let cpu = System::power_on_cpu(model, &cartridge.get_header(), bootrom.is_some());
+
+        let mut system = Self {
+            cpu,
+            mmu: MMU::new(cartridge, display, model, bootrom.clone()),
+            model,
+            bootrom,
+        };
+
+        if model.is_sgb() {
+            system.set_sgb(true);
+        }
 
-        Self {
-            cpu: cpu,
-            mmu: MMU::new(cartridge, display, bootroom),
+        system
+    }
+
+    fn power_on_cpu(model: Model, header: &Header, bootrom: bool) -> CPU {
+        if bootrom {
+            CPU::new()
+        } else {
+            CPU::armed(model, header.checksum())
         }
     }
 
+    // Starts the cartridge over without loading it again, running the boot
+    // ROM again if there is one. Keys held and settings like colors and
+    // cheats stay.
+    pub fn reset(&mut self, kind: ResetKind) {
+        let keys = self.get_keys();
+
+        self.cpu = System::power_on_cpu(self.model, &self.mmu.get_header(), self.bootrom.is_some());
+        self.mmu.reset(kind, self.model, self.bootrom.clone());
+        self.set_keys(keys);
+    }
+
     pub fn step(&mut self) -> u32 {
         let ticks = self.cpu.step(&mut self.mmu);
         self.mmu.step(ticks);
         ticks
     }
 
+    // Runs until the PPU enters VBlank, or for the length of a frame when
+    // the LCD is off. Returns the ticks run.
+    pub fn run_frame(&mut self) -> u32 {
+        let mut ticks = 0;
+
+        while ticks < FRAME_TICKS {
+            ticks += self.step();
+
+            if self.mmu.take_vblank() {
+                break;
+            }
+        }
+
+        ticks
+    }
+
     pub fn get_joypad_adapter(&mut self) -> &mut dyn JoypadAdapter {
         self.mmu.get_joypad_adapter()
     }
+
+    // Keys held on the first controller, one bit per JoypadKey
+    pub fn get_keys(&self) -> u8 {
+        self.mmu.get_keys()
+    }
+
+    // Presses and releases keys so that exactly the given ones are held
+    pub fn set_keys(&mut self, keys: u8) {
+        let joypad = self.mmu.get_joypad_adapter();
+
+        for key in JoypadKey::ALL.iter() {
+            if keys & (1 << *key as u8) != 0 {
+                joypad.pressed(*key);
+            } else {
+                joypad.released(*key);
+            }
+        }
+    }
+
+    pub fn get_header(&self) -> Header {
+        self.mmu.get_header()
+    }
+
+    // Captures the state of the hardware. Settings like colors and cheats
+    // are not part of it.
+    pub fn save_state(&self) -> Vec<u8> {
+        let mut w = StateWriter::new();
+
+        STATE_MAGIC.iter().for_each(|b| w.u8(*b));
+        w.u8(STATE_VERSION);
+        w.u32(self.mmu.get_header().crc32());
+
+        self.cpu.save_state(&mut w);
+        self.mmu.save_state(&mut w);
+        w.into_bytes()
+    }
+
+    // Restores a state saved on the same ROM and hardware. The system is
+    // left untouched when the state can't be loaded.
+    pub fn load_state(&mut self, data: &[u8]) -> Result<(), Box<dyn std::error::Error>> {
+        let mut r = StateReader::new(data);
+
+        let magic = [r.u8()?, r.u8()?, r.u8()?, r.u8()?];
+        if magic != STATE_MAGIC || r.u8()? != STATE_VERSION {
+            return Err("Not a save state of this version".into());
+        }
+
+        if r.u32()? != self.mmu.get_header().crc32() {
+            return Err("The state was saved on another ROM".into());
+        }
+
+        let backup = self.save_state();
+
+        let result = self.cpu.load_state(&mut r)
+            .and_then(|_| self.mmu.load_state(&mut r))
+            .and_then(|_| if r.is_empty() { Ok(()) } else { Err("Invalid state".into()) });
+
+        if let Err(e) = result {
+            self.load_state(&backup).expect("Restoring the state before the failed load");
+            return Err(e);
+        }
+
+        self.mmu.redraw();
+        Ok(())
+    }
+
+    pub fn set_renderer(&mut self, renderer: Renderer) {
+        self.mmu.set_renderer(renderer);
+    }
+
+    pub fn set_colors(&mut self, colors: ColorScheme) {
+        self.mmu.set_colors(colors);
+    }
+
+    pub fn get_colors(&self) -> ColorScheme {
+        self.mmu.get_colors()
+    }
+
+    // Emulates the Super Game Boy: frames sent to the display become
+    // 256x224 with the border, and SGB cartridges can send commands.
+    pub fn set_sgb(&mut self, enabled: bool) {
+        self.mmu.set_sgb(enabled);
+    }
+
+    // Game Genie codes patch ROM reads, GameShark codes write RAM on
+    // every frame. Replaces the codes previously set.
+    pub fn set_cheats(&mut self, cheats: &cheats::Cheats) {
+        self.mmu.set_cheats(cheats);
+    }
+
+    // Receives diagnostics, like accesses to unmapped IO registers
+    pub fn set_log_hook(&mut self, hook: Option<LogHook>) {
+        self.mmu.set_log_hook(hook);
+    }
+
+    // Without a link cable, bytes sent through the serial port only reach
+    // this hook. Test ROMs print their results this way.
+    pub fn set_serial_hook(&mut self, hook: Option<SerialHook>) {
+        self.mmu.set_serial_hook(hook);
+    }
+
+    // Uses the colors a Game Boy Color would pick for DMG-only cartridges.
+    // Returns false, leaving the colors untouched, for any other cartridge.
+    pub fn colorize(&mut self, titles: &TitleTable, combo: Option<Combo>) -> bool {
+        match ppu::colorize::colorize(&self.mmu.get_header(), titles, combo) {
+            Some(colors) => {
+                self.mmu.set_colors(colors);
+                true
+            }
+            None => false,
+        }
+    }
+
+    // VRAM and OAM are inaccessible to the CPU while the PPU uses them.
+    // Disabling the blocking can be handy for debugging.
+    pub fn set_access_blocking(&mut self, enabled: bool) {
+        self.mmu.set_access_blocking(enabled);
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
-
-    struct DummyDisplay {}
-
-    impl Display for DummyDisplay {
-    }
+    use crate::memory::Memory;
+    use crate::test_utils::NullDisplay;
 
     #[test]
     fn create_system() {
@@ -66,7 +265,7 @@ mod tests {
                 _ => panic!("Error!"),
             };
 
-        let mut system = System::new(cartridge, Box::new(DummyDisplay{}), true);
+        let mut system = System::new(cartridge, Box::new(NullDisplay {}), Model::DMG, Some(BootRom::dmg()));
 
         system.step();
 
@@ -77,4 +276,77 @@ mod tests {
 
         system.step();
     }
+
+    // Increments 0xC000 forever
+    fn counter_system() -> System {
+        let mut rom = vec![0; 0x8000];
+        rom[0x100..0x106].copy_from_slice(&[0x21, 0x00, 0xC0, 0x34, 0x18, 0xFD]);
+
+        let cartridge = Cartridge::from_bytes(rom).unwrap();
+        System::new(cartridge, Box::new(NullDisplay {}), Model::DMG, None)
+    }
+
+    #[test]
+    fn save_state_round_trip() {
+        let mut system = counter_system();
+        system.run_frame();
+
+        let state = system.save_state();
+        for _ in 0..10 { system.run_frame(); }
+        let after = system.save_state();
+
+        system.load_state(&state).unwrap();
+        assert_eq!(system.save_state(), state);
+
+        // Running from a loaded state gives the same results
+        for _ in 0..10 { system.run_frame(); }
+        assert_eq!(system.save_state(), after);
+
+        // A broken state leaves the system alone
+        assert!(system.load_state(&state[..state.len() - 1]).is_err());
+        assert_eq!(system.save_state(), after);
+    }
+
+    #[test]
+    fn rewind_to_earlier_frames() {
+        let mut system = counter_system();
+        let mut rewind = Rewind::new(2, 10);
+        let mut states = vec![];
+
+        for i in 1..=8 {
+            system.run_frame();
+            rewind.frame(&system);
+
+            if i % 2 == 0 {
+                states.push(system.save_state());
+            }
+        }
+
+        while let Some(state) = states.pop() {
+            assert!(rewind.rewind(&mut system));
+            assert_eq!(system.save_state(), state);
+        }
+
+        assert!(!rewind.rewind(&mut system));
+    }
+
+    #[test]
+    fn reset_to_power_on() {
+        let fresh = counter_system().save_state();
+        let mut system = counter_system();
+        for _ in 0..10 { system.run_frame(); }
+
+        system.reset(ResetKind::Hard);
+        assert_eq!(system.save_state(), fresh);
+
+        // A soft reset leaves WRAM alone
+        for _ in 0..10 { system.run_frame(); }
+        let counter = system.mmu.read(0xC000);
+        assert_ne!(counter, 0);
+
+        system.reset(ResetKind::Soft);
+        assert_eq!(system.mmu.read(0xC000), counter);
+        system.reset(ResetKind::Hard);
+        assert_eq!(system.mmu.read(0xC000), 0);
+    }
 }
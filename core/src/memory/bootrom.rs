@@ -1,3 +1,12 @@
+use std::fs;
+use std::path::Path;
+
+// The CGB boot ROM is split in two. The second part sits after the cartridge
+// header, from 0x200 to 0x8FF.
+const DMG_SIZE: usize = 0x100;
+const CGB_SIZE: usize = 0x900;
+const HEADER_END: usize = 0x200;
+
 // The DMG boot ROM.
 // This is the most common version of the boot ROM found in the original DMG-01
 // model of Gameboy.
@@ -22,3 +31,65 @@ pub static DMG1: [u8; 0x100] = [
     0x89, 0x00, 0x0E, 0xDC, 0xCC, 0x6E, 0xE6, 0xDD, 0xDD, 0xD9, 0x99, 0xBB, 0xBB, 0x67, 0x63, 0x6E,
     0x0E, 0xEC, 0xCC, 0xDD, 0xDC, 0x99, 0x9F, 0xBB, 0xB9, 0x33, 0x3E, 0xFF, 0xFF, 0x3C, 0xE0, 0x50,
 ];
+
+#[derive(Clone)]
+pub struct BootRom {
+    data: Vec<u8>,
+}
+
+impl BootRom {
+    pub fn dmg() -> Self {
+        Self {
+            data: DMG1.to_vec(),
+        }
+    }
+
+    pub fn from_path(path: &Path) -> Result<Self, Box<dyn std::error::Error>> {
+        BootRom::from_bytes(fs::read(path)?)
+    }
+
+    pub fn from_bytes(data: Vec<u8>) -> Result<Self, Box<dyn std::error::Error>> {
+        if data.is_empty() || data.len() > CGB_SIZE {
+            return Err(format!("Invalid boot ROM size: {} bytes", data.len()).into());
+        }
+
+        Ok(Self { data })
+    }
+
+    // Whether the boot ROM covers the address while it is mapped
+    pub fn maps(&self, addr: u16) -> bool {
+        let addr = addr as usize;
+
+        if addr < DMG_SIZE {
+            addr < self.data.len()
+        } else {
+            addr >= HEADER_END && addr < self.data.len()
+        }
+    }
+
+    pub fn read(&self, addr: u16) -> u8 {
+        self.data[addr as usize]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const HEADER_START: usize = 0x100;
+
+    #[test]
+    fn cgb_boot_rom_skips_header() {
+        let bootrom = BootRom::from_bytes(vec![0xAA; CGB_SIZE]).unwrap();
+
+        assert!(bootrom.maps(0x00FF));
+        assert!(!bootrom.maps(HEADER_START as u16));
+        assert!(!bootrom.maps(0x01FF));
+        assert!(bootrom.maps(0x0200));
+        assert!(bootrom.maps(0x08FF));
+        assert!(!bootrom.maps(0x0900));
+
+        assert!(!BootRom::dmg().maps(0x0200));
+        assert!(BootRom::from_bytes(vec![0; CGB_SIZE + 1]).is_err());
+    }
+}
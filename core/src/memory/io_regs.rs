@@ -0,0 +1,50 @@
+// Bits of the IO registers from 0xFF00 to 0xFF7F as seen on a DMG.
+//
+// READ_MASKS holds the bits that always read as 1, either because they are
+// unused or because the register is write-only. Unmapped registers read 0xFF.
+//
+// WRITE_MASKS holds the bits the CPU can change. Registers with a zero mask
+// are read-only or unmapped and ignore writes.
+
+pub const IO_REGS: usize = 0x80;
+
+#[rustfmt::skip]
+pub const READ_MASKS: [u8; IO_REGS] = [
+    // P1    SB    SC    --    DIV   TIMA  TMA   TAC   --    --    --    --    --    --    --    IF
+    0xC0, 0x00, 0x7E, 0xFF, 0x00, 0x00, 0x00, 0xF8, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xE0,
+    // NR10  NR11  NR12  NR13  NR14  --    NR21  NR22  NR23  NR24  NR30  NR31  NR32  NR33  NR34  --
+    0x80, 0x3F, 0x00, 0xFF, 0xBF, 0xFF, 0x3F, 0x00, 0xFF, 0xBF, 0x7F, 0xFF, 0x9F, 0xFF, 0xBF, 0xFF,
+    // NR41  NR42  NR43  NR44  NR50  NR51  NR52  --    --    --    --    --    --    --    --    --
+    0xFF, 0x00, 0x00, 0xBF, 0x00, 0x00, 0x70, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF,
+    // Wave RAM
+    0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+    // LCDC  STAT  SCY   SCX   LY    LYC   DMA   BGP   OBP0  OBP1  WY    WX    --    --    --    --
+    0x00, 0x80, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0xFF, 0xFF, 0xFF, 0xFF,
+    // BOOT  --    --    --    --    --    --    --    --    --    --    --    --    --    --    --
+    0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF,
+    0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF,
+    0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF,
+];
+
+#[rustfmt::skip]
+pub const WRITE_MASKS: [u8; IO_REGS] = [
+    // P1    SB    SC    --    DIV   TIMA  TMA   TAC   --    --    --    --    --    --    --    IF
+    0x30, 0xFF, 0x81, 0x00, 0xFF, 0xFF, 0xFF, 0x07, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x1F,
+    // NR10  NR11  NR12  NR13  NR14  --    NR21  NR22  NR23  NR24  NR30  NR31  NR32  NR33  NR34  --
+    0x7F, 0xFF, 0xFF, 0xFF, 0xC7, 0x00, 0xFF, 0xFF, 0xFF, 0xC7, 0x80, 0xFF, 0x60, 0xFF, 0xC7, 0x00,
+    // NR41  NR42  NR43  NR44  NR50  NR51  NR52  --    --    --    --    --    --    --    --    --
+    0x3F, 0xFF, 0xFF, 0xC0, 0xFF, 0xFF, 0x80, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+    // Wave RAM
+    0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF,
+    // LCDC  STAT  SCY   SCX   LY    LYC   DMA   BGP   OBP0  OBP1  WY    WX    --    --    --    --
+    0xFF, 0x78, 0xFF, 0xFF, 0x00, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0x00, 0x00, 0x00, 0x00,
+    // BOOT  --    --    --    --    --    --    --    --    --    --    --    --    --    --    --
+    0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+    0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+    0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+];
+
+// Registers without any bit the CPU can read or write
+pub fn unmapped(index: usize) -> bool {
+    READ_MASKS[index] == 0xFF && WRITE_MASKS[index] == 0x00
+}
@@ -2,15 +2,32 @@ use crate::io::timer::Timer;
 use crate::io::joypad::{Joypad, JoypadAdapter};
 use crate::memory::Memory;
 use crate::memory::Ram;
-use crate::memory::bootrom::DMG1;
-use crate::cartridge::Cartridge;
-use crate::ppu::PPU;
-use crate::Display;
+use crate::memory::BootRom;
+use crate::memory::io_regs;
+use crate::io;
+use crate::cartridge::{Cartridge, Header};
+use crate::ppu::{PPU, Renderer};
+use crate::ppu::colors::ColorScheme;
+use crate::sgb::Sgb;
+use crate::model::Model;
+use crate::cheats::Cheats;
+use crate::{Display, LogHook, ResetKind, SerialHook};
+use crate::state::{Snapshot, StateReader, StateWriter};
+
+// M-cycles between the write to 0xFF46 and the transfer of the first byte
+const OAM_DMA_DELAY: u8 = 1;
+const OAM_DMA_LENGTH: u16 = 0xA0;
 
 struct OAMDma {
     active: bool,
     from: u16,
     index: u16,
+    // Last byte transferred, seen by the CPU on bus conflicts
+    value: u8,
+    // Source and remaining delay of a requested transfer. A transfer already
+    // running keeps going until the new one starts.
+    pending: Option<(u16, u8)>,
+    reg: u8,
 }
 
 impl OAMDma {
@@ -19,20 +36,23 @@ impl OAMDma {
             active: false,
             from: 0,
             index: 0,
+            value: 0xFF,
+            pending: None,
+            reg: 0xFF,
         }
     }
 
     pub fn start(&mut self, from: u8) {
-        self.active = true;
-        self.index = 0;
-        self.from = (from as u16) << 8;
+        self.reg = from;
+        self.pending = Some(((from as u16) << 8, OAM_DMA_DELAY));
     }
 }
 
 pub struct MMU {
     intfs: u8,
     inte: u8,
-    bootrom: bool,
+    // Mapped over the cartridge until the boot ROM writes to 0xFF50
+    bootrom: Option<BootRom>,
     cartridge: Cartridge,
     timer: Timer,
     joypad: Joypad,
@@ -40,13 +60,28 @@ pub struct MMU {
     wram: Ram,
     zram: Ram,
     sb: u8,
+    sc: u8,
+    // Sound registers and wave RAM, stored until there is an APU
+    sound: [u8; 0x30],
+    // Receives diagnostics such as accesses to unmapped registers
+    log_hook: Option<LogHook>,
+    serial_hook: Option<SerialHook>,
     oam_dma: OAMDma,
+    sgb: Option<Sgb>,
+    // Colors of the PPU without the SGB, restored when it's turned off
+    colors: ColorScheme,
+    // GameShark codes as (address, value), written on every VBlank
+    ram_cheats: Vec<(u16, u8)>,
+    // Set when the PPU enters VBlank, cleared by take_vblank
+    vblank: bool,
 }
 
 #[allow(dead_code)]
 impl MMU {
-    pub fn new(cartridge: Cartridge, display: Box<dyn Display>, bootrom: bool) -> Self {
-        Self {
+    pub fn new(cartridge: Cartridge, display: Box<dyn Display>, model: Model, bootrom: Option<BootRom>) -> Self {
+        let skip_boot = bootrom.is_none();
+
+        let mut mmu = Self {
             intfs: 0,
             inte: 0,
             bootrom: bootrom,
@@ -57,7 +92,71 @@ impl MMU {
             wram: Ram::new(0x8000),
             zram: Ram::new(0x7F),
             sb: 0,
+            sc: 0,
+            sound: [0; 0x30],
+            log_hook: None,
+            serial_hook: None,
             oam_dma: OAMDma::new(),
+            sgb: None,
+            colors: ColorScheme::default(),
+            ram_cheats: vec![],
+            vblank: false,
+        };
+
+        if skip_boot {
+            mmu.skip_boot(model);
+        }
+
+        mmu
+    }
+
+    // Leaves the registers as the boot ROM of the model would
+    fn skip_boot(&mut self, model: Model) {
+        // CGB registers aren't mapped until its own mode is emulated
+        for (addr, v) in model.post_boot_io() {
+            if addr != 0xFFFF && io_regs::unmapped((addr - 0xFF00) as usize) {
+                continue;
+            }
+
+            match addr {
+                // Keeps the channel status bits of NR52, which the CPU can't write
+                0xFF10..=0xFF3F => self.sound[(addr - 0xFF10) as usize] = v,
+                _ => self.io_write(addr, v),
+            }
+        }
+        self.timer.set_counter(model.post_boot_divider());
+    }
+
+    // Powers the components on again. The cartridge, display and settings
+    // stay, and a soft reset also keeps the RAM of the cartridge and WRAM.
+    pub fn reset(&mut self, kind: ResetKind, model: Model, bootrom: Option<BootRom>) {
+        let skip_boot = bootrom.is_none();
+
+        self.intfs = 0;
+        self.inte = 0;
+        self.bootrom = bootrom;
+        self.cartridge.reset(kind == ResetKind::Hard);
+        self.joypad = Joypad::new();
+        self.timer = Timer::new();
+        self.ppu.reset();
+
+        if kind == ResetKind::Hard {
+            self.wram = Ram::new(0x8000);
+        }
+
+        self.zram = Ram::new(0x7F);
+        self.sb = 0;
+        self.sc = 0;
+        self.sound = [0; 0x30];
+        self.oam_dma = OAMDma::new();
+        self.vblank = false;
+
+        if let Some(sgb) = &mut self.sgb {
+            *sgb = Sgb::new(self.cartridge.get_header().sgb());
+        }
+
+        if skip_boot {
+            self.skip_boot(model);
         }
     }
 
@@ -66,9 +165,17 @@ impl MMU {
         self.handle_oam_dma(ticks);
 
         self.intfs |= self.timer.step(ticks);
-        self.intfs |= self.ppu.step(ticks);
+        let ppu_intfs = self.ppu.step(ticks);
+        self.intfs |= ppu_intfs;
         self.intfs |= self.joypad.step();
 
+        if ppu_intfs & io::intf_raise(0, io::Flag::VBlank) != 0 {
+            self.apply_ram_cheats();
+            self.vblank = true;
+        }
+
+        self.handle_sgb_frame();
+
         self.intfs |= 0xE0;
     }
 
@@ -76,88 +183,236 @@ impl MMU {
         &mut self.joypad
     }
 
-      fn fast_oam_dma(&mut self, value: u8) {
-        let base = (value as u16) << 8;
-        for i in 0 .. 0xA0 {
-            let b = self.read(base + i);
-            self.write(0xFE00 + i, b);
+    pub fn get_keys(&self) -> u8 {
+        self.joypad.keys(0)
+    }
+
+    pub fn take_vblank(&mut self) -> bool {
+        std::mem::replace(&mut self.vblank, false)
+    }
+
+    pub fn set_renderer(&mut self, renderer: Renderer) {
+        self.ppu.set_renderer(renderer);
+    }
+
+    pub fn set_access_blocking(&mut self, enabled: bool) {
+        self.ppu.set_access_blocking(enabled);
+    }
+
+    pub fn set_colors(&mut self, colors: ColorScheme) {
+        self.colors = colors;
+
+        // With the SGB the colors come from its palettes
+        if self.sgb.is_none() {
+            self.ppu.set_colors(colors);
         }
     }
 
-    fn handle_oam_dma(&mut self, ticks: u32) {
-        if !self.oam_dma.active { return; }
+    // The SGB gets the shades output by the PPU and composes a 256x224
+    // screen with its border.
+    pub fn set_sgb(&mut self, enabled: bool) {
+        if enabled == self.sgb.is_some() {
+            return;
+        }
+
+        if enabled {
+            self.sgb = Some(Sgb::new(self.cartridge.get_header().sgb()));
+            self.ppu.set_colors(ColorScheme::from_shades([0, 1, 2, 3]));
+        } else {
+            self.sgb = None;
+            self.joypad.set_players(1);
+            self.ppu.set_colors(self.colors);
+        }
 
-        let cycles = (ticks / 4) as u16;
+        self.ppu.set_hold_frames(enabled);
+    }
 
-        let count  = 
-            if 0x8F - self.oam_dma.index > cycles {
-                cycles
-            } else {
-                0x8F - self.oam_dma.index
-            };
+    fn handle_sgb_frame(&mut self) {
+        let sgb = match &mut self.sgb {
+            Some(sgb) => sgb,
+            None => return,
+        };
+
+        if !self.ppu.take_frame() {
+            return;
+        }
 
-        for i in 0..count {
-            let v = self.read(self.oam_dma.from + self.oam_dma.index + i);
-            self.write(0xFE00 + self.oam_dma.index + i, v);
+        if sgb.transfer_pending() {
+            sgb.transfer(&self.ppu.screen_tiles());
         }
 
-        self.oam_dma.index += count;
+        let screen = sgb.render(self.ppu.get_framebuffer());
+        self.ppu.present(screen);
+    }
 
-        if self.oam_dma.index == 0x8F {
-            self.oam_dma.active = false;
+    // Shows the current frame again, as after loading a state
+    pub fn redraw(&mut self) {
+        match &mut self.sgb {
+            Some(sgb) => {
+                let screen = sgb.render(self.ppu.get_framebuffer());
+                self.ppu.present(screen);
+            }
+            None => self.ppu.redraw(),
+        }
+    }
+
+    pub fn get_header(&self) -> Header {
+        self.cartridge.get_header()
+    }
+
+    pub fn get_colors(&self) -> ColorScheme {
+        self.ppu.get_colors()
+    }
+
+    fn handle_oam_dma(&mut self, ticks: u32) {
+        for _ in 0..(ticks / 4) {
+            if let Some((from, delay)) = self.oam_dma.pending {
+                if delay == 0 {
+                    self.oam_dma.pending = None;
+                    self.oam_dma.active = true;
+                    self.oam_dma.from = from;
+                    self.oam_dma.index = 0;
+                } else {
+                    self.oam_dma.pending = Some((from, delay - 1));
+                }
+            }
+
+            if !self.oam_dma.active { continue; }
+
+            // Sources above 0xDFFF read from the WRAM echo
+            let from = self.oam_dma.from + self.oam_dma.index;
+            let from = if from >= 0xE000 { from - 0x2000 } else { from };
+
+            let v = self.bus_read(from);
+            self.ppu.dma_write(self.oam_dma.index, v);
+            self.oam_dma.value = v;
+            self.oam_dma.index += 1;
+
+            if self.oam_dma.index == OAM_DMA_LENGTH {
+                self.oam_dma.active = false;
+            }
         }
     }
 
     fn io_read(&self, addr: u16) -> u8 {
-        match addr {
+        if addr == 0xFFFF {
+            return self.inte;
+        }
+
+        let index = (addr - 0xFF00) as usize;
+
+        if io_regs::unmapped(index) {
+            self.log(&format!("Read from unmapped IO register 0x{:04X}", addr));
+            return 0xFF;
+        }
+
+        let v = match addr {
             0xFF00 => self.joypad.read(),
-            0xFF01..=0xFF02 => 0xFF,
+            0xFF01 => self.sb,
+            0xFF02 => self.sc,
             0xFF04 => self.timer.get_div(),
             0xFF05 => self.timer.get_tima(),
             0xFF06 => self.timer.get_tma(),
             0xFF07 => self.timer.get_tac(),
             0xFF0F => self.intfs,
-            0xFF10..=0xFF3F => 0, // TODO: Implement sound someday...
-            0xFF40..=0xFF4F => self.ppu.read(addr),
-            0xFF50 => { if self.bootrom { 1 } else { 0 } }
-            // 0xFF51..=0xFF55 => 0, // TODO DMA 
-            0xFF68..=0xFF6B => self.ppu.read(addr),
-            0xFFFF => self.inte,
-            _ => { println!("Warning: Attempt to READ from unmapped IO area: 0x{:04X}", addr); 0xFF }
-        }
+            0xFF10..=0xFF3F => self.sound[(addr - 0xFF10) as usize],
+            0xFF46 => self.oam_dma.reg,
+            0xFF40..=0xFF4B => self.ppu.read(addr),
+            _ => 0xFF,
+        };
+
+        v | io_regs::READ_MASKS[index]
     }
 
     fn io_write(&mut self, addr: u16, v: u8) {
+        if addr == 0xFFFF {
+            self.inte = v;
+            return;
+        }
+
+        let index = (addr - 0xFF00) as usize;
+
+        if io_regs::WRITE_MASKS[index] == 0 {
+            self.log(&format!("Write to read-only or unmapped IO register 0x{:04X}", addr));
+            return;
+        }
+
+        let v = v & io_regs::WRITE_MASKS[index];
+
         match addr {
-            0xFF00 => self.joypad.write(v),
+            0xFF00 => {
+                self.joypad.write(v);
+
+                if let Some(sgb) = &mut self.sgb {
+                    sgb.write(v);
+                    self.joypad.set_players(sgb.players());
+                }
+            }
             0xFF01 => { self.sb = v; }
-            0xFF02 => { if v == 0x81 { print!("{}", self.sb as char) } }
+            0xFF02 => {
+                self.sc = v;
+
+                // Without a link cable a transfer on the internal clock
+                // completes at once, shifting in 0xFF.
+                if v == 0x81 {
+                    if let Some(hook) = &mut self.serial_hook {
+                        hook(self.sb);
+                    }
+                    self.sb = 0xFF;
+                    self.sc = 0x01;
+                    self.intfs = io::intf_raise(self.intfs, io::Flag::Serial);
+                }
+            }
             0xFF04 => self.timer.set_div(v),
             0xFF05 => self.timer.set_tima(v),
             0xFF06 => self.timer.set_tma(v),
             0xFF07 => self.timer.set_tac(v),
             0xFF0F => { self.intfs = v; }
-            0xFF10..=0xFF3F => {} // TODO: Implement sound someday...
-            0xFF46 => { self.fast_oam_dma(v); } //self.oam_dma.start(v),
-            0xFF40..=0xFF4F => self.ppu.write(addr, v),
-            0xFF50 => { if (v & 1) == 1 { self.bootrom = false } }
-            // 0xFF51..=0xFF55 => {} // DMA CGB
-            0xFF68..=0xFF6B => self.ppu.write(addr, v),
-            0xFFFF => self.inte = v,
-            _ => { println!("Warning: Attempt to WRITE on unmapped IO area: 0x{:04X}", addr); }
+            0xFF10..=0xFF3F => self.sound[(addr - 0xFF10) as usize] = v, // TODO: Implement sound someday...
+            0xFF46 => self.oam_dma.start(v),
+            0xFF40..=0xFF4B => self.ppu.write(addr, v),
+            0xFF50 if (v & 1) == 1 => self.bootrom = None,
+            _ => {}
+        }
+    }
+
+    pub fn set_cheats(&mut self, cheats: &Cheats) {
+        self.cartridge.set_patches(cheats.rom_patches());
+        self.ram_cheats = cheats.ram_writes();
+    }
+
+    // Only unbanked codes are accepted. The writes skip the OAM DMA
+    // restrictions, which only apply to the CPU.
+    fn apply_ram_cheats(&mut self) {
+        for i in 0..self.ram_cheats.len() {
+            let (addr, v) = self.ram_cheats[i];
+            self.bus_write(addr, v);
         }
     }
 
+    pub fn set_log_hook(&mut self, hook: Option<LogHook>) {
+        self.log_hook = hook;
+    }
+
+    pub fn set_serial_hook(&mut self, hook: Option<SerialHook>) {
+        self.serial_hook = hook;
+    }
+
+    fn log(&self, message: &str) {
+        if let Some(hook) = &self.log_hook {
+            hook(message);
+        }
+    }
 }
 
-impl Memory for MMU {
-    fn read(&self, addr: u16) -> u8 {
+impl MMU {
+    // Reads the bus without the OAM DMA restrictions applied to the CPU
+    fn bus_read(&self, addr: u16) -> u8 {
         match addr {
             0x000..=0x7FFF => {
-                if self.bootrom && addr < 0x100 {
-                    DMG1[addr as usize]
-                } else {
-                    self.cartridge.read(addr)
+                match &self.bootrom {
+                    Some(bootrom) if bootrom.maps(addr) => bootrom.read(addr),
+                    _ => self.cartridge.read(addr),
                 }
             }
             0x8000..=0x9FFF => self.ppu.read(addr),
@@ -172,7 +427,7 @@ impl Memory for MMU {
         }
     }
 
-    fn write(&mut self, addr: u16, v: u8) {
+    fn bus_write(&mut self, addr: u16, v: u8) {
         match addr {
             0x000..=0x7FFF => self.cartridge.write(addr, v),
             0x8000..=0x9FFF => self.ppu.write(addr, v),
@@ -188,3 +443,336 @@ impl Memory for MMU {
 
     }
 }
+
+impl Memory for MMU {
+    // While OAM DMA runs the CPU can only use HRAM and the IO registers.
+    // OAM reads 0xFF and any other read returns the byte being transferred.
+    fn read(&self, addr: u16) -> u8 {
+        if self.oam_dma.active && addr < 0xFF00 {
+            return match addr {
+                0xFE00..=0xFEFF => 0xFF,
+                _ => self.oam_dma.value,
+            };
+        }
+
+        self.bus_read(addr)
+    }
+
+    fn write(&mut self, addr: u16, v: u8) {
+        if self.oam_dma.active && addr < 0xFF00 {
+            return;
+        }
+
+        self.bus_write(addr, v);
+    }
+}
+
+impl Snapshot for MMU {
+    fn save_state(&self, w: &mut StateWriter) {
+        w.u8(self.intfs);
+        w.u8(self.inte);
+        w.bool(self.bootrom.is_some());
+        self.cartridge.save_state(w);
+        self.timer.save_state(w);
+        self.joypad.save_state(w);
+        self.ppu.save_state(w);
+        self.wram.save_state(w);
+        self.zram.save_state(w);
+        w.u8(self.sb);
+        w.u8(self.sc);
+        w.bytes(&self.sound);
+
+        let dma = &self.oam_dma;
+        w.bool(dma.active);
+        w.u16(dma.from);
+        w.u16(dma.index);
+        w.u8(dma.value);
+        let (pending_from, pending_delay) = dma.pending.unwrap_or((0, 0));
+        w.bool(dma.pending.is_some());
+        w.u16(pending_from);
+        w.u8(pending_delay);
+        w.u8(dma.reg);
+
+        w.bool(self.sgb.is_some());
+        if let Some(sgb) = &self.sgb {
+            sgb.save_state(w);
+        }
+
+        w.bool(self.vblank);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> Result<(), Box<dyn std::error::Error>> {
+        self.intfs = r.u8()?;
+        self.inte = r.u8()?;
+
+        // Once unmapped the boot ROM can't come back
+        let bootrom = r.bool()?;
+        if bootrom && self.bootrom.is_none() {
+            return Err("The state was saved while running the boot ROM".into());
+        }
+
+        self.cartridge.load_state(r)?;
+        self.timer.load_state(r)?;
+        self.joypad.load_state(r)?;
+        self.ppu.load_state(r)?;
+        self.wram.load_state(r)?;
+        self.zram.load_state(r)?;
+        self.sb = r.u8()?;
+        self.sc = r.u8()?;
+        r.bytes_into(&mut self.sound)?;
+
+        let dma = &mut self.oam_dma;
+        dma.active = r.bool()?;
+        dma.from = r.u16()?;
+        dma.index = r.u16()?.min(OAM_DMA_LENGTH);
+        dma.value = r.u8()?;
+        let pending = r.bool()?;
+        let pending_from = r.u16()?;
+        let pending_delay = r.u8()?;
+        dma.pending = if pending { Some((pending_from, pending_delay)) } else { None };
+        dma.reg = r.u8()?;
+
+        match (r.bool()?, &mut self.sgb) {
+            (true, Some(sgb)) => sgb.load_state(r)?,
+            (false, None) => {}
+            _ => return Err("The state was saved with a different Super Game Boy setting".into()),
+        }
+
+        self.vblank = r.bool()?;
+
+        if !bootrom {
+            self.bootrom = None;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::NullDisplay;
+    use std::path::Path;
+
+    fn test_mmu() -> MMU {
+        let path = std::env::temp_dir().join("gamebrust-mmu-test.gb");
+        std::fs::write(&path, vec![0; 0x8000]).unwrap();
+        let cartridge = Cartridge::from_path(Path::new(&path)).unwrap();
+
+        let mut mmu = MMU::new(cartridge, Box::new(NullDisplay {}), Model::DMG, None);
+
+        // Keep the PPU from blocking OAM reads
+        mmu.write(0xFF40, 0x00);
+        mmu
+    }
+
+    #[test]
+    fn oam_dma_transfer() {
+        let mut mmu = test_mmu();
+
+        for i in 0..0xA0 {
+            mmu.write(0xC000 + i, i as u8);
+        }
+
+        mmu.write(0xFF46, 0xC0);
+        assert_eq!(mmu.read(0xFF46), 0xC0);
+
+        // Setup cycle plus the first byte
+        mmu.step(8);
+        assert_eq!(mmu.read(0xFF80), 0);
+        assert_eq!(mmu.read(0xFE00), 0xFF);
+        assert_eq!(mmu.read(0x0000), 0x00);
+        assert_eq!(mmu.read(0xC050), 0x00);
+
+        mmu.step(4 * 0x9F);
+        assert_eq!(mmu.read(0xC050), 0x50);
+        assert_eq!(mmu.read(0xFE9F), 0x9F);
+    }
+
+    #[test]
+    fn oam_dma_blocks_cpu_writes() {
+        let mut mmu = test_mmu();
+
+        mmu.write(0xFF46, 0xC0);
+        mmu.step(8);
+        mmu.write(0xC000, 0x42);
+        mmu.write(0xFF80, 0x42);
+
+        mmu.step(4 * 0x9F);
+        assert_eq!(mmu.read(0xC000), 0x00);
+        assert_eq!(mmu.read(0xFF80), 0x42);
+    }
+
+    #[test]
+    fn oam_dma_restart() {
+        let mut mmu = test_mmu();
+
+        for i in 0..0xA0 {
+            mmu.write(0xC000 + i, 0x11);
+            mmu.write(0xD000 + i, 0x22);
+        }
+
+        mmu.write(0xFF46, 0xC0);
+        mmu.step(4 * 0x10);
+        mmu.write(0xFF46, 0xD0);
+        mmu.step(4 * (0xA0 + 1));
+
+        assert_eq!(mmu.read(0xFE00), 0x22);
+        assert_eq!(mmu.read(0xFE9F), 0x22);
+    }
+
+    #[test]
+    fn post_boot_io_registers() {
+        let path = std::env::temp_dir().join("gamebrust-mmu-boot-test.gb");
+        std::fs::write(&path, vec![0; 0x8000]).unwrap();
+        let cartridge = Cartridge::from_path(Path::new(&path)).unwrap();
+
+        let mmu = MMU::new(cartridge, Box::new(NullDisplay {}), Model::DMG, None);
+
+        assert_eq!(mmu.read(0xFF40), 0x91);
+        assert_eq!(mmu.read(0xFF47), 0xFC);
+        assert_eq!(mmu.read(0xFF04), 0xAB);
+        assert_eq!(mmu.read(0xFF07), 0xF8);
+        assert_eq!(mmu.read(0xFF0F), 0xE1);
+        assert_eq!(mmu.read(0xFF46), 0xFF);
+        assert_eq!(mmu.read(0xFF02), 0x7E);
+        assert_eq!(mmu.read(0xFF26), 0xF1);
+
+        let cartridge = Cartridge::from_path(Path::new(&path)).unwrap();
+        let mmu = MMU::new(cartridge, Box::new(NullDisplay {}), Model::CGB, None);
+
+        assert_eq!(mmu.read(0xFF02), 0x7F);
+        assert_eq!(mmu.read(0xFF04), 0x1E);
+    }
+
+    #[test]
+    fn io_register_readback() {
+        let mut mmu = test_mmu();
+
+        let unmapped = [0xFF03, 0xFF08, 0xFF0E, 0xFF15, 0xFF1F, 0xFF27, 0xFF2F, 0xFF4C, 0xFF4F, 0xFF51, 0xFF68, 0xFF7F];
+        for &addr in unmapped.iter() {
+            mmu.write(addr, 0x00);
+            assert_eq!(mmu.read(addr), 0xFF, "0x{:04X}", addr);
+        }
+
+        // Registers read back as the bits written ORed with the ones that
+        // always read as 1, except for these, given as the values read after
+        // writing 0x00 and 0xFF. Writing 0xFF to SC starts a serial transfer,
+        // which ends at once without a link cable. STAT reports LY=LYC as both
+        // are 0 with the LCD off. Writing resets DIV. P1 with both groups
+        // selected reads as the directions.
+        let special = [
+            (0xFF00, 0xEF, 0xFF), (0xFF02, 0x7E, 0x7F), (0xFF04, 0x00, 0x00), (0xFF41, 0x84, 0xFC),
+        ];
+
+        for index in 0..io_regs::IO_REGS {
+            let addr = 0xFF00 + index as u16;
+            let expected = |v: u8| io_regs::WRITE_MASKS[index] & v | io_regs::READ_MASKS[index];
+            let (low, high) = match special.iter().find(|(a, _, _)| *a == addr) {
+                Some(&(_, low, high)) => (low, high),
+                None => (expected(0x00), expected(0xFF)),
+            };
+
+            mmu.write(addr, 0x00);
+            assert_eq!(mmu.read(addr), low, "0x{:04X} after writing 0x00", addr);
+            mmu.write(addr, 0xFF);
+            assert_eq!(mmu.read(addr), high, "0x{:04X} after writing 0xFF", addr);
+
+            // Turns the LCD back off and stops the OAM DMA
+            mmu.write(addr, 0x00);
+            mmu.step(4 * (0xA0 + 1));
+        }
+    }
+
+    #[test]
+    fn unmapped_access_is_logged() {
+        use std::cell::RefCell;
+        use std::rc::Rc;
+
+        let mut mmu = test_mmu();
+        let messages = Rc::new(RefCell::new(vec![]));
+        let log = messages.clone();
+        mmu.set_log_hook(Some(Box::new(move |message: &str| log.borrow_mut().push(message.to_string()))));
+
+        mmu.read(0xFF03);
+        mmu.write(0xFF44, 0x00);
+        mmu.read(0xFF40);
+
+        assert_eq!(messages.borrow().len(), 2);
+    }
+
+    #[test]
+    fn serial_output_reaches_the_hook() {
+        use std::cell::RefCell;
+        use std::rc::Rc;
+
+        let mut mmu = test_mmu();
+        let sent = Rc::new(RefCell::new(vec![]));
+        let bytes = sent.clone();
+        mmu.set_serial_hook(Some(Box::new(move |byte| bytes.borrow_mut().push(byte))));
+
+        for &byte in b"Passed".iter() {
+            mmu.write(0xFF01, byte);
+            mmu.write(0xFF02, 0x81);
+        }
+
+        // Transfers on the external clock wait for the other Game Boy
+        mmu.write(0xFF01, b'!');
+        mmu.write(0xFF02, 0x80);
+
+        assert_eq!(sent.borrow().as_slice(), b"Passed");
+    }
+
+    #[test]
+    fn cheats_patch_rom_and_write_ram() {
+        let mut mmu = test_mmu();
+        mmu.write(0xFF40, 0x91);
+
+        let cheats = Cheats::parse("
+            014238C0
+            3E1-29F
+            -01430CC0
+        ").unwrap();
+        mmu.set_cheats(&cheats);
+
+        assert_eq!(mmu.read(0x0129), 0x3E);
+        assert_eq!(mmu.read(0xC038), 0x00);
+
+        mmu.step(456 * 154);
+        assert_eq!(mmu.read(0xC038), 0x42);
+        assert_eq!(mmu.read(0xC00C), 0x00);
+    }
+
+    #[test]
+    fn ram_cheats_apply_during_oam_dma() {
+        let mut mmu = test_mmu();
+        mmu.write(0xFF40, 0x91);
+        mmu.set_cheats(&Cheats::parse("014238C0").unwrap());
+
+        // The DMA is still running when VBlank starts
+        mmu.step(456 * 144 - 40);
+        mmu.write(0xFF46, 0xC1);
+        for _ in 0..0xA0 + 1 {
+            mmu.step(4);
+        }
+
+        assert_eq!(mmu.read(0xC038), 0x42);
+    }
+
+    #[test]
+    fn colors_survive_the_sgb_setting() {
+        let mut mmu = test_mmu();
+        let pocket = ColorScheme::from_preset(crate::ppu::colors::PalettePreset::PocketGrayscale);
+
+        mmu.set_colors(pocket);
+        mmu.set_sgb(false);
+        assert_eq!(mmu.get_colors(), pocket);
+
+        // The colors set while the SGB is on come back when it's turned off
+        mmu.set_sgb(true);
+        assert_ne!(mmu.get_colors(), pocket);
+        mmu.set_colors(ColorScheme::default());
+        mmu.set_colors(pocket);
+        mmu.set_sgb(false);
+        assert_eq!(mmu.get_colors(), pocket);
+    }
+}
@@ -1,7 +1,10 @@
 mod mmu;
 mod bootrom;
+mod io_regs;
 
 pub use mmu::MMU;
+pub use bootrom::BootRom;
+use crate::state::{Snapshot, StateReader, StateWriter};
 
 pub trait Memory {
     fn read(&self, _addr: u16) -> u8;
@@ -46,3 +49,13 @@ impl Memory for Ram {
         self.data[addr as usize] = v
     }
 }
+
+impl Snapshot for Ram {
+    fn save_state(&self, w: &mut StateWriter) {
+        w.bytes(&self.data);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> Result<(), Box<dyn std::error::Error>> {
+        r.bytes_into(&mut self.data)
+    }
+}
@@ -0,0 +1,180 @@
+// IO registers as left by the DMG boot ROM, from the Power Up Sequence of
+// Pan Docs. DIV is set through the divider counter and DMA is left alone,
+// as writing it would start a transfer.
+const DMG_IO: [(u16, u8); 37] = [
+    (0xFF00, 0xCF), // P1
+    (0xFF01, 0x00), // SB
+    (0xFF02, 0x7E), // SC
+    (0xFF05, 0x00), // TIMA
+    (0xFF06, 0x00), // TMA
+    (0xFF07, 0xF8), // TAC
+    (0xFF0F, 0xE1), // IF
+    (0xFF10, 0x80), // NR10
+    (0xFF11, 0xBF), // NR11
+    (0xFF12, 0xF3), // NR12
+    (0xFF13, 0xFF), // NR13
+    (0xFF14, 0xBF), // NR14
+    (0xFF16, 0x3F), // NR21
+    (0xFF17, 0x00), // NR22
+    (0xFF18, 0xFF), // NR23
+    (0xFF19, 0xBF), // NR24
+    (0xFF1A, 0x7F), // NR30
+    (0xFF1B, 0xFF), // NR31
+    (0xFF1C, 0x9F), // NR32
+    (0xFF1D, 0xFF), // NR33
+    (0xFF1E, 0xBF), // NR34
+    (0xFF20, 0xFF), // NR41
+    (0xFF21, 0x00), // NR42
+    (0xFF22, 0x00), // NR43
+    (0xFF23, 0xBF), // NR44
+    (0xFF24, 0x77), // NR50
+    (0xFF25, 0xF3), // NR51
+    (0xFF26, 0xF1), // NR52
+    (0xFF40, 0x91), // LCDC
+    (0xFF41, 0x85), // STAT
+    (0xFF42, 0x00), // SCY
+    (0xFF43, 0x00), // SCX
+    (0xFF45, 0x00), // LYC
+    (0xFF47, 0xFC), // BGP
+    (0xFF4A, 0x00), // WY
+    (0xFF4B, 0x00), // WX
+    (0xFFFF, 0x00), // IE
+];
+
+// Registers the CGB boot ROM leaves differently in CGB mode, on top of the
+// DMG ones. The palettes are all written, so their indexes wrap around to 0.
+const CGB_IO: [(u16, u8); 12] = [
+    (0xFF02, 0x7F), // SC
+    (0xFF4D, 0x7E), // KEY1
+    (0xFF4F, 0xFE), // VBK
+    (0xFF51, 0xFF), // HDMA1
+    (0xFF52, 0xFF), // HDMA2
+    (0xFF53, 0xFF), // HDMA3
+    (0xFF54, 0xFF), // HDMA4
+    (0xFF55, 0xFF), // HDMA5
+    (0xFF56, 0x3E), // RP
+    (0xFF68, 0xC0), // BCPS
+    (0xFF6A, 0xC0), // OCPS
+    (0xFF70, 0xF8), // SVBK
+];
+
+/// Hardware revision being emulated. Games tell them apart by the CPU
+/// registers left by the boot ROM, so the model decides the state the
+/// system starts with when the boot ROM is skipped.
+#[derive(Debug, PartialEq, Clone, Copy, Default)]
+pub enum Model {
+    DMG0,
+    #[default]
+    DMG,
+    MGB,
+    SGB,
+    SGB2,
+    CGB,
+    AGB,
+}
+
+impl Model {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name.to_ascii_lowercase().as_str() {
+            "dmg0" => Some(Model::DMG0),
+            "dmg" => Some(Model::DMG),
+            "mgb" | "pocket" => Some(Model::MGB),
+            "sgb" => Some(Model::SGB),
+            "sgb2" => Some(Model::SGB2),
+            "cgb" | "color" => Some(Model::CGB),
+            "agb" | "advance" => Some(Model::AGB),
+            _ => None,
+        }
+    }
+
+    pub fn is_sgb(self) -> bool {
+        self == Model::SGB || self == Model::SGB2
+    }
+
+    /// AF, BC, DE and HL as left by the boot ROM. The DMG boot ROM leaves the
+    /// H and C flags set unless the header checksum is zero.
+    pub fn post_boot_registers(self, header_checksum: u8) -> [u16; 4] {
+        match self {
+            Model::DMG0 => [0x0100, 0xFF13, 0x00C1, 0x8403],
+            Model::DMG => {
+                let f = if header_checksum == 0 { 0x80 } else { 0xB0 };
+                [0x0100 | f, 0x0013, 0x00D8, 0x014D]
+            }
+            Model::MGB => {
+                let f = if header_checksum == 0 { 0x80 } else { 0xB0 };
+                [0xFF00 | f, 0x0013, 0x00D8, 0x014D]
+            }
+            Model::SGB => [0x0100, 0x0014, 0x0000, 0xC060],
+            Model::SGB2 => [0xFF00, 0x0014, 0x0000, 0xC060],
+            Model::CGB => [0x1180, 0x0000, 0xFF56, 0x000D],
+            Model::AGB => [0x1100, 0x0100, 0xFF56, 0x000D],
+        }
+    }
+
+    /// IO registers written when the boot ROM is skipped
+    pub fn post_boot_io(self) -> Vec<(u16, u8)> {
+        let changes: &[(u16, u8)] = match self {
+            Model::DMG0 => &[(0xFF41, 0x81)],
+            Model::DMG | Model::MGB => &[],
+            // The SGB has no sound of its own on the Game Boy side
+            Model::SGB | Model::SGB2 => &[(0xFF26, 0xF0)],
+            Model::CGB | Model::AGB => &CGB_IO,
+        };
+
+        let mut io = DMG_IO.to_vec();
+
+        for &(addr, v) in changes.iter() {
+            match io.iter_mut().find(|(a, _)| *a == addr) {
+                Some(reg) => reg.1 = v,
+                None => io.push((addr, v)),
+            }
+        }
+
+        io
+    }
+
+    /// Internal 16-bit divider counter when the boot ROM hands over, DIV
+    /// being its upper byte. The SGB boot ROM waits for the SNES, so it
+    /// doesn't take a fixed amount of time and starts with a cleared counter.
+    pub fn post_boot_divider(self) -> u16 {
+        match self {
+            Model::DMG0 => 0x1800,
+            Model::DMG | Model::MGB => 0xABCC,
+            Model::SGB | Model::SGB2 => 0x0000,
+            Model::CGB | Model::AGB => 0x1EA0,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn model_names() {
+        assert_eq!(Model::from_name("dmg0"), Some(Model::DMG0));
+        assert_eq!(Model::from_name("CGB"), Some(Model::CGB));
+        assert_eq!(Model::from_name("nes"), None);
+    }
+
+    #[test]
+    fn dmg_flags_depend_on_header_checksum() {
+        assert_eq!(Model::DMG.post_boot_registers(0x33)[0], 0x01B0);
+        assert_eq!(Model::DMG.post_boot_registers(0x00)[0], 0x0180);
+        assert_eq!(Model::CGB.post_boot_registers(0x33)[0] >> 8, 0x11);
+    }
+
+    #[test]
+    fn post_boot_io_per_model() {
+        let reg = |model: Model, addr: u16| model.post_boot_io().iter().find(|(a, _)| *a == addr).map(|(_, v)| *v);
+
+        assert_eq!(reg(Model::DMG, 0xFF02), Some(0x7E));
+        assert_eq!(reg(Model::CGB, 0xFF02), Some(0x7F));
+        assert_eq!(reg(Model::AGB, 0xFF70), Some(0xF8));
+        assert_eq!(reg(Model::DMG, 0xFF4D), None);
+        assert_eq!(reg(Model::DMG0, 0xFF41), Some(0x81));
+        assert_eq!(reg(Model::SGB2, 0xFF26), Some(0xF0));
+        assert_eq!(Model::CGB.post_boot_divider() >> 8, 0x1E);
+        assert_eq!(Model::default(), Model::DMG);
+    }
+}
@@ -0,0 +1,356 @@
+use crate::cartridge::Header;
+use crate::model::Model;
+use crate::System;
+use std::fs;
+use std::path::Path;
+
+// Key letters in the order of a frame line, after the BizHawk input logs
+const KEYS: [(char, usize); 8] = [
+    ('U', 0), // Up
+    ('D', 1), // Down
+    ('L', 2), // Left
+    ('R', 3), // Right
+    ('S', 6), // Start
+    ('s', 7), // Select
+    ('B', 5), // B
+    ('A', 4), // A
+];
+
+const BASE64: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+fn encode_base64(data: &[u8]) -> String {
+    let mut text = String::with_capacity(data.len().div_ceil(3) * 4);
+
+    for chunk in data.chunks(3) {
+        let n = chunk.iter().enumerate().fold(0, |n, (i, b)| n | (*b as u32) << (16 - 8 * i));
+
+        for i in 0..4 {
+            if i <= chunk.len() {
+                text.push(BASE64[(n >> (18 - 6 * i)) as usize & 0x3F] as char);
+            } else {
+                text.push('=');
+            }
+        }
+    }
+
+    text
+}
+
+fn decode_base64(text: &str) -> Option<Vec<u8>> {
+    let mut data = Vec::with_capacity(text.len() / 4 * 3);
+    let mut n = 0;
+    let mut bits = 0;
+
+    for c in text.trim_end_matches('=').bytes() {
+        n = (n << 6 | BASE64.iter().position(|b| *b == c)? as u32) & 0xFFFF;
+        bits += 6;
+
+        if bits >= 8 {
+            bits -= 8;
+            data.push((n >> bits) as u8);
+        }
+    }
+
+    Some(data)
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub enum Start {
+    PowerOn,
+    // A save state, loaded before the first frame
+    State(Vec<u8>),
+}
+
+/// Input movie: the keys held on every frame since power-on or since a
+/// save state. Replaying it on the same ROM and model reproduces the run
+/// exactly.
+///
+/// Movie files are text, with a header followed by one line per frame
+/// holding a letter for each key held and a '.' for each key released.
+/// Movies starting from a save state hold it in base64, on a `State:` line
+/// after `Start: State`:
+///
+/// ```text
+/// Title: TETRIS
+/// CRC32: 46df91ad
+/// Model: DMG
+/// BootRom: false
+/// Start: PowerOn
+///
+/// |UDLRSsBA|
+/// |........|
+/// |......S.|
+/// ```
+#[derive(Debug, PartialEq, Clone)]
+pub struct Movie {
+    pub title: String,
+    pub crc32: u32,
+    pub model: Model,
+    pub bootrom: bool,
+    pub start: Start,
+    // Keys held on each frame, one bit per JoypadKey
+    frames: Vec<u8>,
+}
+
+impl Movie {
+    pub fn new(header: &Header, model: Model, bootrom: bool, start: Start) -> Self {
+        Self {
+            title: header.title().to_string(),
+            crc32: header.crc32(),
+            model,
+            bootrom,
+            start,
+            frames: vec![],
+        }
+    }
+
+    pub fn from_path(path: &Path) -> Result<Self, Box<dyn std::error::Error>> {
+        Movie::parse(&fs::read_to_string(path)?)
+    }
+
+    pub fn parse(text: &str) -> Result<Self, Box<dyn std::error::Error>> {
+        let mut movie = Self {
+            title: String::new(),
+            crc32: 0,
+            model: Model::default(),
+            bootrom: false,
+            start: Start::PowerOn,
+            frames: vec![],
+        };
+
+        let mut crc32 = None;
+        let mut from_state = false;
+
+        for (n, line) in text.lines().enumerate() {
+            let line = line.trim();
+            let error = |message: &str| format!("line {}: {}", n + 1, message);
+
+            if line.is_empty() || line.starts_with('#') || line == "|UDLRSsBA|" {
+                continue;
+            }
+
+            if line.starts_with('|') {
+                let keys = Movie::parse_frame(line).ok_or_else(|| error("invalid frame"))?;
+                movie.frames.push(keys);
+                continue;
+            }
+
+            let mut parts = line.splitn(2, ':');
+            let field = parts.next().unwrap_or("").trim();
+            let value = parts.next().ok_or_else(|| error("expected a field"))?.trim();
+
+            match field {
+                "Title" => movie.title = value.to_string(),
+                "CRC32" => crc32 = Some(u32::from_str_radix(value, 16).map_err(|_| error("invalid CRC32"))?),
+                "Model" => movie.model = Model::from_name(value).ok_or_else(|| error("unknown model"))?,
+                "BootRom" => movie.bootrom = value == "true",
+                "Start" if value == "PowerOn" => from_state = false,
+                "Start" if value == "State" => from_state = true,
+                "Start" => return Err(error("expected PowerOn or State").into()),
+                "State" => {
+                    let state = decode_base64(value).ok_or_else(|| error("invalid state"))?;
+                    movie.start = Start::State(state);
+                }
+                _ => return Err(error("unknown field").into()),
+            }
+        }
+
+        movie.crc32 = crc32.ok_or("The movie has no CRC32")?;
+
+        match (from_state, &movie.start) {
+            (true, Start::PowerOn) => Err("The movie starts from a state but doesn't hold one".into()),
+            (false, Start::State(_)) => Err("The movie holds a state but starts from power-on".into()),
+            _ => Ok(movie),
+        }
+    }
+
+    fn parse_frame(line: &str) -> Option<u8> {
+        let line = line.strip_prefix('|')?.strip_suffix('|')?;
+
+        if line.chars().count() != KEYS.len() {
+            return None;
+        }
+
+        let mut keys = 0;
+
+        for (c, (letter, key)) in line.chars().zip(KEYS.iter()) {
+            if c == *letter {
+                keys |= 1 << key;
+            } else if c != '.' {
+                return None;
+            }
+        }
+
+        Some(keys)
+    }
+
+    pub fn save(&self, path: &Path) -> Result<(), Box<dyn std::error::Error>> {
+        let mut text = format!(
+            "Title: {}\nCRC32: {:08x}\nModel: {:?}\nBootRom: {}\n",
+            self.title, self.crc32, self.model, self.bootrom
+        );
+
+        match &self.start {
+            Start::PowerOn => text.push_str("Start: PowerOn\n"),
+            Start::State(state) => text.push_str(&format!("Start: State\nState: {}\n", encode_base64(state))),
+        }
+
+        text.push_str("\n|UDLRSsBA|\n");
+
+        for keys in self.frames.iter() {
+            text.push('|');
+            for (letter, key) in KEYS.iter() {
+                text.push(if keys & (1 << key) != 0 { *letter } else { '.' });
+            }
+            text.push_str("|\n");
+        }
+
+        fs::write(path, text)?;
+        Ok(())
+    }
+
+    // Fails when the movie was recorded on another ROM or model, or with a
+    // different choice about running the boot ROM
+    pub fn check(&self, header: &Header, model: Model, bootrom: bool) -> Result<(), Box<dyn std::error::Error>> {
+        if header.crc32() != self.crc32 {
+            return Err(format!(
+                "The movie was recorded on '{}' (CRC32 {:08x}), not on this ROM (CRC32 {:08x})",
+                self.title, self.crc32, header.crc32()
+            ).into());
+        }
+
+        if model != self.model {
+            return Err(format!("The movie was recorded on a {:?}", self.model).into());
+        }
+
+        if bootrom != self.bootrom {
+            let run = if self.bootrom { "running" } else { "skipping" };
+            return Err(format!("The movie was recorded {} the boot ROM", run).into());
+        }
+
+        Ok(())
+    }
+
+    // Brings a system just powered on to the start of the movie
+    pub fn load_start(&self, system: &mut System) -> Result<(), Box<dyn std::error::Error>> {
+        match &self.start {
+            Start::PowerOn => Ok(()),
+            Start::State(state) => system.load_state(state),
+        }
+    }
+
+    pub fn push(&mut self, keys: u8) {
+        self.frames.push(keys);
+    }
+
+    pub fn frame(&self, frame: usize) -> Option<u8> {
+        self.frames.get(frame).copied()
+    }
+
+    pub fn len(&self) -> usize {
+        self.frames.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.frames.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::io::joypad::JoypadKey;
+    use crate::test_utils::NullDisplay;
+
+    fn movie() -> Movie {
+        Movie {
+            title: "TETRIS".to_string(),
+            crc32: 0x46DF91AD,
+            model: Model::DMG,
+            bootrom: false,
+            start: Start::PowerOn,
+            frames: vec![],
+        }
+    }
+
+    #[test]
+    fn movie_file_round_trip() {
+        let mut movie = movie();
+        movie.push(0);
+        movie.push(1 << JoypadKey::Start as u8);
+        movie.push(1 << JoypadKey::Up as u8 | 1 << JoypadKey::A as u8);
+
+        let path = std::env::temp_dir().join("gamebrust-movie-test.gbm");
+        movie.save(&path).unwrap();
+
+        let text = fs::read_to_string(&path).unwrap();
+        assert!(text.contains("|....S...|\n|U......A|\n"));
+        assert_eq!(Movie::from_path(&path).unwrap(), movie);
+    }
+
+    #[test]
+    fn base64_round_trip() {
+        for len in 0..8 {
+            let data: Vec<u8> = (0..len).map(|i| 0xF7 - i * 37).collect();
+            assert_eq!(decode_base64(&encode_base64(&data)), Some(data));
+        }
+
+        assert_eq!(encode_base64(b"Game"), "R2FtZQ==");
+        assert_eq!(decode_base64("R2F!"), None);
+    }
+
+    #[test]
+    fn replay_from_a_state() {
+        // Increments 0xC000 while A is held
+        let system = || {
+            let mut rom = vec![0; 0x8000];
+            rom[0x100..0x10F].copy_from_slice(&[
+                0x21, 0x00, 0xC0, 0x3E, 0x10, 0xE0, 0x00, 0xF0, 0x00, 0xCB, 0x47, 0x20, 0xFA, 0x34, 0x18,
+            ]);
+            rom[0x10F] = 0xF7;
+            let cartridge = crate::cartridge::Cartridge::from_bytes(rom).unwrap();
+            System::new(cartridge, Box::new(NullDisplay {}), Model::DMG, None)
+        };
+
+        let mut recorded = system();
+        for _ in 0..10 {
+            recorded.run_frame();
+        }
+
+        let header = recorded.get_header();
+        let mut movie = Movie::new(&header, Model::DMG, false, Start::State(recorded.save_state()));
+
+        for frame in 0..20 {
+            let keys = if frame % 3 == 0 { 1 << JoypadKey::A as u8 } else { 0 };
+            recorded.set_keys(keys);
+            movie.push(keys);
+            recorded.run_frame();
+        }
+
+        let path = std::env::temp_dir().join("gamebrust-movie-state-test.gbm");
+        movie.save(&path).unwrap();
+        let movie = Movie::from_path(&path).unwrap();
+
+        let mut replayed = system();
+        movie.load_start(&mut replayed).unwrap();
+
+        for frame in 0..movie.len() {
+            replayed.set_keys(movie.frame(frame).unwrap());
+            replayed.run_frame();
+        }
+
+        assert_eq!(replayed.save_state(), recorded.save_state());
+    }
+
+    #[test]
+    fn invalid_movies() {
+        assert!(Movie::parse("Title: TETRIS\n|........|").is_err());
+        assert!(Movie::parse("CRC32: 46df91ad\n|.......|").is_err());
+        assert!(Movie::parse("CRC32: 46df91ad\n|X.......|").is_err());
+        assert!(Movie::parse("CRC32: 46df91ad\nStart: State").is_err());
+        assert!(Movie::parse("CRC32: 46df91ad\nStart: Middle").is_err());
+        assert!(Movie::parse("CRC32: 46df91ad\nStart: State\nState: R2F!").is_err());
+        assert!(Movie::parse("CRC32: 46df91ad\nState: R2FtZQ==").is_err());
+        assert_eq!(Movie::parse("CRC32: 46df91ad\n|U.......|").unwrap().frame(0), Some(1));
+    }
+}
@@ -0,0 +1,413 @@
+use std::fs;
+use std::path::Path;
+use crate::cartridge::Header;
+use crate::io::joypad::JoypadKey;
+use super::colors::{parse_shades, ColorScheme, Shades};
+
+// Colors of the CGB boot ROM, as RGB555, four per palette
+const PALETTES: [u16; 120] = [
+    0x7FFF, 0x32BF, 0x00D0, 0x0000, 0x639F, 0x4279, 0x15B0, 0x04CB,
+    0x7FFF, 0x6E31, 0x454A, 0x0000, 0x7FFF, 0x1BEF, 0x0200, 0x0000,
+    0x7FFF, 0x421F, 0x1CF2, 0x0000, 0x7FFF, 0x5294, 0x294A, 0x0000,
+    0x7FFF, 0x03FF, 0x012F, 0x0000, 0x7FFF, 0x03EF, 0x01D6, 0x0000,
+    0x7FFF, 0x42B5, 0x3DC8, 0x0000, 0x7E74, 0x03FF, 0x0180, 0x0000,
+    0x67FF, 0x77AC, 0x1A13, 0x2D6B, 0x7ED6, 0x4BFF, 0x2175, 0x0000,
+    0x53FF, 0x4A5F, 0x7E52, 0x0000, 0x4FFF, 0x7ED2, 0x3A4C, 0x1CE0,
+    0x03ED, 0x7FFF, 0x255F, 0x0000, 0x036A, 0x021F, 0x03FF, 0x7FFF,
+    0x7FFF, 0x01DF, 0x0112, 0x0000, 0x231F, 0x035F, 0x00F2, 0x0009,
+    0x7FFF, 0x03EA, 0x011F, 0x0000, 0x299F, 0x001A, 0x000C, 0x0000,
+    0x7FFF, 0x027F, 0x001F, 0x0000, 0x7FFF, 0x03E0, 0x0206, 0x0120,
+    0x7FFF, 0x7EEB, 0x001F, 0x7C00, 0x7FFF, 0x3FFF, 0x7E00, 0x001F,
+    0x7FFF, 0x03FF, 0x001F, 0x0000, 0x03FF, 0x001F, 0x000C, 0x0000,
+    0x7FFF, 0x033F, 0x0193, 0x0000, 0x0000, 0x4200, 0x037F, 0x7FFF,
+    0x7FFF, 0x7E8C, 0x7C00, 0x0000, 0x7FFF, 0x1BEF, 0x6180, 0x0000,
+];
+
+// Palettes of the OBJ0, OBJ1 and BG layers
+const fn combination(obj0: usize, obj1: usize, bg: usize) -> (usize, usize, usize) {
+    (obj0 * 4, obj1 * 4, bg * 4)
+}
+
+// Offsets in PALETTES of the OBJ0, OBJ1 and BG colors picked by the boot
+// ROM. A few of them start in the middle of a palette.
+const COMBINATIONS: [(usize, usize, usize); 51] = [
+    combination(4, 4, 29), combination(18, 18, 18), combination(20, 20, 20), combination(24, 24, 24),
+    combination(9, 9, 9), combination(0, 0, 0), combination(27, 27, 27), combination(5, 5, 5),
+    combination(12, 12, 12), combination(26, 26, 26), combination(16, 8, 8), combination(4, 28, 28),
+    combination(4, 2, 2), combination(3, 4, 4), combination(4, 29, 29), combination(28, 4, 28),
+    combination(2, 17, 2), combination(16, 16, 8), combination(4, 4, 7), combination(4, 4, 18),
+    combination(4, 4, 20), combination(19, 19, 9), (15, 15, 44), combination(17, 17, 2),
+    combination(4, 4, 2), combination(4, 4, 3), combination(28, 28, 0), combination(3, 3, 0),
+    combination(0, 0, 1), combination(18, 22, 18), combination(20, 22, 20), combination(24, 22, 24),
+    combination(16, 22, 8), combination(17, 4, 13), (111, 0, 56), (111, 16, 60),
+    combination(19, 22, 9), combination(16, 28, 10), combination(4, 23, 28), combination(17, 22, 2),
+    combination(4, 0, 2), combination(4, 28, 3), combination(28, 3, 0), combination(3, 28, 4),
+    combination(21, 28, 4), combination(3, 28, 0), combination(25, 3, 28), combination(0, 28, 8),
+    combination(4, 3, 28), combination(28, 3, 6), combination(4, 28, 29),
+];
+
+// Combination used for titles missing from the table
+const DEFAULT_COMBINATION: usize = 0;
+
+// Title hashes of the boot ROM with their combination. Titles sharing a
+// hash are told apart by their 4th letter.
+const TITLES: [(u8, Option<u8>, usize); 93] = [
+    (0x88, None, 4), (0x16, None, 5), (0x36, None, 35), (0xD1, None, 34),
+    (0xDB, None, 3), (0xF2, None, 31), (0x3C, None, 15), (0x8C, None, 10),
+    (0x92, None, 5), (0x3D, None, 19), (0x5C, None, 36), (0x58, None, 7),
+    (0xC9, None, 37), (0x3E, None, 30), (0x70, None, 44), (0x1D, None, 21),
+    (0x59, None, 32), (0x69, None, 31), (0x19, None, 20), (0x35, None, 5),
+    (0xA8, None, 33), (0x14, None, 13), (0xAA, None, 14), (0x75, None, 5),
+    (0x95, None, 29), (0x99, None, 5), (0x34, None, 18), (0x6F, None, 9),
+    (0x15, None, 3), (0xFF, None, 2), (0x97, None, 26), (0x4B, None, 25),
+    (0x90, None, 25), (0x17, None, 41), (0x10, None, 42), (0x39, None, 26),
+    (0xF7, None, 45), (0xF6, None, 42), (0xA2, None, 45), (0x49, None, 36),
+    (0x4E, None, 38), (0x43, None, 26), (0x68, None, 42), (0xE0, None, 30),
+    (0x8B, None, 41), (0xF0, None, 34), (0xCE, None, 34), (0x0C, None, 5),
+    (0x29, None, 42), (0xE8, None, 6), (0xB7, None, 5), (0x86, None, 33),
+    (0x9A, None, 25), (0x52, None, 42), (0x01, None, 42), (0x9D, None, 40),
+    (0x71, None, 2), (0x9C, None, 16), (0xBD, None, 25), (0x5D, None, 42),
+    (0x6D, None, 42), (0x67, None, 5), (0x3F, None, 0), (0x6B, None, 39),
+    (0xB3, Some(b'B'), 36), (0x46, Some(b'E'), 22), (0x28, Some(b'F'), 25), (0xA5, Some(b'A'), 6),
+    (0xC6, Some(b'A'), 32), (0xD3, Some(b'R'), 12), (0x27, Some(b'B'), 36), (0x61, Some(b'E'), 11),
+    (0x18, Some(b'K'), 39), (0x66, Some(b'E'), 18), (0x6A, Some(b'K'), 39), (0xBF, Some(b' '), 24),
+    (0x0D, Some(b'R'), 31), (0xF4, Some(b'-'), 50), (0xB3, Some(b'U'), 17), (0x46, Some(b'R'), 46),
+    (0x28, Some(b'A'), 6), (0xA5, Some(b'R'), 27), (0xC6, Some(b' '), 0), (0xD3, Some(b'I'), 47),
+    (0x27, Some(b'N'), 41), (0x61, Some(b'A'), 41), (0x18, Some(b'I'), 0), (0x66, Some(b'L'), 0),
+    (0x6A, Some(b'I'), 19), (0xBF, Some(b'C'), 34), (0x0D, Some(b'E'), 23), (0xF4, Some(b' '), 18),
+    (0xB3, Some(b'R'), 29),
+];
+
+// RGB555 to 0x00RRGGBB
+fn rgb(color: u16) -> u32 {
+    let expand = |c: u16| ((c << 3) | (c >> 2)) as u32 & 0xFF;
+    expand(color & 0x1F) << 16 | expand((color >> 5) & 0x1F) << 8 | expand((color >> 10) & 0x1F)
+}
+
+fn shades(offset: usize) -> Shades {
+    let mut shades = [0; 4];
+    for (i, shade) in shades.iter_mut().enumerate() {
+        *shade = rgb(PALETTES[offset + i]);
+    }
+    shades
+}
+
+fn combination_colors(index: usize) -> ColorScheme {
+    let (obj0, obj1, bg) = COMBINATIONS[index];
+    ColorScheme { bg: shades(bg), obj0: shades(obj0), obj1: shades(obj1) }
+}
+
+/// Palettes the CGB boot ROM lets the user pick by holding a direction,
+/// optionally with A or B, while the logo is shown.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Combo {
+    Up,
+    UpA,
+    UpB,
+    Left,
+    LeftA,
+    LeftB,
+    Down,
+    DownA,
+    DownB,
+    Right,
+    RightA,
+    RightB,
+}
+
+impl Combo {
+    pub fn from_keys(direction: JoypadKey, button: Option<JoypadKey>) -> Option<Self> {
+        let combo = match (direction, button) {
+            (JoypadKey::Up, None) => Combo::Up,
+            (JoypadKey::Up, Some(JoypadKey::A)) => Combo::UpA,
+            (JoypadKey::Up, Some(JoypadKey::B)) => Combo::UpB,
+            (JoypadKey::Left, None) => Combo::Left,
+            (JoypadKey::Left, Some(JoypadKey::A)) => Combo::LeftA,
+            (JoypadKey::Left, Some(JoypadKey::B)) => Combo::LeftB,
+            (JoypadKey::Down, None) => Combo::Down,
+            (JoypadKey::Down, Some(JoypadKey::A)) => Combo::DownA,
+            (JoypadKey::Down, Some(JoypadKey::B)) => Combo::DownB,
+            (JoypadKey::Right, None) => Combo::Right,
+            (JoypadKey::Right, Some(JoypadKey::A)) => Combo::RightA,
+            (JoypadKey::Right, Some(JoypadKey::B)) => Combo::RightB,
+            _ => return None,
+        };
+
+        Some(combo)
+    }
+
+    /// Parses names such as "left" or "up+a"
+    pub fn from_name(name: &str) -> Option<Self> {
+        let name = name.to_ascii_lowercase();
+        let mut parts = name.split('+');
+
+        let direction = match parts.next()? {
+            "up" => JoypadKey::Up,
+            "left" => JoypadKey::Left,
+            "down" => JoypadKey::Down,
+            "right" => JoypadKey::Right,
+            _ => return None,
+        };
+
+        let button = match parts.next() {
+            None => None,
+            Some("a") => Some(JoypadKey::A),
+            Some("b") => Some(JoypadKey::B),
+            Some(_) => return None,
+        };
+
+        if parts.next().is_some() {
+            return None;
+        }
+
+        Combo::from_keys(direction, button)
+    }
+
+    pub fn colors(self) -> ColorScheme {
+        let index = match self {
+            Combo::Up => 5,
+            Combo::UpA => 43,
+            Combo::UpB => 28,
+            Combo::Left => 48,
+            Combo::LeftA => 40,
+            Combo::LeftB => 7,
+            Combo::Down => 8,
+            Combo::DownA => 3,
+            Combo::DownB => 49,
+            Combo::Right => 1,
+            Combo::RightA => DEFAULT_COMBINATION,
+            Combo::RightB => 6,
+        };
+
+        combination_colors(index)
+    }
+}
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+struct TitleEntry {
+    hash: u8,
+    // Needed when several titles share the same hash
+    letter: Option<u8>,
+    colors: ColorScheme,
+}
+
+/// Title lookup table used by the CGB boot ROM. Games are identified by the
+/// sum of their title bytes and, on collisions, by the 4th title letter.
+/// The default table is the one of the boot ROM.
+#[derive(Debug, PartialEq, Clone)]
+pub struct TitleTable {
+    entries: Vec<TitleEntry>,
+}
+
+impl Default for TitleTable {
+    fn default() -> Self {
+        let entries = TITLES.iter()
+            .map(|&(hash, letter, index)| TitleEntry { hash, letter, colors: combination_colors(index) })
+            .collect();
+
+        Self { entries }
+    }
+}
+
+impl TitleTable {
+    /// Loads a table file overriding entries of the default table. Each line
+    /// maps a title hash, optionally followed by the 4th title letter, to a
+    /// combo name or to four hex colors. Colors can be restricted to a
+    /// single layer:
+    ///
+    /// ```text
+    /// # Hash  Letter  Colors
+    /// 14: up+a
+    /// 61 E: bg FFFFFF 63A5FF 0000FF 000000
+    /// ```
+    pub fn from_path(path: &Path) -> Result<Self, Box<dyn std::error::Error>> {
+        TitleTable::parse(&fs::read_to_string(path)?)
+    }
+
+    pub fn parse(text: &str) -> Result<Self, Box<dyn std::error::Error>> {
+        let mut entries = TitleTable::default().entries;
+
+        for (n, line) in text.lines().enumerate() {
+            let line = line.split('#').next().unwrap_or("").trim();
+
+            if line.is_empty() {
+                continue;
+            }
+
+            let i = line.find(':').ok_or_else(|| format!("line {}: expected ':'", n + 1))?;
+            let mut key = line[..i].split_whitespace();
+            let value = line[i + 1..].trim();
+
+            let hash = key.next()
+                .and_then(|hash| u8::from_str_radix(hash.trim_start_matches("0x"), 16).ok())
+                .ok_or_else(|| format!("line {}: invalid title hash", n + 1))?;
+
+            let letter = match key.next() {
+                Some(letter) if letter.len() == 1 => Some(letter.as_bytes()[0]),
+                Some(_) => return Err(format!("line {}: invalid title letter", n + 1).into()),
+                None => None,
+            };
+
+            let base = entries.iter()
+                .find(|entry| entry.hash == hash && entry.letter == letter)
+                .map(|entry| entry.colors);
+
+            let colors = match Combo::from_name(value) {
+                Some(combo) => combo.colors(),
+                None => {
+                    // Single layer lines refine the current entry of the title
+                    let mut colors = base.unwrap_or_else(|| Combo::RightA.colors());
+                    let (layer, text) = match value.split_whitespace().next() {
+                        Some(layer @ "bg") | Some(layer @ "obj0") | Some(layer @ "obj1") => {
+                            (Some(layer), &value[layer.len()..])
+                        }
+                        _ => (None, value),
+                    };
+
+                    let shades = parse_shades(text)
+                        .ok_or_else(|| format!("line {}: expected a combo or four hex colors", n + 1))?;
+
+                    match layer {
+                        Some("bg") => colors.bg = shades,
+                        Some("obj0") => colors.obj0 = shades,
+                        Some("obj1") => colors.obj1 = shades,
+                        _ => colors = ColorScheme::from_shades(shades),
+                    }
+
+                    colors
+                }
+            };
+
+            entries.retain(|entry| !(entry.hash == hash && entry.letter == letter));
+            entries.push(TitleEntry { hash, letter, colors });
+        }
+
+        Ok(Self { entries })
+    }
+
+    fn lookup(&self, hash: u8, letter: u8) -> Option<ColorScheme> {
+        self.entries.iter()
+            .find(|entry| entry.hash == hash && entry.letter == Some(letter))
+            .or_else(|| self.entries.iter().find(|entry| entry.hash == hash && entry.letter.is_none()))
+            .map(|entry| entry.colors)
+    }
+}
+
+/// Picks the colors a Game Boy Color would use for a DMG-only cartridge.
+/// A manual combo always wins. Otherwise Nintendo titles found in the table
+/// get their own palette and everything else gets the Right + A one.
+/// Returns None for cartridges with CGB support.
+pub fn colorize(header: &Header, titles: &TitleTable, combo: Option<Combo>) -> Option<ColorScheme> {
+    if !header.dmg_only() {
+        return None;
+    }
+
+    if let Some(combo) = combo {
+        return Some(combo.colors());
+    }
+
+    let colors = if header.nintendo_licensed() {
+        titles.lookup(header.title_hash(), header.title_letter())
+    } else {
+        None
+    };
+
+    Some(colors.unwrap_or_else(|| Combo::RightA.colors()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn header(title: &str, licensee: u8) -> Header {
+        let mut rom = vec![0; 0x150];
+        rom[0x134..0x134 + title.len()].copy_from_slice(title.as_bytes());
+        rom[0x14B] = licensee;
+        Header::read(&rom).unwrap()
+    }
+
+    #[test]
+    fn combo_names() {
+        assert_eq!(Combo::from_name("up"), Some(Combo::Up));
+        assert_eq!(Combo::from_name("Left+B"), Some(Combo::LeftB));
+        assert_eq!(Combo::from_name("right+a"), Some(Combo::RightA));
+        assert_eq!(Combo::from_name("up+start"), None);
+        assert_eq!(Combo::from_name("a+up"), None);
+    }
+
+    #[test]
+    fn title_hash_and_letter() {
+        let header = header("POKEMON BLUE", 0x01);
+        assert_eq!(header.title_hash(), 0x61);
+        assert_eq!(header.title_letter(), b'E');
+    }
+
+    #[test]
+    fn lookup_by_hash_and_letter() {
+        let titles = TitleTable::parse("
+            # Both titles share the 0x61 hash
+            61: down
+            61 E: left
+            61 E: obj1 FFFFFF A5A5A5 525252 000000
+        ").unwrap();
+
+        let blue = colorize(&header("POKEMON BLUE", 0x01), &titles, None).unwrap();
+        assert_eq!(blue.bg, Combo::Left.colors().bg);
+        assert_eq!(blue.obj0, Combo::Left.colors().obj0);
+        assert_eq!(blue.obj1, [0xFFFFFF, 0xA5A5A5, 0x525252, 0x000000]);
+
+        // Same hash, different 4th letter
+        let other = colorize(&header("POKDMON BLUF", 0x01), &titles, None).unwrap();
+        assert_eq!(other, Combo::Down.colors());
+    }
+
+    #[test]
+    fn defaults_and_overrides() {
+        let titles = TitleTable::parse("61 E: left").unwrap();
+
+        // Third party games get the default palette
+        let third_party = colorize(&header("POKEMON BLUE", 0x08), &titles, None);
+        assert_eq!(third_party, Some(Combo::RightA.colors()));
+
+        let manual = colorize(&header("POKEMON BLUE", 0x01), &titles, Some(Combo::RightB));
+        assert_eq!(manual, Some(Combo::RightB.colors()));
+
+        let mut rom = vec![0; 0x150];
+        rom[0x143] = 0x80;
+        assert_eq!(colorize(&Header::read(&rom).unwrap(), &titles, None), None);
+    }
+
+    #[test]
+    fn boot_rom_table() {
+        let titles = TitleTable::default();
+
+        let tetris = colorize(&header("TETRIS", 0x01), &titles, None).unwrap();
+        assert_eq!(tetris, ColorScheme::from_shades([0xFFFFFF, 0xFFFF00, 0xFF0000, 0x000000]));
+
+        let red = colorize(&header("POKEMON RED", 0x01), &titles, None).unwrap();
+        assert_eq!(red.bg, [0xFFFFFF, 0xFF8484, 0x943939, 0x000000]);
+        assert_eq!(red.obj0, [0xFFFFFF, 0x7BFF31, 0x008400, 0x000000]);
+        assert_eq!(red.obj1, red.bg);
+
+        // Shares its hash with titles that have another 4th letter
+        let blue = colorize(&header("POKEMON BLUE", 0x01), &titles, None).unwrap();
+        assert_eq!(blue.bg, [0xFFFFFF, 0x63A5FF, 0x0000FF, 0x000000]);
+
+        // Sprites of Super Mario Land start in the middle of a palette
+        let mario = colorize(&header("SUPER MARIOLAND", 0x01), &titles, None).unwrap();
+        assert_eq!(mario.obj0, [0x000000, 0xFFFFFF, 0xFF8484, 0x943939]);
+
+        // Files override single entries
+        let titles = TitleTable::parse("DB: left").unwrap();
+        assert_eq!(colorize(&header("TETRIS", 0x01), &titles, None), Some(Combo::Left.colors()));
+        assert_eq!(colorize(&header("POKEMON RED", 0x01), &titles, None), Some(red));
+    }
+
+    #[test]
+    fn invalid_table() {
+        assert!(TitleTable::parse("61 left").is_err());
+        assert!(TitleTable::parse("XY: left").is_err());
+        assert!(TitleTable::parse("61 EE: left").is_err());
+        assert!(TitleTable::parse("61: sideways").is_err());
+    }
+}
@@ -0,0 +1,154 @@
+use std::fs;
+use std::path::Path;
+
+/// RGB values for the four DMG shades, from lightest to darkest.
+pub type Shades = [u32; 4];
+
+const CLASSIC_GREEN: Shades = [0xACB56A, 0x848F58, 0x404D40, 0x2C373D];
+const POCKET_GRAYSCALE: Shades = [0xE3E6C9, 0xC3C4A5, 0x8E8B61, 0x6C6C4E];
+const LIGHT: Shades = [0x00B581, 0x009A71, 0x00694A, 0x004F3B];
+const HIGH_CONTRAST: Shades = [0xFFFFFF, 0xAAAAAA, 0x555555, 0x000000];
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum PalettePreset {
+    ClassicGreen,
+    PocketGrayscale,
+    Light,
+    HighContrast,
+}
+
+impl PalettePreset {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "green" | "classic" | "classic-green" => Some(PalettePreset::ClassicGreen),
+            "pocket" | "grayscale" | "pocket-grayscale" => Some(PalettePreset::PocketGrayscale),
+            "light" => Some(PalettePreset::Light),
+            "contrast" | "high-contrast" => Some(PalettePreset::HighContrast),
+            _ => None,
+        }
+    }
+
+    fn shades(self) -> Shades {
+        match self {
+            PalettePreset::ClassicGreen => CLASSIC_GREEN,
+            PalettePreset::PocketGrayscale => POCKET_GRAYSCALE,
+            PalettePreset::Light => LIGHT,
+            PalettePreset::HighContrast => HIGH_CONTRAST,
+        }
+    }
+}
+
+/// Colors used to render each DMG layer. The BGP, OBP0 and OBP1 registers
+/// select among the four shades of their layer.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct ColorScheme {
+    pub bg: Shades,
+    pub obj0: Shades,
+    pub obj1: Shades,
+}
+
+impl Default for ColorScheme {
+    fn default() -> Self {
+        ColorScheme::from_preset(PalettePreset::ClassicGreen)
+    }
+}
+
+impl ColorScheme {
+    pub fn from_shades(shades: Shades) -> Self {
+        Self {
+            bg: shades,
+            obj0: shades,
+            obj1: shades,
+        }
+    }
+
+    pub fn from_preset(preset: PalettePreset) -> Self {
+        ColorScheme::from_shades(preset.shades())
+    }
+
+    /// Loads a palette file. Each line holds four hex colors, lightest first,
+    /// optionally prefixed by the layer they apply to:
+    ///
+    /// ```text
+    /// # Lines without a layer apply to all of them
+    /// E0F8D0 88C070 346856 081820
+    /// obj1: FFFFFF AAAAAA 555555 000000
+    /// ```
+    pub fn from_path(path: &Path) -> Result<Self, Box<dyn std::error::Error>> {
+        ColorScheme::parse(&fs::read_to_string(path)?)
+    }
+
+    pub fn parse(text: &str) -> Result<Self, Box<dyn std::error::Error>> {
+        let mut scheme = ColorScheme::default();
+
+        for (n, line) in text.lines().enumerate() {
+            let line = line.split('#').next().unwrap_or("").trim();
+
+            if line.is_empty() {
+                continue;
+            }
+
+            let (layer, colors) = match line.find([':', '=']) {
+                Some(i) => (Some(line[..i].trim()), &line[i + 1..]),
+                None => (None, line),
+            };
+
+            let shades = parse_shades(colors)
+                .ok_or_else(|| format!("line {}: expected four hex colors", n + 1))?;
+
+            match layer {
+                None => scheme = ColorScheme::from_shades(shades),
+                Some("bg") => scheme.bg = shades,
+                Some("obj0") => scheme.obj0 = shades,
+                Some("obj1") => scheme.obj1 = shades,
+                Some(layer) => return Err(format!("line {}: unknown layer '{}'", n + 1, layer).into()),
+            }
+        }
+
+        Ok(scheme)
+    }
+}
+
+pub(super) fn parse_shades(text: &str) -> Option<Shades> {
+    let mut shades = [0; 4];
+    let mut count = 0;
+
+    for word in text.split(|c: char| c.is_whitespace() || c == ',').filter(|w| !w.is_empty()) {
+        let hex = word.trim_start_matches("0x");
+
+        if count == 4 || hex.len() != 6 {
+            return None;
+        }
+
+        shades[count] = u32::from_str_radix(hex, 16).ok()?;
+        count += 1;
+    }
+
+    if count == 4 { Some(shades) } else { None }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_palette_file() {
+        let scheme = ColorScheme::parse("
+            # Original DMG
+            bg: 0xACB56A 0x848F58 0x404D40 0x2C373D
+            E0F8D0, 88C070, 346856, 081820
+            obj1: FFFFFF AAAAAA 555555 000000
+        ").unwrap();
+
+        assert_eq!(scheme.bg, [0xE0F8D0, 0x88C070, 0x346856, 0x081820]);
+        assert_eq!(scheme.obj0, scheme.bg);
+        assert_eq!(scheme.obj1, HIGH_CONTRAST);
+    }
+
+    #[test]
+    fn parse_invalid_palette_file() {
+        assert!(ColorScheme::parse("bg: FFFFFF AAAAAA 555555").is_err());
+        assert!(ColorScheme::parse("obj2: FFFFFF AAAAAA 555555 000000").is_err());
+        assert!(ColorScheme::parse("FFFFFF AAAAAA 555555 00000G").is_err());
+    }
+}
@@ -0,0 +1,491 @@
+use std::collections::VecDeque;
+use crate::memory::Memory;
+use super::{PPU, TileMap, TileSet, Sprite, SCREEN_W};
+use crate::state::{Snapshot, StateReader, StateWriter};
+
+// The first tile fetched on each line is thrown away by the hardware, which
+// delays the first pixel by the length of a full fetch.
+const FIRST_FETCH_DOTS: u8 = 6;
+// Dots spent by the sprite fetcher once the background fetcher is stopped.
+const SPRITE_FETCH_DOTS: u8 = 6;
+
+#[derive(PartialEq, Clone, Copy)]
+enum Source {
+    Background,
+    Obj0,
+    Obj1,
+}
+
+#[derive(Clone, Copy)]
+struct Pixel {
+    color: u8,
+    source: Source,
+    bg_priority: bool,
+}
+
+impl Pixel {
+    fn transparent() -> Self {
+        Self {
+            color: 0,
+            source: Source::Obj0,
+            bg_priority: false,
+        }
+    }
+}
+
+#[derive(PartialEq, Clone, Copy)]
+enum Step {
+    Tile,
+    DataLow,
+    DataHigh,
+    Push,
+}
+
+struct Fetcher {
+    step: Step,
+    dots: u8,
+    map_x: u8,
+    tile: u8,
+    low: u8,
+    high: u8,
+    window: bool,
+}
+
+impl Fetcher {
+    fn new() -> Self {
+        Self {
+            step: Step::Tile,
+            dots: 0,
+            map_x: 0,
+            tile: 0,
+            low: 0,
+            high: 0,
+            window: false,
+        }
+    }
+}
+
+pub struct PixelFifo {
+    bg: VecDeque<Pixel>,
+    obj: VecDeque<Pixel>,
+    fetcher: Fetcher,
+    // Sprites on the current line still waiting to be fetched, by priority
+    sprites: Vec<Sprite>,
+    // Dots left for the sprite fetcher, the pixel output is stalled meanwhile
+    sprite_dots: u8,
+    sprite: Option<Sprite>,
+    // Pixels discarded at the start of the line to apply SCX fine scroll
+    discard: u8,
+    stall: u8,
+    lx: u8,
+}
+
+impl PixelFifo {
+    pub fn new() -> Self {
+        Self {
+            bg: VecDeque::with_capacity(16),
+            obj: VecDeque::with_capacity(8),
+            fetcher: Fetcher::new(),
+            sprites: Vec::with_capacity(10),
+            sprite_dots: 0,
+            sprite: None,
+            discard: 0,
+            stall: 0,
+            lx: 0,
+        }
+    }
+}
+
+fn tile_line_addr(set: &TileSet, tile: u8, y: u8) -> u16 {
+    TileSet::base_addr(set) + TileSet::tile_offset(set, tile) + ((y % 8) as u16) * 2
+}
+
+impl PPU {
+    pub(super) fn fifo_start_line(&mut self) {
+        let mut sprites = if self.sprites_enabled { self.oam_search() } else { vec![] };
+
+        // Sprites fully hidden by the left edge are never fetched
+        sprites.retain(|sprite| sprite.x > -8);
+
+        let fifo = &mut self.fifo;
+        fifo.bg.clear();
+        fifo.obj.clear();
+        fifo.fetcher = Fetcher::new();
+        fifo.sprites = sprites;
+        fifo.sprite_dots = 0;
+        fifo.sprite = None;
+        fifo.discard = self.scx & 0x07;
+        fifo.stall = FIRST_FETCH_DOTS;
+        fifo.lx = 0;
+    }
+
+    // Advances the pixel pipeline by one dot. Returns true once the 160
+    // pixels of the line have been pushed to the LCD.
+    pub(super) fn fifo_dot(&mut self) -> bool {
+        if self.fifo.stall > 0 {
+            self.fifo.stall -= 1;
+            return false;
+        }
+
+        if self.fifo.sprite_dots > 0 {
+            self.fifo.sprite_dots -= 1;
+            if self.fifo.sprite_dots == 0 {
+                self.fetch_sprite();
+            }
+            return false;
+        }
+
+        self.check_window();
+
+        if self.sprites_enabled {
+            if let Some(i) = self.next_sprite() {
+                // The background fetcher has to finish the current tile
+                // before the sprite fetcher can take over.
+                if self.fifo.fetcher.step != Step::Push {
+                    self.fetcher_dot();
+                    return false;
+                }
+
+                self.fifo.sprite = Some(self.fifo.sprites.remove(i));
+                self.fifo.sprite_dots = SPRITE_FETCH_DOTS - 1;
+                if self.fifo.sprite_dots == 0 {
+                    self.fetch_sprite();
+                }
+                return false;
+            }
+        }
+
+        self.fetcher_dot();
+        self.output_pixel();
+
+        self.fifo.lx >= SCREEN_W as u8
+    }
+
+    pub(super) fn fifo_window_drawn(&self) -> bool {
+        self.fifo.fetcher.window
+    }
+
+    fn check_window(&mut self) {
+        if self.fifo.fetcher.window || !self.window_active() {
+            return;
+        }
+
+        if !self.window_wrap && (self.fifo.lx as u16 + 7) < self.wx as u16 {
+            return;
+        }
+
+        // With WX below 7 the window starts at the left edge of the screen
+        // but its first 7-WX pixels are shifted out.
+        self.fifo.discard = if self.window_wrap { 0 } else { 7_u8.saturating_sub(self.wx) };
+        self.fifo.bg.clear();
+        self.fifo.fetcher = Fetcher::new();
+        self.fifo.fetcher.window = true;
+    }
+
+    fn next_sprite(&self) -> Option<usize> {
+        let lx = self.fifo.lx as i16;
+
+        self.fifo.sprites.iter().position(|sprite| sprite.x <= lx)
+    }
+
+    fn fetcher_dot(&mut self) {
+        let fetcher = &mut self.fifo.fetcher;
+
+        if fetcher.step == Step::Push {
+            if self.fifo.bg.is_empty() {
+                for b in (0..8).rev() {
+                    let l = (fetcher.low >> b) & 1;
+                    let h = (fetcher.high >> b) & 1;
+                    self.fifo.bg.push_back(Pixel {
+                        color: h << 1 | l,
+                        source: Source::Background,
+                        bg_priority: false,
+                    });
+                }
+                fetcher.map_x = fetcher.map_x.wrapping_add(1);
+                fetcher.step = Step::Tile;
+            }
+            return;
+        }
+
+        fetcher.dots += 1;
+        if fetcher.dots < 2 {
+            return;
+        }
+        fetcher.dots = 0;
+
+        let (map, x, y) = if fetcher.window {
+            (&self.window_map, fetcher.map_x, self.window_line)
+        } else {
+            let bg_x = (self.scx >> 3).wrapping_add(fetcher.map_x) & 0x1F;
+            let bg_y = self.scy.wrapping_add(self.ly);
+            (&self.background_map, bg_x, bg_y)
+        };
+
+        match fetcher.step {
+            Step::Tile => {
+                let addr = TileMap::base_addr(map) + (x as u16 & 0x1F) + ((y as u16 >> 3) << 5);
+                fetcher.tile = self.vram.read(addr);
+                fetcher.step = Step::DataLow;
+            }
+            Step::DataLow => {
+                let addr = tile_line_addr(&self.tile_data, fetcher.tile, y);
+                fetcher.low = self.vram.read(addr);
+                fetcher.step = Step::DataHigh;
+            }
+            Step::DataHigh => {
+                let addr = tile_line_addr(&self.tile_data, fetcher.tile, y);
+                fetcher.high = self.vram.read(addr + 1);
+                fetcher.step = Step::Push;
+            }
+            Step::Push => {}
+        }
+    }
+
+    fn fetch_sprite(&mut self) {
+        let sprite = match self.fifo.sprite.take() {
+            Some(sprite) => sprite,
+            None => return,
+        };
+
+        let tile_y = (self.ly as i16 - sprite.y) as u8;
+        let source = if sprite.palette == 0 { Source::Obj0 } else { Source::Obj1 };
+
+        // Sprites partially hidden by the left edge lose their first pixels
+        let skip = if sprite.x < 0 { (-sprite.x) as u8 } else { 0 };
+
+        while self.fifo.obj.len() < 8 {
+            self.fifo.obj.push_back(Pixel::transparent());
+        }
+
+        for x in skip..8 {
+            let color = self.get_tile_color(&TileSet::Set1, sprite.tile, x, tile_y, sprite.x_flip, sprite.y_flip, true);
+            let slot = &mut self.fifo.obj[(x - skip) as usize];
+
+            // Pixels already in the FIFO belong to sprites with higher priority
+            if slot.color == 0 {
+                *slot = Pixel {
+                    color,
+                    source,
+                    bg_priority: sprite.bg_priority,
+                };
+            }
+        }
+    }
+
+    fn output_pixel(&mut self) {
+        let bg = match self.fifo.bg.pop_front() {
+            Some(pixel) => pixel,
+            None => return,
+        };
+
+        // Only background pixels are discarded, sprites fetched meanwhile
+        // start at the first pixel shown
+        if self.fifo.discard > 0 {
+            self.fifo.discard -= 1;
+            return;
+        }
+
+        let obj = self.fifo.obj.pop_front();
+
+        let bg_color = if self.lcdc0 { bg.color } else { 0 };
+
+        let color = match obj {
+            Some(obj) if obj.color != 0 && self.sprites_enabled && (!obj.bg_priority || bg_color == 0) => {
+                match obj.source {
+                    Source::Obj1 => self.obp1.to_rgb(obj.color),
+                    _ => self.obp0.to_rgb(obj.color),
+                }
+            }
+            _ => {
+                if self.lcdc0 { self.bgp.to_rgb(bg_color) } else { self.colors.bg[0] }
+            }
+        };
+
+        let offset = self.ly as usize * SCREEN_W as usize + self.fifo.lx as usize;
+        self.framebuffer[offset] = color;
+        self.fifo.lx += 1;
+    }
+}
+
+fn save_pixels(w: &mut StateWriter, pixels: &VecDeque<Pixel>) {
+    w.u8(pixels.len() as u8);
+    for pixel in pixels.iter() {
+        w.u8(pixel.color);
+        w.u8(pixel.source as u8);
+        w.bool(pixel.bg_priority);
+    }
+}
+
+fn load_pixels(r: &mut StateReader, pixels: &mut VecDeque<Pixel>) -> Result<(), Box<dyn std::error::Error>> {
+    pixels.clear();
+    for _ in 0..r.u8()? {
+        pixels.push_back(Pixel {
+            color: r.u8()? & 0x03,
+            source: r.choice(&[Source::Background, Source::Obj0, Source::Obj1])?,
+            bg_priority: r.bool()?,
+        });
+    }
+    Ok(())
+}
+
+fn save_sprite(w: &mut StateWriter, sprite: &Sprite) {
+    w.u16(sprite.x as u16);
+    w.u16(sprite.y as u16);
+    w.u8(sprite.tile);
+    w.bool(sprite.bg_priority);
+    w.bool(sprite.y_flip);
+    w.bool(sprite.x_flip);
+    w.u8(sprite.palette);
+}
+
+fn load_sprite(r: &mut StateReader) -> Result<Sprite, Box<dyn std::error::Error>> {
+    Ok(Sprite {
+        x: r.u16()? as i16,
+        y: r.u16()? as i16,
+        tile: r.u8()?,
+        bg_priority: r.bool()?,
+        y_flip: r.bool()?,
+        x_flip: r.bool()?,
+        palette: r.u8()? & 1,
+    })
+}
+
+impl Snapshot for PixelFifo {
+    fn save_state(&self, w: &mut StateWriter) {
+        save_pixels(w, &self.bg);
+        save_pixels(w, &self.obj);
+
+        let fetcher = &self.fetcher;
+        w.u8(fetcher.step as u8);
+        w.u8(fetcher.dots);
+        w.u8(fetcher.map_x);
+        w.u8(fetcher.tile);
+        w.u8(fetcher.low);
+        w.u8(fetcher.high);
+        w.bool(fetcher.window);
+
+        w.u8(self.sprites.len() as u8);
+        self.sprites.iter().for_each(|sprite| save_sprite(w, sprite));
+        w.u8(self.sprite_dots);
+        w.bool(self.sprite.is_some());
+        if let Some(sprite) = &self.sprite {
+            save_sprite(w, sprite);
+        }
+
+        w.u8(self.discard);
+        w.u8(self.stall);
+        w.u8(self.lx);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> Result<(), Box<dyn std::error::Error>> {
+        load_pixels(r, &mut self.bg)?;
+        load_pixels(r, &mut self.obj)?;
+
+        let fetcher = &mut self.fetcher;
+        fetcher.step = r.choice(&[Step::Tile, Step::DataLow, Step::DataHigh, Step::Push])?;
+        fetcher.dots = r.u8()?;
+        fetcher.map_x = r.u8()?;
+        fetcher.tile = r.u8()?;
+        fetcher.low = r.u8()?;
+        fetcher.high = r.u8()?;
+        fetcher.window = r.bool()?;
+
+        self.sprites.clear();
+        for _ in 0..r.u8()? {
+            self.sprites.push(load_sprite(r)?);
+        }
+        self.sprite_dots = r.u8()?;
+        self.sprite = if r.bool()? { Some(load_sprite(r)?) } else { None };
+
+        self.discard = r.u8()?;
+        self.stall = r.u8()?;
+        self.lx = r.u8()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::COLORS;
+    use crate::test_utils::NullDisplay;
+
+    // Runs the PPU until the current line leaves mode 3 and returns the
+    // amount of dots spent in it.
+    fn mode3_length(ppu: &mut PPU) -> u32 {
+        while ppu.mode != super::super::Mode::Transfer { ppu.step(1); }
+        let start = ppu.clock;
+        while ppu.mode == super::super::Mode::Transfer { ppu.step(1); }
+        ppu.clock - start
+    }
+
+    fn fifo_ppu() -> PPU {
+        let mut ppu = PPU::new(Box::new(NullDisplay {}));
+        ppu.set_renderer(super::super::Renderer::Fifo);
+        ppu.write(0xFF40, 0x91);
+        ppu
+    }
+
+    #[test]
+    fn mode3_length_depends_on_scx() {
+        let mut ppu = fifo_ppu();
+        assert_eq!(mode3_length(&mut ppu), 172);
+
+        ppu.write(0xFF43, 3);
+        assert_eq!(mode3_length(&mut ppu), 175);
+    }
+
+    #[test]
+    fn mode3_length_grows_with_sprites() {
+        let mut ppu = fifo_ppu();
+        ppu.write(0xFF40, 0x93);
+
+        // Put a sprite on every line at screen X = 0
+        ppu.write(0xFE00, 16);
+        ppu.write(0xFE01, 8);
+
+        while ppu.ly != 1 { ppu.step(1); }
+        let length = mode3_length(&mut ppu);
+        assert!((172 + 6..=172 + 11).contains(&length));
+    }
+
+    #[test]
+    fn mid_line_bgp_write() {
+        let mut ppu = fifo_ppu();
+        ppu.write(0xFF47, 0x00);
+
+        while ppu.mode != super::super::Mode::Transfer { ppu.step(1); }
+        ppu.step(FIRST_FETCH_DOTS as u32 + 81);
+        ppu.write(0xFF47, 0xFF);
+        while ppu.mode == super::super::Mode::Transfer { ppu.step(1); }
+
+        let line = ppu.ly as usize * SCREEN_W as usize;
+        assert_eq!(ppu.framebuffer[line], COLORS[0]);
+        assert_eq!(ppu.framebuffer[line + 159], COLORS[3]);
+    }
+    #[test]
+    fn fine_scroll_keeps_sprite_pixels() {
+        let mut ppu = fifo_ppu();
+        ppu.write(0xFF40, 0x93);
+        ppu.write(0xFF43, 3);
+        ppu.write(0xFF48, 0xE4);
+
+        // Tile 1 only has its first column set
+        for addr in 0x8010..0x8020 {
+            ppu.write(addr, 0x80);
+        }
+
+        // Sprite at screen X = 0, fetched while the SCX pixels are discarded
+        ppu.write(0xFE00, 16);
+        ppu.write(0xFE01, 8);
+        ppu.write(0xFE02, 1);
+
+        while ppu.ly != 1 { ppu.step(1); }
+        mode3_length(&mut ppu);
+
+        let line = SCREEN_W as usize;
+        assert_eq!(ppu.framebuffer[line], COLORS[3]);
+        assert_eq!(ppu.framebuffer[line + 1], COLORS[0]);
+    }
+}
@@ -1,8 +1,14 @@
 pub mod sprite;
+pub mod colors;
+pub mod colorize;
+mod fifo;
 use crate::memory::{Memory, Ram};
 use crate::io;
 use crate::Display;
 use sprite::Sprite;
+use fifo::PixelFifo;
+use colors::{ColorScheme, Shades};
+use crate::state::{Snapshot, StateReader, StateWriter};
 
 const SCREEN_W: u16 = 160;
 const SCREEN_H: u16 = 144;
@@ -10,12 +16,55 @@ const SCREEN_H: u16 = 144;
 const VRAM_SIZE: usize = 0x4000;
 const VOAM_SIZE: usize = 0xA0;
 
-const COLORS: [u32; 4] = [0xACB56A, 0x848F58, 0x404d40, 0x2c373d];
+const OAM_SEARCH_DOTS: u32 = 80;
+const TRANSFER_DOTS: u32 = 172;
+const LINE_DOTS: u32 = 456;
+const FRAME_DOTS: u32 = LINE_DOTS * 154;
+// The first line after turning the LCD on is 4 dots shorter than usual
+const FIRST_LINE_OFFSET: u32 = 4;
+
+#[cfg(test)]
+const COLORS: Shades = [0xACB56A, 0x848F58, 0x404D40, 0x2C373D];
+
+// Holds the place of the display while the PPU is rebuilt
+struct NoDisplay {}
+
+impl Display for NoDisplay {}
+
+/// Selects how the PPU produces pixels during mode 3.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Renderer {
+    /// Renders the whole line at once when entering HBlank. Mode 3 always
+    /// lasts 172 dots. Fast, but ignores mid-scanline register writes.
+    Scanline,
+    /// Models the background and sprite fetchers feeding the pixel FIFO.
+    /// Mode 3 length depends on SCX, the window and the sprites on the line.
+    Fifo,
+}
+
+impl Renderer {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name.to_lowercase().as_str() {
+            "scanline" => Some(Renderer::Scanline),
+            "fifo" => Some(Renderer::Fifo),
+            _ => None,
+        }
+    }
+}
 
 pub struct PPU {
+    // Dots elapsed since the start of the current line (0..456)
     clock: u32,
+    renderer: Renderer,
+    fifo: PixelFifo,
+    // Block CPU access to VRAM during mode 3 and to OAM during modes 2 and 3
+    access_blocking: bool,
     framebuffer: Vec<u32>,
     display: Box<dyn Display>,
+    // Keep finished frames for the owner to post-process and present
+    hold_frames: bool,
+    frame_ready: bool,
+    colors: ColorScheme,
     vram: Ram,
     voam: [u8; VOAM_SIZE],
 
@@ -28,16 +77,34 @@ pub struct PPU {
     vblank_inte: bool,
     hblank_inte: bool,
     lyc: u8,
+    // All the STAT interrupt sources are ORed into a single line and the
+    // interrupt is only requested on its rising edge ("STAT blocking").
+    stat_line: bool,
+    // Interrupts requested by register writes, delivered on the next step.
+    intfs: u8,
     mode: Mode,
     scx: u8,
     scy: u8,
     wx: u8,
     wy: u8,
+    // Internal window line counter. It only advances on lines where the
+    // window was actually drawn, so hiding the window mid-frame doesn't skip
+    // tile rows when it is shown again.
+    window_line: u8,
+    // Latched once LY matched WY during the current frame.
+    wy_latch: bool,
+    // With WX=166 the window covers the whole following line.
+    window_wrap: bool,
     bgp: Palette, // BGP - BG Palette Data (R/W) - Non CGB Mode Only
     obp0: Palette, // OBP0 - Object Palette 0 Data (R/W) - Non CGB Mode Only
     obp1: Palette, // OBP1 - Object Palette 1 Data (R/W) - Non CGB Mode Only
 
     lcd_on: bool,
+    // Set when the LCD is turned on. The first line skips OAM search and
+    // reports mode 0 until the transfer starts.
+    first_line: bool,
+    // The LCD stays blank during the first frame after being turned on.
+    blank_frame: bool,
     window_map: TileMap,
     window_on: bool,
     tile_data: TileSet,
@@ -62,14 +129,22 @@ impl PPU {
     pub fn new(display: Box<dyn Display>) -> Self {
         Self {
             clock: 0,
+            renderer: Renderer::Scanline,
+            fifo: PixelFifo::new(),
+            access_blocking: true,
             display: display,
-            framebuffer: vec![COLORS[0]; (SCREEN_W * SCREEN_H) as usize],
+            hold_frames: false,
+            frame_ready: false,
+            colors: ColorScheme::default(),
+            framebuffer: vec![ColorScheme::default().bg[0]; (SCREEN_W * SCREEN_H) as usize],
             vram: Ram::new(VRAM_SIZE),
             voam: [0; VOAM_SIZE],
             mode: Mode::HBlank,
             lcdc0: true,
             ly: 0,
             lyc: 0,
+            stat_line: false,
+            intfs: 0,
             lyc_inte: false,
             oam_inte: false,
             vblank_inte: false,
@@ -78,49 +153,200 @@ impl PPU {
             scx: 0,
             wy: 0,
             wx: 0,
+            window_line: 0,
+            wy_latch: false,
+            window_wrap: false,
             window_map: TileMap::Low,
             window_on: false,
             tile_data: TileSet::Set1,
             background_map: TileMap::High,
-            bgp: Palette::from(0),
-            obp0: Palette::from(0),
-            obp1: Palette::from(0),
+            bgp: Palette::new(0, &ColorScheme::default().bg),
+            obp0: Palette::new(0, &ColorScheme::default().obj0),
+            obp1: Palette::new(0, &ColorScheme::default().obj1),
             lcd_on: true,
+            first_line: false,
+            blank_frame: false,
             sprite_size: SpriteSize::S8x8,
             sprites_enabled: false,
         }
     }
 
+    // Back to power-on, keeping the display and the settings
+    pub fn reset(&mut self) {
+        let display = std::mem::replace(&mut self.display, Box::new(NoDisplay {}));
+        let mut ppu = PPU::new(display);
+
+        ppu.renderer = self.renderer;
+        ppu.access_blocking = self.access_blocking;
+        ppu.hold_frames = self.hold_frames;
+        ppu.set_colors(self.colors);
+        *self = ppu;
+    }
+
+    // OAM writes from the DMA controller
+    pub fn dma_write(&mut self, index: u16, v: u8) {
+        self.voam[index as usize] = v;
+    }
+
+    pub fn set_renderer(&mut self, renderer: Renderer) {
+        self.renderer = renderer;
+    }
+
+    pub fn set_colors(&mut self, colors: ColorScheme) {
+        self.colors = colors;
+        self.bgp = Palette::new(self.bgp.value, &colors.bg);
+        self.obp0 = Palette::new(self.obp0.value, &colors.obj0);
+        self.obp1 = Palette::new(self.obp1.value, &colors.obj1);
+    }
+
+    pub fn get_colors(&self) -> ColorScheme {
+        self.colors
+    }
+
+    pub fn set_access_blocking(&mut self, enabled: bool) {
+        self.access_blocking = enabled;
+    }
+
+    pub fn set_hold_frames(&mut self, enabled: bool) {
+        self.hold_frames = enabled;
+    }
+
+    // Returns true once after each frame held back from the display
+    pub fn take_frame(&mut self) -> bool {
+        let ready = self.frame_ready;
+        self.frame_ready = false;
+        ready
+    }
+
+    pub fn get_framebuffer(&self) -> &Vec<u32> {
+        &self.framebuffer
+    }
+
+    pub fn present(&mut self, buffer: &Vec<u32>) {
+        self.display.update(buffer);
+    }
+
+    // Sends the last frame to the display again
+    pub fn redraw(&mut self) {
+        self.display.update(&self.framebuffer);
+    }
+
+    // Copies the 256 tiles shown on the first screen rows, in display order.
+    // This is how data is handed to the SGB through VRAM transfers.
+    pub fn screen_tiles(&self) -> Vec<u8> {
+        let mut data = Vec::with_capacity(0x1000);
+
+        for i in 0..256_u16 {
+            let addr = TileMap::base_addr(&self.background_map) + (i / 20) * 32 + i % 20;
+            let tile = self.vram.read(addr);
+            let from = TileSet::base_addr(&self.tile_data) + TileSet::tile_offset(&self.tile_data, tile);
+
+            for offset in 0..16 {
+                data.push(self.vram.read(from + offset));
+            }
+        }
+
+        data
+    }
+
+    fn vram_accessible(&self) -> bool {
+        !self.access_blocking || !self.lcd_on || self.mode != Mode::Transfer
+    }
+
+    fn oam_accessible(&self) -> bool {
+        !self.access_blocking || !self.lcd_on ||
+            (self.mode != Mode::OAMSearch && self.mode != Mode::Transfer)
+    }
+
     pub fn step(&mut self, ticks: u32) -> u8 {
-        let mut intfs: u8 = 0;
+        let mut intfs: u8 = self.intfs;
+        self.intfs = 0;
+
+        if !self.lcd_on {
+            self.step_off(ticks);
+            return intfs;
+        }
 
-        if !self.lcd_on { return 0 }
+        for _ in 0..ticks {
+            intfs |= self.dot();
+        }
+
+        intfs
+    }
 
+    // While the LCD is off the screen is white, but the display keeps
+    // receiving frames at the usual rate.
+    fn step_off(&mut self, ticks: u32) {
         self.clock += ticks;
 
+        if self.clock >= FRAME_DOTS {
+            self.clock -= FRAME_DOTS;
+            self.push_blank_frame();
+        }
+    }
+
+    fn push_blank_frame(&mut self) {
+        for pixel in self.framebuffer.iter_mut() {
+            *pixel = self.colors.bg[0];
+        }
+        self.update_display();
+    }
+
+    fn update_display(&mut self) {
+        if self.hold_frames {
+            self.frame_ready = true;
+        } else {
+            self.display.update(&self.framebuffer);
+        }
+    }
+
+    fn push_frame(&mut self) {
+        if self.blank_frame {
+            self.blank_frame = false;
+            self.push_blank_frame();
+        } else {
+            self.update_display();
+        }
+    }
+
+    fn dot(&mut self) -> u8 {
+        let mut intfs: u8 = 0;
+
+        self.clock += 1;
+
         match self.mode {
             Mode::OAMSearch => {
                 // Mode: 2
-                if self.clock >= 80 {
-                    self.clock -= 80;
+                if self.clock >= OAM_SEARCH_DOTS {
                     intfs |= self.change_mode(Mode::Transfer);
                 }
             }
 
             Mode::Transfer => {
                 // Mode: 3
-                if self.clock >= 172 {
-                    self.clock -= 172;
+                let done = match self.renderer {
+                    Renderer::Scanline => self.clock >= OAM_SEARCH_DOTS + TRANSFER_DOTS,
+                    Renderer::Fifo => self.fifo_dot(),
+                };
+
+                if done {
                     intfs |= self.change_mode(Mode::HBlank);
                 }
             }
 
+            Mode::HBlank if self.first_line => {
+                // Mode: 0, reported while the first line waits for the transfer
+                if self.clock >= OAM_SEARCH_DOTS {
+                    self.first_line = false;
+                    intfs |= self.change_mode(Mode::Transfer);
+                }
+            }
+
             Mode::HBlank => {
                 // Mode: 0
-                if self.clock >= 204 {
-                    self.clock -= 204;
+                if self.clock >= LINE_DOTS {
+                    self.clock = 0;
                     self.ly = self.ly.wrapping_add(1);
-                    intfs |= self.check_lyc();
 
                     if self.ly > 143 {
                         intfs |= self.change_mode(Mode::VBlank);
@@ -132,14 +358,12 @@ impl PPU {
 
             Mode::VBlank => {
                 // Mode: 1
-                if self.clock >= 456 {
-                    self.clock -= 456;
+                if self.clock >= LINE_DOTS {
+                    self.clock = 0;
                     self.ly = self.ly.wrapping_add(1);
-                    intfs |= self.check_lyc();
 
                     if self.ly > 153 {
-                        // println!("UPDATE!");
-                        self.display.update(&self.framebuffer);
+                        self.push_frame();
 
                         self.ly = 0;
                         intfs |= self.change_mode(Mode::OAMSearch);
@@ -148,7 +372,7 @@ impl PPU {
             }
         }
 
-        intfs
+        intfs | self.update_stat_line()
     }
 
     fn render_line(&mut self) {
@@ -160,33 +384,61 @@ impl PPU {
 
         let sprites = if self.sprites_enabled { self.oam_search() } else { vec![] };
 
-        let win_y = self.ly as i32 - self.wy as i32;
+        let window = self.window_active();
+        let win_x0 = if self.window_wrap { 0 } else { self.wx as i32 - 7 };
+        let mut window_drawn = false;
 
         for x in 0..SCREEN_W as u8 {
-            let mut color = 0xACB56B;
+            let mut color = self.colors.bg[0];
+            let mut bg_color = 0;
 
             let bg_y = self.scy.wrapping_add(self.ly);
             let bg_x = self.scx.wrapping_add(x as u8);
 
-            let win_x = - ((self.wx as i32) - 7) + (x as i32);
-
             if self.lcdc0 {
-                if self.window_on && win_y >= 0 && win_x >= 0 {
-                    color = self.get_bg_color(win_x as u8, win_y as u8, &self.window_map);
+                if window && (x as i32) >= win_x0 {
+                    let win_x = (x as i32 - win_x0) as u8;
+                    bg_color = self.get_bg_color(win_x, self.window_line, &self.window_map);
+                    window_drawn = true;
                 } else {
-                    color = self.get_bg_color(bg_x, bg_y, &self.background_map);
+                    bg_color = self.get_bg_color(bg_x, bg_y, &self.background_map);
                 }
+                color = self.bgp.to_rgb(bg_color);
             };
 
             if self.sprites_enabled {
-                color = self.get_sprite_color(&sprites, x, self.ly, color);
+                if let Some(sprite_color) = self.get_sprite_color(&sprites, x, bg_color) {
+                    color = sprite_color;
+                }
             }
 
             self.framebuffer[(self.ly as usize * SCREEN_W as usize + x as usize)] = color;
         }
+
+        self.finish_window_line(window_drawn);
+    }
+
+    // Whether the window can be drawn on the current line. WX values above
+    // 166 push the window past the right edge of the screen.
+    fn window_active(&self) -> bool {
+        self.lcdc0 && self.window_on && self.wy_latch && (self.window_wrap || self.wx < 167)
+    }
+
+    fn finish_window_line(&mut self, drawn: bool) {
+        if drawn {
+            self.window_line = self.window_line.wrapping_add(1);
+        }
+
+        self.window_wrap = drawn && self.wx == 166;
+    }
+
+    fn reset_window(&mut self) {
+        self.window_line = 0;
+        self.wy_latch = false;
+        self.window_wrap = false;
     }
 
-    fn get_bg_color(&self, x: u8, y: u8, map: &TileMap) -> u32 {
+    fn get_bg_color(&self, x: u8, y: u8, map: &TileMap) -> u8 {
         let bg_map_base = TileMap::base_addr(map);
         let tile_map_x = (x >> 3) as u16;
         let tile_map_y = (y >> 3) as u16;
@@ -197,19 +449,12 @@ impl PPU {
             .vram
             .read(bg_map_base + (tile_map_x + (tile_map_y << 5)) as u16);
 
-        let color = self.get_tile_color(&self.tile_data, tile_idx, tile_x, tile_y, false, false, false);
-
-        self.bgp.to_rgb(color)
+        self.get_tile_color(&self.tile_data, tile_idx, tile_x, tile_y, false, false, false)
     }
 
     fn get_tile_color(&self, set: &TileSet, index: u8, x: u8, y: u8, x_flip: bool, y_flip: bool, is_sprite: bool) -> u8 {
         let tile_set_base = TileSet::base_addr(&set);
-
-        let tile_offset = (if *set == TileSet::Set1 {
-            index as u16
-        } else {
-            ((index as i8 as i16) + 128) as u16
-        }) * 16;
+        let tile_offset = TileSet::tile_offset(set, index);
 
         let sprite16 = is_sprite && self.sprite_size == SpriteSize::S8x16;
 
@@ -227,15 +472,18 @@ impl PPU {
         h << 1 | l
     }
 
+    // Selects the first 10 sprites in OAM that overlap the current line and
+    // sorts them by drawing priority: lowest X first and, for equal X, lowest
+    // OAM index first. Sprites outside the horizontal screen area still take
+    // one of the 10 slots.
     fn oam_search(&self) -> Vec<Sprite> {
         let mut res = Vec::with_capacity(10);
-        let mut ri = 0;
 
         for i in 0..40 {
             let sprite_offset = i * 4;
-            let spritey = self.voam[sprite_offset as usize] as u16 as i32 - 16;
-            let spritex = self.voam[sprite_offset as usize + 1] as u16 as i32 - 8;
-            let tile = (self.voam[sprite_offset as usize + 2] & (if self.sprite_size == SpriteSize::S8x16 { 0xFE } else { 0xFF }));
+            let spritey = self.voam[sprite_offset as usize] as i32 - 16;
+            let spritex = self.voam[sprite_offset as usize + 1] as i32 - 8;
+            let tile = self.voam[sprite_offset as usize + 2] & (if self.sprite_size == SpriteSize::S8x16 { 0xFE } else { 0xFF });
             let flags = self.voam[sprite_offset as usize + 3];
             let line = self.ly as i32;
             let sprite_size = self.sprite_size as i32;
@@ -243,39 +491,47 @@ impl PPU {
             if line < spritey || line >= spritey + sprite_size {
                 continue;
             }
-            if spritex < -7 || spritex >= (SCREEN_W as i32) {
-                continue;
-            }
-
-            let sprite = Sprite::new(spritex, spritey, tile, flags);
-
-            res.push(sprite);
 
-            ri += 1;
+            res.push(Sprite::new(spritex, spritey, tile, flags));
 
-            if ri == 10 {
+            if res.len() == 10 {
                 break;
             }
         }
 
+        res.sort_by_key(|sprite| sprite.x);
+
         res
     }
 
-    fn get_sprite_color(&self, sprites: &Vec<Sprite>, x: u8, y: u8, bg: u32) -> u32 {
-        let mut current_color = bg;
-
-        for i in 0..sprites.len() {
-            let sprite = &sprites[i] ;
+    // Returns the color of the highest priority opaque sprite pixel at x, or
+    // None when no sprite covers it or the BG takes priority over it.
+    fn get_sprite_color(&self, sprites: &[Sprite], x: u8, bg_color: u8) -> Option<u32> {
+        let x = x as i16;
+        let y = self.ly as i16;
 
-            if x < sprite.x || x > sprite.x + 7 {
+        for sprite in sprites {
+            if x < sprite.x || x >= sprite.x + 8 {
+                continue;
+            }
+            if y < sprite.y || y >= sprite.y + (self.sprite_size as i16) {
                 continue;
             }
-            if y < sprite.y || y > sprite.y + (self.sprite_size as u8) {
+
+            let tile_y = (y - sprite.y) as u8;
+            let tile_x = (x - sprite.x) as u8;
+
+            let color =
+                self.get_tile_color(&TileSet::Set1, sprite.tile, tile_x, tile_y, sprite.x_flip, sprite.y_flip, true);
+
+            if color == 0 {
                 continue;
             }
 
-            let tile_y = y - sprite.y;
-            let tile_x = x - sprite.x;
+            // OAM priority bit: the sprite is hidden behind BG colors 1-3
+            if sprite.bg_priority && bg_color != 0 {
+                return None;
+            }
 
             let palette = if sprite.palette == 0 {
                 &self.obp0
@@ -283,46 +539,79 @@ impl PPU {
                 &self.obp1
             };
 
-            let color =
-                self.get_tile_color(&TileSet::Set1, sprite.tile, tile_x, tile_y, sprite.x_flip, sprite.y_flip, true);
-
-            if color != 0 {
-                current_color = palette.to_rgb(color);
-            // } else {
-            //     current_color = bg;
-            }
+            return Some(palette.to_rgb(color));
         }
 
-        current_color
+        None
     }
 
     fn change_mode(&mut self, next: Mode) -> u8 {
         let mut intfs = 0;
         self.mode = next;
 
-        if match self.mode {
-            Mode::OAMSearch => { self.oam_inte }
-            Mode::HBlank => { self.render_line(); self.hblank_inte }
+        match self.mode {
+            Mode::OAMSearch => {
+                if self.ly == self.wy { self.wy_latch = true; }
+            }
+            Mode::Transfer => {
+                if self.renderer == Renderer::Fifo { self.fifo_start_line(); }
+            }
+            Mode::HBlank => {
+                match self.renderer {
+                    Renderer::Scanline => self.render_line(),
+                    Renderer::Fifo => self.finish_window_line(self.fifo_window_drawn()),
+                }
+            }
             Mode::VBlank => {
+                self.reset_window();
                 intfs = io::intf_raise(intfs, io::Flag::VBlank);
-                self.vblank_inte
             }
-            _ => false,
-        } {
-            intfs = io::intf_raise(intfs, io::Flag::LCDStat);
         }
 
         intfs
     }
 
-    fn check_lyc(&mut self) -> u8 {
-        if self.lyc_inte && self.ly == self.lyc {
+    // Value of LY as seen by the LY=LYC comparator. It takes one M-cycle for
+    // the comparator to see a new line, and on line 153 LY already reads 0
+    // after the first M-cycle.
+    fn ly_compare(&self) -> Option<u8> {
+        match (self.ly, self.clock) {
+            (0, _) => Some(0),
+            (_, 0..=3) => None,
+            (153, 4..=7) => Some(153),
+            (153, _) => Some(0),
+            (ly, _) => Some(ly),
+        }
+    }
+
+    fn lyc_match(&self) -> bool {
+        self.ly_compare() == Some(self.lyc)
+    }
+
+    fn stat_sources(&self) -> bool {
+        (self.lyc_inte && self.lyc_match()) ||
+            (self.hblank_inte && self.mode == Mode::HBlank) ||
+            (self.vblank_inte && self.mode == Mode::VBlank) ||
+            // The OAM source also fires when entering VBlank on line 144
+            (self.oam_inte && (self.mode == Mode::OAMSearch || (self.ly == 144 && self.clock < 4)))
+    }
+
+    fn update_stat_line(&mut self) -> u8 {
+        let line = self.lcd_on && self.stat_sources();
+        let rising = line && !self.stat_line;
+        self.stat_line = line;
+
+        if rising {
             io::intf_raise(0, io::Flag::LCDStat)
         } else {
             0
         }
     }
 
+    fn get_ly(&self) -> u8 {
+        if self.ly == 153 && self.clock >= 4 { 0 } else { self.ly }
+    }
+
     fn get_lcdc(&self) -> u8 {
         (if self.lcd_on { 1 << 7 } else { 0 }) |
             (if self.window_map == TileMap::High { 1 << 6 } else { 0 }) |
@@ -339,7 +628,7 @@ impl PPU {
             (if self.oam_inte { 1 << 5 } else { 0 }) |
             (if self.vblank_inte { 1 << 4 } else { 0 }) |
             (if self.hblank_inte { 1 << 3 } else { 0 }) |
-            (if self.ly == self.lyc { 1 << 2 } else { 0 }) |
+            (if self.lyc_match() { 1 << 2 } else { 0 }) |
             (if self.lcd_on { self.mode as u8 } else { 0 }) |
             0x80
 
@@ -356,7 +645,24 @@ impl PPU {
         self.sprites_enabled = (v & (1 << 1)) != 0;
         self.lcdc0 = (v & 1) != 0;
 
-        if prev_lcd_on && !self.lcd_on { self.clock = 0; self.ly = 0; self.mode = Mode::HBlank }
+        if prev_lcd_on && !self.lcd_on {
+            self.clock = 0;
+            self.ly = 0;
+            self.mode = Mode::HBlank;
+            self.stat_line = false;
+            self.reset_window();
+            self.push_blank_frame();
+        }
+
+        if !prev_lcd_on && self.lcd_on {
+            self.clock = FIRST_LINE_OFFSET;
+            self.ly = 0;
+            self.mode = Mode::HBlank;
+            self.first_line = true;
+            self.blank_frame = true;
+            self.wy_latch = self.wy == 0;
+            self.intfs |= self.update_stat_line();
+        }
     }
 
     fn set_stat(&mut self, v: u8) {
@@ -364,19 +670,24 @@ impl PPU {
         self.oam_inte = (v & (1 << 5)) != 0;
         self.vblank_inte = (v & (1 << 4)) != 0;
         self.hblank_inte = (v & (1 << 3)) != 0;
+
+        // Enabling a source whose condition is already met raises the line
+        self.intfs |= self.update_stat_line();
     }
 }
 
 impl Memory for PPU {
     fn read(&self, addr: u16) -> u8 {
         match addr {
+            0x8000..=0x9FFF if !self.vram_accessible() => 0xFF,
             0x8000..=0x9FFF => self.vram.read(addr - 0x8000),
+            0xFE00 ..= 0xFE9F if !self.oam_accessible() => 0xFF,
             0xFE00 ..= 0xFE9F => self.voam[addr as usize - 0xFE00],
             0xFF40 => self.get_lcdc(),
             0xFF41 => self.get_stat(),
             0xFF42 => self.scy,
             0xFF43 => self.scx,
-            0xFF44 => self.ly,
+            0xFF44 => self.get_ly(),
             0xFF45 => self.lyc,
             0xFF47 => self.bgp.into(),
             0xFF48 => self.obp0.into(),
@@ -389,17 +700,22 @@ impl Memory for PPU {
 
     fn write(&mut self, addr: u16, v: u8) {
         match addr {
+            0x8000..=0x9FFF if !self.vram_accessible() => { }
             0x8000..=0x9FFF => self.vram.write(addr - 0x8000, v),
+            0xFE00 ..= 0xFE9F if !self.oam_accessible() => { }
             0xFE00 ..= 0xFE9F => { self.voam[(addr - 0xFE00) as usize] = v },
             0xFF40 => self.set_lcdc(v),
             0xFF41 => self.set_stat(v),
             0xFF42 => self.scy = v,
             0xFF43 => {  self.scx = v },
             0xFF44 => { }
-            0xFF45 => { self.lyc = v }
-            0xFF47 => { self.bgp = Palette::from(v); }
-            0xFF48 => { self.obp0 = Palette::from(v); }
-            0xFF49 => { self.obp1 = Palette::from(v); }
+            0xFF45 => {
+                self.lyc = v;
+                self.intfs |= self.update_stat_line();
+            }
+            0xFF47 => { self.bgp = Palette::new(v, &self.colors.bg); }
+            0xFF48 => { self.obp0 = Palette::new(v, &self.colors.obj0); }
+            0xFF49 => { self.obp1 = Palette::new(v, &self.colors.obj1); }
             0xFF4A => self.wy = v,
             0xFF4B => self.wx = v,
             _ => { /* println!("Warning: Attempt to WRITE 0x{:02X} on unmapped PPU area: 0x{:04X}", v, addr) */ }
@@ -428,6 +744,15 @@ impl TileSet {
             TileSet::Set2 => 0x0800,
         }
     }
+
+    // Offset of the tile data relative to the base address of the set
+    pub fn tile_offset(set: &TileSet, index: u8) -> u16 {
+        (if *set == TileSet::Set1 {
+            index as u16
+        } else {
+            ((index as i8 as i16) + 128) as u16
+        }) * 16
+    }
 }
 
 #[derive(PartialEq, Clone, Copy)]
@@ -457,17 +782,17 @@ struct Palette {
     render: [u32; 4],
 }
 
-impl std::convert::From<u8> for Palette {
-    fn from(value: u8) -> Self {
+impl Palette {
+    pub fn new(value: u8, shades: &Shades) -> Self {
         let mut render: [u32; 4] = [0; 4];
 
         for i in 0..4 {
-            render[i] = COLORS[((value >> (i * 2)) & 0x03) as usize];
+            render[i] = shades[((value >> (i * 2)) & 0x03) as usize];
         }
 
         Self {
-            value: value,
-            render: render
+            value,
+            render,
         }
     }
 }
@@ -479,3 +804,321 @@ impl Palette {
         self.render[color_index as usize]
     }
 }
+
+impl Snapshot for PPU {
+    fn save_state(&self, w: &mut StateWriter) {
+        w.u32(self.clock);
+        self.fifo.save_state(w);
+        w.u32s(&self.framebuffer);
+        w.bool(self.frame_ready);
+        self.vram.save_state(w);
+        w.bytes(&self.voam);
+
+        w.u8(self.ly);
+        w.bool(self.lyc_inte);
+        w.bool(self.oam_inte);
+        w.bool(self.vblank_inte);
+        w.bool(self.hblank_inte);
+        w.u8(self.lyc);
+        w.bool(self.stat_line);
+        w.u8(self.intfs);
+        w.u8(self.mode as u8);
+        w.u8(self.scx);
+        w.u8(self.scy);
+        w.u8(self.wx);
+        w.u8(self.wy);
+        w.u8(self.window_line);
+        w.bool(self.wy_latch);
+        w.bool(self.window_wrap);
+        w.u8(self.bgp.value);
+        w.u8(self.obp0.value);
+        w.u8(self.obp1.value);
+
+        w.bool(self.lcd_on);
+        w.bool(self.first_line);
+        w.bool(self.blank_frame);
+        w.bool(self.window_map == TileMap::High);
+        w.bool(self.window_on);
+        w.bool(self.tile_data == TileSet::Set2);
+        w.bool(self.background_map == TileMap::High);
+        w.bool(self.sprite_size == SpriteSize::S8x16);
+        w.bool(self.sprites_enabled);
+        w.bool(self.lcdc0);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> Result<(), Box<dyn std::error::Error>> {
+        let tile_map = |high: bool| if high { TileMap::High } else { TileMap::Low };
+
+        self.clock = r.u32()?;
+        self.fifo.load_state(r)?;
+        r.u32s_into(&mut self.framebuffer)?;
+        self.frame_ready = r.bool()?;
+        self.vram.load_state(r)?;
+        r.bytes_into(&mut self.voam)?;
+
+        self.ly = r.u8()?;
+        self.lyc_inte = r.bool()?;
+        self.oam_inte = r.bool()?;
+        self.vblank_inte = r.bool()?;
+        self.hblank_inte = r.bool()?;
+        self.lyc = r.u8()?;
+        self.stat_line = r.bool()?;
+        self.intfs = r.u8()?;
+        self.mode = r.choice(&[Mode::HBlank, Mode::VBlank, Mode::OAMSearch, Mode::Transfer])?;
+        self.scx = r.u8()?;
+        self.scy = r.u8()?;
+        self.wx = r.u8()?;
+        self.wy = r.u8()?;
+        self.window_line = r.u8()?;
+        self.wy_latch = r.bool()?;
+        self.window_wrap = r.bool()?;
+        self.bgp = Palette::new(r.u8()?, &self.colors.bg);
+        self.obp0 = Palette::new(r.u8()?, &self.colors.obj0);
+        self.obp1 = Palette::new(r.u8()?, &self.colors.obj1);
+
+        self.lcd_on = r.bool()?;
+        self.first_line = r.bool()?;
+        self.blank_frame = r.bool()?;
+        self.window_map = tile_map(r.bool()?);
+        self.window_on = r.bool()?;
+        self.tile_data = if r.bool()? { TileSet::Set2 } else { TileSet::Set1 };
+        self.background_map = tile_map(r.bool()?);
+        self.sprite_size = if r.bool()? { SpriteSize::S8x16 } else { SpriteSize::S8x8 };
+        self.sprites_enabled = r.bool()?;
+        self.lcdc0 = r.bool()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::NullDisplay;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    // Keeps the first pixel of every frame pushed to the display
+    struct RecordingDisplay {
+        frames: Rc<RefCell<Vec<u32>>>,
+    }
+
+    impl Display for RecordingDisplay {
+        fn update(&mut self, framebuffer: &Vec<u32>) {
+            self.frames.borrow_mut().push(framebuffer[0]);
+        }
+    }
+
+    fn run_to_line(ppu: &mut PPU, ly: u8) {
+        while ppu.ly != ly { ppu.step(4); }
+    }
+
+    #[test]
+    fn window_line_counter_resumes() {
+        let mut ppu = PPU::new(Box::new(NullDisplay {}));
+        ppu.write(0xFF4A, 10);
+        ppu.write(0xFF4B, 7);
+        ppu.write(0xFF40, 0xB1);
+
+        // Let a full frame go by so WY is latched from line 0
+        run_to_line(&mut ppu, 0);
+        run_to_line(&mut ppu, 20);
+        assert_eq!(ppu.window_line, 10);
+
+        ppu.write(0xFF40, 0x91);
+        run_to_line(&mut ppu, 40);
+        assert_eq!(ppu.window_line, 10);
+
+        ppu.write(0xFF40, 0xB1);
+        run_to_line(&mut ppu, 45);
+        assert_eq!(ppu.window_line, 15);
+
+        run_to_line(&mut ppu, 0);
+        assert_eq!(ppu.window_line, 0);
+    }
+
+    #[test]
+    fn window_hidden_past_right_edge() {
+        let mut ppu = PPU::new(Box::new(NullDisplay {}));
+        ppu.write(0xFF4A, 0);
+        ppu.write(0xFF4B, 167);
+        ppu.write(0xFF40, 0xB1);
+
+        run_to_line(&mut ppu, 0);
+        run_to_line(&mut ppu, 10);
+        assert_eq!(ppu.window_line, 0);
+    }
+
+    // Line 0 is skipped right after power on, so wait for the next frame
+    fn render_first_line(ppu: &mut PPU) {
+        run_to_line(ppu, 1);
+        run_to_line(ppu, 0);
+        run_to_line(ppu, 1);
+    }
+
+    fn sprite_ppu(renderer: Renderer) -> PPU {
+        let mut ppu = PPU::new(Box::new(NullDisplay {}));
+        ppu.set_renderer(renderer);
+        ppu.write(0xFF47, 0xE4);
+        ppu.write(0xFF48, 0xE4);
+
+        // Tile 1: solid color 3, tile 2: solid color 1
+        for i in 0..8 {
+            ppu.write(0x8010 + i * 2, 0xFF);
+            ppu.write(0x8011 + i * 2, 0xFF);
+            ppu.write(0x8020 + i * 2, 0xFF);
+        }
+
+        // Sprite 0 at screen X=4, sprite 1 at screen X=0, both on line 0
+        for (i, v) in [16, 12, 2, 0, 16, 8, 1, 0].iter().enumerate() {
+            ppu.write(0xFE00 + i as u16, *v);
+        }
+
+        ppu
+    }
+
+    #[test]
+    fn sprite_lowest_x_wins() {
+        for renderer in [Renderer::Scanline, Renderer::Fifo] {
+            let mut ppu = sprite_ppu(renderer);
+            ppu.write(0xFF40, 0x93);
+
+            render_first_line(&mut ppu);
+
+            assert_eq!(ppu.framebuffer[2], COLORS[3]);
+            assert_eq!(ppu.framebuffer[6], COLORS[3]);
+            assert_eq!(ppu.framebuffer[10], COLORS[1]);
+        }
+    }
+
+    #[test]
+    fn sprite_behind_background() {
+        for renderer in [Renderer::Scanline, Renderer::Fifo] {
+            let mut ppu = sprite_ppu(renderer);
+            ppu.write(0xFE03, 0x80);
+            ppu.write(0xFE07, 0x80);
+
+            // Fill the first BG tile row with tile 2 (color 1) up to X=8
+            ppu.write(0x9800, 2);
+            ppu.write(0xFF40, 0x93);
+
+            render_first_line(&mut ppu);
+
+            // Hidden by BG color 1, but still drawn over BG color 0
+            assert_eq!(ppu.framebuffer[2], COLORS[1]);
+            assert_eq!(ppu.framebuffer[10], COLORS[1]);
+        }
+    }
+
+    #[test]
+    fn sprite_partially_off_left_edge() {
+        for renderer in [Renderer::Scanline, Renderer::Fifo] {
+            let mut ppu = sprite_ppu(renderer);
+            ppu.write(0xFE05, 4);
+            ppu.write(0xFE01, 200);
+            ppu.write(0xFF40, 0x93);
+
+            render_first_line(&mut ppu);
+
+            assert_eq!(ppu.framebuffer[0], COLORS[3]);
+            assert_eq!(ppu.framebuffer[3], COLORS[3]);
+            assert_eq!(ppu.framebuffer[4], COLORS[0]);
+            assert_eq!(ppu.framebuffer[159], COLORS[0]);
+        }
+    }
+
+    fn count_stat_interrupts(ppu: &mut PPU, dots: u32) -> u32 {
+        (0..dots).filter(|_| ppu.step(1) & (1 << io::Flag::LCDStat as u8) != 0).count() as u32
+    }
+
+    #[test]
+    fn stat_blocking() {
+        let mut ppu = PPU::new(Box::new(NullDisplay {}));
+        run_to_line(&mut ppu, 153);
+
+        // HBlank is followed by OAM search, so OAM only fires on line 0
+        ppu.write(0xFF41, 0x28);
+        assert_eq!(count_stat_interrupts(&mut ppu, LINE_DOTS * 154), 145);
+    }
+
+    #[test]
+    fn stat_enable_retriggers() {
+        let mut ppu = PPU::new(Box::new(NullDisplay {}));
+        run_to_line(&mut ppu, 1);
+        while ppu.mode != Mode::HBlank { ppu.step(1); }
+
+        ppu.write(0xFF41, 0x08);
+        assert_ne!(ppu.step(1) & (1 << io::Flag::LCDStat as u8), 0);
+    }
+
+    #[test]
+    fn ly_153_quirk() {
+        let mut ppu = PPU::new(Box::new(NullDisplay {}));
+        ppu.write(0xFF45, 0);
+        ppu.write(0xFF41, 0x40);
+        run_to_line(&mut ppu, 153);
+
+        assert_eq!(ppu.read(0xFF44), 153);
+        ppu.step(4);
+        assert_eq!(ppu.read(0xFF44), 0);
+
+        // LYC=0 matches during line 153 already
+        assert_ne!(count_stat_interrupts(&mut ppu, 8), 0);
+        assert_eq!(ppu.read(0xFF41) & 0x04, 0x04);
+    }
+
+    #[test]
+    fn lcd_off_pushes_blank_frames() {
+        let frames = Rc::new(RefCell::new(vec![]));
+        let mut ppu = PPU::new(Box::new(RecordingDisplay { frames: frames.clone() }));
+        ppu.write(0xFF47, 0xFF);
+        render_first_line(&mut ppu);
+        frames.borrow_mut().clear();
+
+        ppu.write(0xFF40, 0x11);
+        assert_eq!(*frames.borrow(), vec![COLORS[0]]);
+
+        ppu.step(FRAME_DOTS);
+        assert_eq!(*frames.borrow(), vec![COLORS[0], COLORS[0]]);
+    }
+
+    #[test]
+    fn lcd_on_first_frame_is_blank() {
+        let frames = Rc::new(RefCell::new(vec![]));
+        let mut ppu = PPU::new(Box::new(RecordingDisplay { frames: frames.clone() }));
+        ppu.write(0xFF47, 0xFF);
+        ppu.write(0xFF40, 0x11);
+        frames.borrow_mut().clear();
+
+        ppu.write(0xFF40, 0x91);
+        assert_eq!(ppu.read(0xFF41) & 0x03, 0);
+
+        // The first line is 4 dots short and has no OAM search
+        ppu.step(OAM_SEARCH_DOTS - FIRST_LINE_OFFSET);
+        assert_eq!(ppu.read(0xFF41) & 0x03, 3);
+        ppu.step(LINE_DOTS - OAM_SEARCH_DOTS);
+        assert_eq!(ppu.read(0xFF44), 1);
+
+        ppu.step(FRAME_DOTS * 2);
+        assert_eq!(*frames.borrow(), vec![COLORS[0], COLORS[3]]);
+    }
+
+    #[test]
+    fn vram_and_oam_blocked_by_mode() {
+        let mut ppu = PPU::new(Box::new(NullDisplay {}));
+        ppu.write(0x8000, 0x12);
+        ppu.write(0xFE00, 0x34);
+
+        run_to_line(&mut ppu, 1);
+        assert_eq!(ppu.read(0xFE00), 0xFF);
+        assert_eq!(ppu.read(0x8000), 0x12);
+
+        while ppu.mode != Mode::Transfer { ppu.step(1); }
+        ppu.write(0x8000, 0x56);
+        assert_eq!(ppu.read(0x8000), 0xFF);
+        assert_eq!(ppu.read(0xFE00), 0xFF);
+
+        ppu.set_access_blocking(false);
+        assert_eq!(ppu.read(0x8000), 0x12);
+        assert_eq!(ppu.read(0xFE00), 0x34);
+    }
+}
@@ -1,7 +1,8 @@
 #[derive(Clone, Copy)]
 pub struct Sprite {
-    pub x: u8,
-    pub y: u8,
+    // Screen coordinates, negative when partially off the left or top edge
+    pub x: i16,
+    pub y: i16,
     pub tile: u8,
     pub bg_priority: bool,
     pub y_flip: bool,
@@ -12,8 +13,8 @@ pub struct Sprite {
 impl Sprite {
     pub fn new(x: i32, y: i32, tile: u8, flags: u8) -> Self {
         Self {
-            y: y as u8,
-            x: x as u8,
+            y: y as i16,
+            x: x as i16,
             tile: tile,
             bg_priority: (flags >> 7) & 1 == 1,
             y_flip: (flags >> 6) & 1 == 1,
@@ -0,0 +1,201 @@
+use crate::System;
+use std::collections::VecDeque;
+
+/// Rewind buffer of save states, captured every few frames. Only the newest
+/// state is kept whole: each older one is stored as its difference with the
+/// state that follows it, which is mostly zeros and compresses well.
+pub struct Rewind {
+    interval: u32,
+    capacity: usize,
+    // Frames since the last capture
+    frames: u32,
+    latest: Option<Vec<u8>>,
+    // Oldest first
+    deltas: VecDeque<Vec<u8>>,
+}
+
+impl Rewind {
+    pub fn new(interval: u32, capacity: usize) -> Self {
+        Self {
+            interval: interval.max(1),
+            capacity: capacity.max(1),
+            frames: 0,
+            latest: None,
+            deltas: VecDeque::new(),
+        }
+    }
+
+    // Called after every frame, captures a state when the interval elapsed
+    pub fn frame(&mut self, system: &System) {
+        self.frames += 1;
+
+        if self.frames >= self.interval {
+            self.frames = 0;
+            self.push(system.save_state());
+        }
+    }
+
+    pub fn push(&mut self, state: Vec<u8>) {
+        if let Some(previous) = self.latest.take() {
+            self.deltas.push_back(delta(&previous, &state));
+
+            if self.deltas.len() >= self.capacity {
+                self.deltas.pop_front();
+            }
+        }
+
+        self.latest = Some(state);
+    }
+
+    // Takes the newest state out of the buffer
+    pub fn pop(&mut self) -> Option<Vec<u8>> {
+        let state = self.latest.take()?;
+
+        if let Some(delta) = self.deltas.pop_back() {
+            self.latest = Some(undo(&state, &delta));
+        }
+
+        self.frames = 0;
+        Some(state)
+    }
+
+    // Steps back to the newest state. Returns false once the buffer is empty.
+    pub fn rewind(&mut self, system: &mut System) -> bool {
+        match self.pop() {
+            Some(state) => system.load_state(&state).is_ok(),
+            None => false,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.deltas.len() + self.latest.iter().count()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.latest.is_none()
+    }
+
+    pub fn clear(&mut self) {
+        self.latest = None;
+        self.deltas.clear();
+        self.frames = 0;
+    }
+
+    // Bytes used by the states
+    pub fn memory(&self) -> usize {
+        self.deltas.iter().map(|delta| delta.len()).sum::<usize>()
+            + self.latest.as_ref().map_or(0, |state| state.len())
+    }
+}
+
+fn write_varint(out: &mut Vec<u8>, mut v: usize) {
+    while v >= 0x80 {
+        out.push((v as u8) | 0x80);
+        v >>= 7;
+    }
+    out.push(v as u8);
+}
+
+fn read_varint(data: &[u8], pos: &mut usize) -> usize {
+    let mut v = 0;
+    let mut shift = 0;
+
+    while let Some(byte) = data.get(*pos) {
+        *pos += 1;
+        v |= ((byte & 0x7F) as usize) << shift;
+
+        if byte & 0x80 == 0 {
+            break;
+        }
+        shift += 7;
+    }
+
+    v
+}
+
+// Encodes `old` XOR `new` as runs of unchanged bytes followed by runs of
+// changed ones, so that `old` can be rebuilt from `new`.
+fn delta(old: &[u8], new: &[u8]) -> Vec<u8> {
+    let xor = |i: usize| old[i] ^ new.get(i).copied().unwrap_or(0);
+    let mut out = Vec::new();
+    write_varint(&mut out, old.len());
+
+    let mut i = 0;
+
+    while i < old.len() {
+        let start = i;
+        while i < old.len() && xor(i) == 0 {
+            i += 1;
+        }
+        write_varint(&mut out, i - start);
+
+        let start = i;
+        while i < old.len() && xor(i) != 0 {
+            i += 1;
+        }
+        write_varint(&mut out, i - start);
+        out.extend((start..i).map(xor));
+    }
+
+    out
+}
+
+fn undo(new: &[u8], delta: &[u8]) -> Vec<u8> {
+    let mut pos = 0;
+    let len = read_varint(delta, &mut pos);
+
+    let mut old = new.to_vec();
+    old.resize(len, 0);
+
+    let mut i = 0;
+
+    while i < len && pos < delta.len() {
+        i += read_varint(delta, &mut pos);
+
+        let changed = read_varint(delta, &mut pos);
+        for _ in 0..changed {
+            if let (Some(v), Some(x)) = (old.get_mut(i), delta.get(pos)) {
+                *v ^= x;
+            }
+            i += 1;
+            pos += 1;
+        }
+    }
+
+    old
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn delta_round_trip() {
+        let old = vec![1, 2, 3, 4, 5, 6, 7, 8];
+        let new = vec![1, 2, 9, 4, 5, 6, 0, 0, 10];
+
+        let d = delta(&old, &new);
+        assert_eq!(undo(&new, &d), old);
+        assert_eq!(undo(&old, &delta(&new, &old)), new);
+
+        // Unchanged states cost a few bytes
+        assert!(delta(&[0x55; 4096], &[0x55; 4096]).len() < 8);
+    }
+
+    #[test]
+    fn states_come_back_newest_first() {
+        let mut rewind = Rewind::new(1, 3);
+
+        for i in 0..5_u8 {
+            rewind.push(vec![i; 16]);
+        }
+
+        // Only the newest states fit
+        assert_eq!(rewind.len(), 3);
+        assert_eq!(rewind.pop(), Some(vec![4; 16]));
+        assert_eq!(rewind.pop(), Some(vec![3; 16]));
+        assert_eq!(rewind.pop(), Some(vec![2; 16]));
+        assert_eq!(rewind.pop(), None);
+        assert!(rewind.is_empty());
+    }
+}
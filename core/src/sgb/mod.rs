@@ -0,0 +1,618 @@
+use crate::ppu::colors::Shades;
+use crate::state::{Snapshot, StateReader, StateWriter};
+
+pub const SGB_SCREEN_W: usize = 256;
+pub const SGB_SCREEN_H: usize = 224;
+
+const GB_SCREEN_W: usize = 160;
+const GB_SCREEN_H: usize = 144;
+// Position of the Game Boy screen inside the border
+const GB_SCREEN_X: usize = 48;
+const GB_SCREEN_Y: usize = 40;
+
+// The attribute map assigns a palette to each 8x8 cell of the screen
+const ATTR_W: usize = 20;
+const ATTR_H: usize = 18;
+
+const PACKET_SIZE: usize = 16;
+const PACKET_BITS: usize = PACKET_SIZE * 8;
+
+const BORDER_W: usize = 32;
+const BORDER_H: usize = 28;
+const BORDER_TILE_SIZE: usize = 32;
+const BORDER_PALETTES: usize = 4;
+
+// Palette used by the SGB until the game sends its own
+const DEFAULT_SHADES: Shades = [0xF8E8C8, 0xD89048, 0xA82820, 0x301850];
+
+const PAL01: u8 = 0x00;
+const PAL23: u8 = 0x01;
+const PAL03: u8 = 0x02;
+const PAL12: u8 = 0x03;
+const ATTR_BLK: u8 = 0x04;
+const ATTR_LIN: u8 = 0x05;
+const ATTR_DIV: u8 = 0x06;
+const ATTR_CHR: u8 = 0x07;
+const MLT_REQ: u8 = 0x11;
+const CHR_TRN: u8 = 0x13;
+const PCT_TRN: u8 = 0x14;
+const MASK_EN: u8 = 0x17;
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+enum Mask {
+    None,
+    // Keeps showing the last frame
+    Freeze,
+    Black,
+    Color0,
+}
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+enum Transfer {
+    // Border tiles, first or second half of the tile set
+    Chr(usize),
+    // Border map and palettes
+    Pct,
+}
+
+// Decodes the bits sent by pulsing P14 (0) and P15 (1). Each packet starts
+// with both lines low and ends with a 0 bit after its 128 data bits.
+struct PacketReader {
+    packet: [u8; PACKET_SIZE],
+    bits: usize,
+    receiving: bool,
+    // Both lines have to go high again between pulses
+    armed: bool,
+}
+
+impl PacketReader {
+    pub fn new() -> Self {
+        Self {
+            packet: [0; PACKET_SIZE],
+            bits: 0,
+            receiving: false,
+            armed: false,
+        }
+    }
+
+    pub fn write(&mut self, v: u8) -> Option<[u8; PACKET_SIZE]> {
+        match v & 0x30 {
+            0x00 => {
+                self.packet = [0; PACKET_SIZE];
+                self.bits = 0;
+                self.receiving = true;
+                self.armed = false;
+            }
+            0x30 => {
+                self.armed = true;
+            }
+            pulse if self.receiving && self.armed => {
+                self.armed = false;
+                let bit = if pulse == 0x10 { 1 } else { 0 };
+
+                if self.bits == PACKET_BITS {
+                    self.receiving = false;
+                    // A packet not followed by the 0 stop bit is dropped
+                    return if bit == 0 { Some(self.packet) } else { None };
+                }
+
+                self.packet[self.bits / 8] |= bit << (self.bits % 8);
+                self.bits += 1;
+            }
+            _ => {}
+        }
+
+        None
+    }
+}
+
+pub struct Sgb {
+    reader: PacketReader,
+    // Cartridges without the SGB flag in the header can't send commands
+    commands: bool,
+    // Data of the command being received, which may span several packets
+    data: Vec<u8>,
+    palettes: [Shades; 4],
+    attrs: [u8; ATTR_W * ATTR_H],
+    mask: Mask,
+    transfer: Option<Transfer>,
+    border_tiles: Vec<u8>,
+    border_map: Vec<u16>,
+    border_palettes: [[u32; 16]; BORDER_PALETTES],
+    players: u8,
+    // Last Game Boy frame, as shades 0-3, kept while the screen is frozen
+    frame: Vec<u32>,
+    screen: Vec<u32>,
+}
+
+impl Sgb {
+    pub fn new(commands: bool) -> Self {
+        Self {
+            reader: PacketReader::new(),
+            commands,
+            data: Vec::with_capacity(PACKET_SIZE * 7),
+            palettes: [DEFAULT_SHADES; 4],
+            attrs: [0; ATTR_W * ATTR_H],
+            mask: Mask::None,
+            transfer: None,
+            border_tiles: vec![0; 256 * BORDER_TILE_SIZE],
+            border_map: vec![0; BORDER_W * BORDER_H],
+            border_palettes: [[0; 16]; BORDER_PALETTES],
+            players: 1,
+            frame: vec![0; GB_SCREEN_W * GB_SCREEN_H],
+            screen: vec![0; SGB_SCREEN_W * SGB_SCREEN_H],
+        }
+    }
+
+    // Observes the writes to the joypad register
+    pub fn write(&mut self, v: u8) {
+        if !self.commands {
+            return;
+        }
+
+        if let Some(packet) = self.reader.write(v) {
+            self.receive(&packet);
+        }
+    }
+
+    pub fn players(&self) -> u8 {
+        self.players
+    }
+
+    pub fn transfer_pending(&self) -> bool {
+        self.transfer.is_some()
+    }
+
+    // Receives the 4KB shown on screen after a *_TRN command
+    pub fn transfer(&mut self, data: &[u8]) {
+        match self.transfer.take() {
+            Some(Transfer::Chr(bank)) => {
+                let from = bank * 0x1000;
+                self.border_tiles[from..from + 0x1000].copy_from_slice(&data[..0x1000]);
+            }
+            Some(Transfer::Pct) => {
+                for (i, entry) in self.border_map.iter_mut().enumerate() {
+                    *entry = word(data, i * 2);
+                }
+
+                for (p, palette) in self.border_palettes.iter_mut().enumerate() {
+                    for (c, color) in palette.iter_mut().enumerate() {
+                        *color = to_rgb(word(data, 0x800 + (p * 16 + c) * 2));
+                    }
+                }
+            }
+            None => {}
+        }
+    }
+
+    fn receive(&mut self, packet: &[u8; PACKET_SIZE]) {
+        self.data.extend_from_slice(packet);
+
+        let length = match (self.data[0] & 0x07) as usize {
+            0 => 1,
+            n => n,
+        };
+
+        if self.data.len() < length * PACKET_SIZE {
+            return;
+        }
+
+        let data = std::mem::replace(&mut self.data, Vec::with_capacity(PACKET_SIZE * 7));
+        self.command(data[0] >> 3, &data);
+    }
+
+    fn command(&mut self, command: u8, data: &[u8]) {
+        match command {
+            PAL01 => self.set_palettes(0, 1, data),
+            PAL23 => self.set_palettes(2, 3, data),
+            PAL03 => self.set_palettes(0, 3, data),
+            PAL12 => self.set_palettes(1, 2, data),
+            ATTR_BLK => self.attr_blk(data),
+            ATTR_LIN => self.attr_lin(data),
+            ATTR_DIV => self.attr_div(data),
+            ATTR_CHR => self.attr_chr(data),
+            MLT_REQ => {
+                self.players = match data[1] & 0x03 {
+                    1 => 2,
+                    3 => 4,
+                    _ => 1,
+                };
+            }
+            CHR_TRN => self.transfer = Some(Transfer::Chr((data[1] & 0x01) as usize)),
+            PCT_TRN => self.transfer = Some(Transfer::Pct),
+            MASK_EN => {
+                self.mask = match data[1] & 0x03 {
+                    1 => Mask::Freeze,
+                    2 => Mask::Black,
+                    3 => Mask::Color0,
+                    _ => Mask::None,
+                };
+            }
+            _ => {}
+        }
+    }
+
+    // Color 0 is shared by all the palettes
+    fn set_palettes(&mut self, a: usize, b: usize, data: &[u8]) {
+        let color0 = to_rgb(word(data, 1));
+
+        for palette in self.palettes.iter_mut() {
+            palette[0] = color0;
+        }
+
+        for i in 0..3 {
+            self.palettes[a][i + 1] = to_rgb(word(data, 3 + i * 2));
+            self.palettes[b][i + 1] = to_rgb(word(data, 9 + i * 2));
+        }
+    }
+
+    fn attr_blk(&mut self, data: &[u8]) {
+        let count = data[1] as usize;
+
+        for set in data[2..].chunks(6).take(count).filter(|set| set.len() == 6) {
+            let control = set[0] & 0x07;
+            let inside = set[1] & 0x03;
+            let outside = (set[1] >> 4) & 0x03;
+
+            // When only one side is changed the border takes its palette
+            let border = match control {
+                0x01 => Some(inside),
+                0x04 => Some(outside),
+                _ if control & 0x02 != 0 => Some((set[1] >> 2) & 0x03),
+                _ => None,
+            };
+
+            let (x1, y1) = ((set[2] & 0x1F) as usize, (set[3] & 0x1F) as usize);
+            let (x2, y2) = ((set[4] & 0x1F) as usize, (set[5] & 0x1F) as usize);
+
+            for y in 0..ATTR_H {
+                for x in 0..ATTR_W {
+                    let within = x >= x1 && x <= x2 && y >= y1 && y <= y2;
+                    let edge = within && (x == x1 || x == x2 || y == y1 || y == y2);
+
+                    let palette = if edge {
+                        border
+                    } else if within {
+                        if control & 0x01 != 0 { Some(inside) } else { None }
+                    } else if control & 0x04 != 0 {
+                        Some(outside)
+                    } else {
+                        None
+                    };
+
+                    if let Some(palette) = palette {
+                        self.attrs[y * ATTR_W + x] = palette;
+                    }
+                }
+            }
+        }
+    }
+
+    fn attr_lin(&mut self, data: &[u8]) {
+        let count = data[1] as usize;
+
+        for &line in data[2..].iter().take(count) {
+            let n = (line & 0x1F) as usize;
+            let palette = (line >> 5) & 0x03;
+
+            if line & 0x80 != 0 {
+                if n < ATTR_H {
+                    for x in 0..ATTR_W { self.attrs[n * ATTR_W + x] = palette; }
+                }
+            } else if n < ATTR_W {
+                for y in 0..ATTR_H { self.attrs[y * ATTR_W + n] = palette; }
+            }
+        }
+    }
+
+    fn attr_div(&mut self, data: &[u8]) {
+        let after = data[1] & 0x03;
+        let before = (data[1] >> 2) & 0x03;
+        let on_line = (data[1] >> 4) & 0x03;
+        let horizontal = data[1] & 0x40 != 0;
+        let coord = (data[2] & 0x1F) as usize;
+
+        for y in 0..ATTR_H {
+            for x in 0..ATTR_W {
+                let n = if horizontal { y } else { x };
+
+                self.attrs[y * ATTR_W + x] =
+                    if n < coord { before } else if n == coord { on_line } else { after };
+            }
+        }
+    }
+
+    fn attr_chr(&mut self, data: &[u8]) {
+        let mut x = (data[1] as usize) % ATTR_W;
+        let mut y = (data[2] as usize) % ATTR_H;
+        let count = (word(data, 3) as usize).min(ATTR_W * ATTR_H);
+        let vertical = data[5] & 0x01 != 0;
+
+        for i in 0..count {
+            let byte = match data.get(6 + i / 4) {
+                Some(byte) => *byte,
+                None => break,
+            };
+
+            self.attrs[y * ATTR_W + x] = (byte >> (6 - (i % 4) * 2)) & 0x03;
+
+            if vertical {
+                y += 1;
+                if y == ATTR_H { y = 0; x = (x + 1) % ATTR_W; }
+            } else {
+                x += 1;
+                if x == ATTR_W { x = 0; y = (y + 1) % ATTR_H; }
+            }
+        }
+    }
+
+    // Composes the border and the Game Boy frame, given as shades 0-3
+    pub fn render(&mut self, frame: &[u32]) -> &Vec<u32> {
+        if self.mask != Mask::Freeze {
+            self.frame.copy_from_slice(frame);
+        }
+
+        let backdrop = self.palettes[0][0];
+
+        for y in 0..SGB_SCREEN_H {
+            for x in 0..SGB_SCREEN_W {
+                let color = self.border_color(x, y);
+
+                let gx = x.wrapping_sub(GB_SCREEN_X);
+                let gy = y.wrapping_sub(GB_SCREEN_Y);
+                let in_screen = gx < GB_SCREEN_W && gy < GB_SCREEN_H;
+
+                self.screen[y * SGB_SCREEN_W + x] = match color {
+                    Some(color) => color,
+                    None if in_screen => match self.mask {
+                        Mask::Black => 0x000000,
+                        Mask::Color0 => backdrop,
+                        _ => {
+                            let palette = self.attrs[(gy / 8) * ATTR_W + gx / 8] as usize;
+                            let shade = self.frame[gy * GB_SCREEN_W + gx] as usize & 0x03;
+                            self.palettes[palette][shade]
+                        }
+                    },
+                    None => backdrop,
+                };
+            }
+        }
+
+        &self.screen
+    }
+
+    // Border pixels using color 0 are transparent
+    fn border_color(&self, x: usize, y: usize) -> Option<u32> {
+        let entry = self.border_map[(y / 8) * BORDER_W + x / 8];
+        let tile = (entry & 0xFF) as usize * BORDER_TILE_SIZE;
+        let palette = ((entry >> 10) & 0x03) as usize;
+
+        let col = if entry & 0x4000 != 0 { 7 - x % 8 } else { x % 8 };
+        let row = if entry & 0x8000 != 0 { 7 - y % 8 } else { y % 8 };
+        let bit = 7 - col;
+
+        let planes = [
+            self.border_tiles[tile + row * 2],
+            self.border_tiles[tile + row * 2 + 1],
+            self.border_tiles[tile + 16 + row * 2],
+            self.border_tiles[tile + 16 + row * 2 + 1],
+        ];
+
+        let color = planes.iter()
+            .enumerate()
+            .fold(0, |color, (i, plane)| color | (((plane >> bit) & 1) << i));
+
+        match color {
+            0 => None,
+            c => Some(self.border_palettes[palette][c as usize]),
+        }
+    }
+}
+
+fn word(data: &[u8], i: usize) -> u16 {
+    let l = *data.get(i).unwrap_or(&0) as u16;
+    let h = *data.get(i + 1).unwrap_or(&0) as u16;
+    h << 8 | l
+}
+
+// Expands a BGR555 color
+fn to_rgb(color: u16) -> u32 {
+    let expand = |v: u16| ((v << 3) | (v >> 2)) as u32;
+
+    let r = expand(color & 0x1F);
+    let g = expand((color >> 5) & 0x1F);
+    let b = expand((color >> 10) & 0x1F);
+
+    r << 16 | g << 8 | b
+}
+
+impl Snapshot for Sgb {
+    fn save_state(&self, w: &mut StateWriter) {
+        w.bytes(&self.reader.packet);
+        w.u8(self.reader.bits as u8);
+        w.bool(self.reader.receiving);
+        w.bool(self.reader.armed);
+
+        w.bool(self.commands);
+        w.bytes(&self.data);
+        for shades in self.palettes.iter() {
+            w.u32s(shades);
+        }
+        w.bytes(&self.attrs);
+        w.u8(self.mask as u8);
+        w.u8(match self.transfer {
+            None => 0,
+            Some(Transfer::Chr(half)) => 1 + half as u8,
+            Some(Transfer::Pct) => 3,
+        });
+        w.bytes(&self.border_tiles);
+        self.border_map.iter().for_each(|v| w.u16(*v));
+        for palette in self.border_palettes.iter() {
+            w.u32s(palette);
+        }
+        w.u8(self.players);
+        w.u32s(&self.frame);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> Result<(), Box<dyn std::error::Error>> {
+        r.bytes_into(&mut self.reader.packet)?;
+        self.reader.bits = (r.u8()? as usize).min(PACKET_BITS);
+        self.reader.receiving = r.bool()?;
+        self.reader.armed = r.bool()?;
+
+        self.commands = r.bool()?;
+        self.data = r.bytes()?;
+        for shades in self.palettes.iter_mut() {
+            r.u32s_into(shades)?;
+        }
+        r.bytes_into(&mut self.attrs)?;
+        self.mask = r.choice(&[Mask::None, Mask::Freeze, Mask::Black, Mask::Color0])?;
+        self.transfer = r.choice(&[None, Some(Transfer::Chr(0)), Some(Transfer::Chr(1)), Some(Transfer::Pct)])?;
+        r.bytes_into(&mut self.border_tiles)?;
+        for v in self.border_map.iter_mut() {
+            *v = r.u16()?;
+        }
+        for palette in self.border_palettes.iter_mut() {
+            r.u32s_into(palette)?;
+        }
+        self.players = r.u8()?;
+        r.u32s_into(&mut self.frame)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn send(sgb: &mut Sgb, data: &[u8]) {
+        for packet in data.chunks(PACKET_SIZE) {
+            sgb.write(0x00);
+            sgb.write(0x30);
+
+            for i in 0..PACKET_BITS {
+                let bit = packet.get(i / 8).map_or(0, |byte| (byte >> (i % 8)) & 1);
+                sgb.write(if bit == 1 { 0x10 } else { 0x20 });
+                sgb.write(0x30);
+            }
+
+            sgb.write(0x20);
+            sgb.write(0x30);
+        }
+    }
+
+    fn attr(sgb: &Sgb, x: usize, y: usize) -> u8 {
+        sgb.attrs[y * ATTR_W + x]
+    }
+
+    #[test]
+    fn pal01_sets_palettes() {
+        let mut sgb = Sgb::new(true);
+        send(&mut sgb, &[
+            PAL01 << 3 | 1,
+            0xFF, 0x7F, // White
+            0x1F, 0x00, 0xE0, 0x03, 0x00, 0x7C, // Red, green, blue
+            0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+        ]);
+
+        assert_eq!(sgb.palettes[0], [0xFFFFFF, 0xFF0000, 0x00FF00, 0x0000FF]);
+        assert_eq!(sgb.palettes[1], [0xFFFFFF, 0, 0, 0]);
+        assert_eq!(sgb.palettes[2][0], 0xFFFFFF);
+        assert_eq!(sgb.palettes[2][1], DEFAULT_SHADES[1]);
+    }
+
+    #[test]
+    fn commands_need_sgb_cartridge() {
+        let mut sgb = Sgb::new(false);
+        send(&mut sgb, &[MLT_REQ << 3 | 1, 0x01]);
+        assert_eq!(sgb.players(), 1);
+    }
+
+    #[test]
+    fn packet_without_stop_bit_is_dropped() {
+        let mut sgb = Sgb::new(true);
+        sgb.write(0x00);
+        sgb.write(0x30);
+
+        for i in 0..=PACKET_BITS {
+            let bit = if i < 8 { (MLT_REQ << 3 | 1) >> i & 1 } else { 1 };
+            sgb.write(if bit == 1 { 0x10 } else { 0x20 });
+            sgb.write(0x30);
+        }
+
+        assert_eq!(sgb.players(), 1);
+    }
+
+    #[test]
+    fn attr_blk_regions() {
+        let mut sgb = Sgb::new(true);
+        send(&mut sgb, &[
+            ATTR_BLK << 3 | 1,
+            1,
+            0x07, 0b00_10_01_11, 2, 2, 5, 5,
+        ]);
+
+        assert_eq!(attr(&sgb, 3, 3), 3);
+        assert_eq!(attr(&sgb, 2, 4), 1);
+        assert_eq!(attr(&sgb, 5, 5), 1);
+        assert_eq!(attr(&sgb, 0, 0), 2);
+        assert_eq!(attr(&sgb, 19, 17), 2);
+
+        // Only the inside is changed, the border follows it
+        send(&mut sgb, &[ATTR_BLK << 3 | 1, 1, 0x01, 0x00, 2, 2, 5, 5]);
+        assert_eq!(attr(&sgb, 2, 2), 0);
+        assert_eq!(attr(&sgb, 0, 0), 2);
+    }
+
+    #[test]
+    fn attr_lin_div_and_chr() {
+        let mut sgb = Sgb::new(true);
+
+        send(&mut sgb, &[ATTR_DIV << 3 | 1, 0b0_01_10_11, 9]);
+        assert_eq!(attr(&sgb, 8, 0), 2);
+        assert_eq!(attr(&sgb, 9, 17), 1);
+        assert_eq!(attr(&sgb, 10, 5), 3);
+
+        send(&mut sgb, &[ATTR_LIN << 3 | 1, 2, 0x80 | 0x20 | 4, 0x40 | 1]);
+        assert_eq!(attr(&sgb, 0, 4), 1);
+        assert_eq!(attr(&sgb, 19, 4), 1);
+        assert_eq!(attr(&sgb, 1, 17), 2);
+
+        send(&mut sgb, &[ATTR_CHR << 3 | 1, 18, 0, 3, 0, 0, 0b11_10_01_00]);
+        assert_eq!(attr(&sgb, 18, 0), 3);
+        assert_eq!(attr(&sgb, 19, 0), 2);
+        assert_eq!(attr(&sgb, 0, 1), 1);
+    }
+
+    #[test]
+    fn render_border_and_screen() {
+        let mut sgb = Sgb::new(true);
+
+        send(&mut sgb, &[PCT_TRN << 3 | 1]);
+        assert!(sgb.transfer_pending());
+
+        // Border cell (0, 0) uses tile 1 from palette 4
+        let mut data = vec![0; 0x1000];
+        data[0] = 0x01;
+        data[1] = 0x10;
+        data[0x802] = 0x1F;
+        sgb.transfer(&data);
+
+        send(&mut sgb, &[CHR_TRN << 3 | 1, 0]);
+        let mut tiles = vec![0; 0x1000];
+        tiles[BORDER_TILE_SIZE] = 0x80;
+        sgb.transfer(&tiles);
+        assert!(!sgb.transfer_pending());
+
+        let frame = vec![3; GB_SCREEN_W * GB_SCREEN_H];
+        let screen = sgb.render(&frame).clone();
+
+        assert_eq!(screen[0], 0xFF0000);
+        assert_eq!(screen[1], DEFAULT_SHADES[0]);
+        assert_eq!(screen[GB_SCREEN_Y * SGB_SCREEN_W + GB_SCREEN_X], DEFAULT_SHADES[3]);
+
+        send(&mut sgb, &[MASK_EN << 3 | 1, 2]);
+        let screen = sgb.render(&frame);
+        assert_eq!(screen[GB_SCREEN_Y * SGB_SCREEN_W + GB_SCREEN_X], 0);
+    }
+}
@@ -0,0 +1,161 @@
+// Serialization of the emulated state, used by save states and rewind.
+//
+// Components write their fields in a fixed order with a StateWriter and read
+// them back in the same order with a StateReader. Only the state of the
+// hardware is kept: settings such as colors or cheats are left alone.
+
+type Result<T> = std::result::Result<T, Box<dyn std::error::Error>>;
+
+pub trait Snapshot {
+    fn save_state(&self, w: &mut StateWriter);
+    fn load_state(&mut self, r: &mut StateReader) -> Result<()>;
+}
+
+pub struct StateWriter {
+    data: Vec<u8>,
+}
+
+impl StateWriter {
+    pub fn new() -> Self {
+        Self { data: Vec::with_capacity(0x20000) }
+    }
+
+    pub fn into_bytes(self) -> Vec<u8> {
+        self.data
+    }
+
+    pub fn u8(&mut self, v: u8) {
+        self.data.push(v);
+    }
+
+    pub fn bool(&mut self, v: bool) {
+        self.data.push(v as u8);
+    }
+
+    pub fn u16(&mut self, v: u16) {
+        self.data.extend_from_slice(&v.to_le_bytes());
+    }
+
+    pub fn u32(&mut self, v: u32) {
+        self.data.extend_from_slice(&v.to_le_bytes());
+    }
+
+    // Length prefixed
+    pub fn bytes(&mut self, v: &[u8]) {
+        self.u32(v.len() as u32);
+        self.data.extend_from_slice(v);
+    }
+
+    pub fn u32s(&mut self, v: &[u32]) {
+        self.u32(v.len() as u32);
+        v.iter().for_each(|v| self.u32(*v));
+    }
+}
+
+pub struct StateReader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> StateReader<'a> {
+    pub fn new(data: &'a [u8]) -> Self {
+        Self { data, pos: 0 }
+    }
+
+    fn take(&mut self, count: usize) -> Result<&'a [u8]> {
+        let bytes = self.data.get(self.pos..self.pos + count).ok_or("Truncated state")?;
+        self.pos += count;
+        Ok(bytes)
+    }
+
+    pub fn u8(&mut self) -> Result<u8> {
+        Ok(self.take(1)?[0])
+    }
+
+    pub fn bool(&mut self) -> Result<bool> {
+        Ok(self.u8()? != 0)
+    }
+
+    pub fn u16(&mut self) -> Result<u16> {
+        let b = self.take(2)?;
+        Ok(u16::from_le_bytes([b[0], b[1]]))
+    }
+
+    pub fn u32(&mut self) -> Result<u32> {
+        let b = self.take(4)?;
+        Ok(u32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+    }
+
+    pub fn bytes(&mut self) -> Result<Vec<u8>> {
+        let len = self.u32()? as usize;
+        Ok(self.take(len)?.to_vec())
+    }
+
+    // Fills a buffer of a fixed size, like RAM
+    pub fn bytes_into(&mut self, buffer: &mut [u8]) -> Result<()> {
+        if self.u32()? as usize != buffer.len() {
+            return Err("The state doesn't match the emulated hardware".into());
+        }
+
+        buffer.copy_from_slice(self.take(buffer.len())?);
+        Ok(())
+    }
+
+    pub fn u32s_into(&mut self, buffer: &mut [u32]) -> Result<()> {
+        if self.u32()? as usize != buffer.len() {
+            return Err("The state doesn't match the emulated hardware".into());
+        }
+
+        for v in buffer.iter_mut() {
+            *v = self.u32()?;
+        }
+        Ok(())
+    }
+
+    // Enum stored as its index
+    pub fn choice<T: Copy>(&mut self, values: &[T]) -> Result<T> {
+        let i = self.u8()? as usize;
+        values.get(i).copied().ok_or_else(|| "Invalid state".into())
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.pos == self.data.len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn write_and_read_back() {
+        let mut w = StateWriter::new();
+        w.u8(0x12);
+        w.bool(true);
+        w.u16(0x3456);
+        w.u32(0x789ABCDE);
+        w.bytes(&[1, 2, 3]);
+        w.u32s(&[0xFFFFFF, 0]);
+
+        let data = w.into_bytes();
+        let mut r = StateReader::new(&data);
+        assert_eq!(r.u8().unwrap(), 0x12);
+        assert!(r.bool().unwrap());
+        assert_eq!(r.u16().unwrap(), 0x3456);
+        assert_eq!(r.u32().unwrap(), 0x789ABCDE);
+
+        // Fixed size buffers must match
+        let mut small = [0; 2];
+        assert!(StateReader::new(&data[8..]).bytes_into(&mut small).is_err());
+
+        let mut buffer = [0; 3];
+        r.bytes_into(&mut buffer).unwrap();
+        assert_eq!(buffer, [1, 2, 3]);
+
+        let mut shades = [0; 2];
+        r.u32s_into(&mut shades).unwrap();
+        assert_eq!(shades, [0xFFFFFF, 0]);
+        assert!(r.is_empty());
+        assert!(r.u8().is_err());
+    }
+}
@@ -0,0 +1,8 @@
+// Fixtures shared by the unit tests.
+
+use crate::Display;
+
+// Drops every frame
+pub struct NullDisplay {}
+
+impl Display for NullDisplay {}
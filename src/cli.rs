@@ -0,0 +1,254 @@
+use crate::timing::SyncMode;
+use core::{Combo, Model, Renderer};
+use std::path::PathBuf;
+
+pub const USAGE: &str = "Usage: gamebrust [options] <rom-file|zip|7z|gz>
+
+Hardware:
+    --model <name>             dmg0, dmg, mgb, sgb, sgb2, cgb or agb (default: dmg)
+    --bootrom <file>           Run a boot ROM instead of skipping it
+    --sgb                      Emulate the Super Game Boy, with its border
+    --no-access-blocking       Let the CPU access VRAM and OAM while the PPU uses them
+
+ROM:
+    --rom-entry <name>         File to load from an archive (default: the first ROM)
+    --patch <file>             Apply an IPS, UPS or BPS patch (default: the one next to the ROM)
+    --cheats <file>            Load GameShark and Game Genie codes (default: <TITLE>.cht
+                               in the save directory, if it exists)
+
+Video:
+    --palette <name|file>      green, pocket, light, contrast or a palette file
+    --colorize <auto|combo>    Pick colors like a Game Boy Color, 'auto' or a combo such as 'up+a'
+    --title-palettes <file>    Override entries of the boot ROM table of palettes per title
+    --renderer <scanline|fifo> How the PPU draws lines (default: scanline)
+    --scale <n>                Initial window size as a multiple of the screen (default: 3)
+    --fullscreen               Borderless window covering the screen
+
+Timing and sound:
+    --sync <video|audio|free>  What paces the emulation (default: video)
+    --mute                     Don't play sound, does nothing until audio is emulated
+
+Movies:
+    --record <movie>           Record the input from power-on, or from --state
+    --play <movie>             Replay a recorded movie
+    --state <file>             Start from a save state
+
+Files:
+    --config <file>            Settings and key bindings (default: gamebrust/config.toml
+                               in the user's config directory)
+    --save-dir <dir>           Where save states and screenshots go (default: next to the ROM)
+
+Without a window:
+    --headless                 Run as fast as possible without a window, requires --frames
+    --frames <n>               Frames to run
+    --screenshot <png>         Save the last frame
+    --play <movie>             Input to replay, as above
+
+    -h, --help                 Show this help";
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct Options {
+    pub rom: PathBuf,
+    pub model: Model,
+    pub bootrom: Option<PathBuf>,
+    pub sgb: bool,
+    pub access_blocking: bool,
+    pub entry: Option<String>,
+    pub patch: Option<PathBuf>,
+    pub cheats: Option<PathBuf>,
+    pub palette: Option<String>,
+    pub colorize: Option<Option<Combo>>,
+    pub title_palettes: Option<PathBuf>,
+    pub renderer: Option<Renderer>,
+    pub scale: Option<usize>,
+    pub fullscreen: bool,
+    pub sync: SyncMode,
+    pub mute: bool,
+    pub record: Option<PathBuf>,
+    pub play: Option<PathBuf>,
+    pub state: Option<PathBuf>,
+    pub save_dir: Option<PathBuf>,
+    pub config: Option<PathBuf>,
+    pub headless: bool,
+    pub frames: Option<u32>,
+    pub screenshot: Option<PathBuf>,
+}
+
+impl Options {
+    // None when the help was asked for
+    pub fn parse<I: IntoIterator<Item = String>>(args: I) -> Result<Option<Options>, String> {
+        let mut rom = None;
+        let mut options = Options {
+            rom: PathBuf::new(),
+            model: Model::default(),
+            bootrom: None,
+            sgb: false,
+            access_blocking: true,
+            entry: None,
+            patch: None,
+            cheats: None,
+            palette: None,
+            colorize: None,
+            title_palettes: None,
+            renderer: None,
+            scale: None,
+            fullscreen: false,
+            sync: SyncMode::Video,
+            mute: false,
+            record: None,
+            play: None,
+            state: None,
+            save_dir: None,
+            config: None,
+            headless: false,
+            frames: None,
+            screenshot: None,
+        };
+
+        let mut args = args.into_iter();
+
+        while let Some(arg) = args.next() {
+            let mut value = |what: &str| args.next().ok_or(format!("{} requires {}", arg, what));
+
+            match arg.as_str() {
+                "-h" | "--help" => return Ok(None),
+                "--model" => {
+                    let name = value("a model name")?;
+                    options.model = Model::from_name(&name).ok_or(format!("Unknown model '{}'", name))?;
+                }
+                "--bootrom" => options.bootrom = Some(PathBuf::from(value("a file")?)),
+                "--sgb" => options.sgb = true,
+                "--no-access-blocking" => options.access_blocking = false,
+                "--rom-entry" => options.entry = Some(value("the name of a file in the archive")?),
+                "--patch" => options.patch = Some(PathBuf::from(value("an IPS, UPS or BPS file")?)),
+                "--cheats" => options.cheats = Some(PathBuf::from(value("a file")?)),
+                "--palette" => options.palette = Some(value("a preset name or a file")?),
+                "--colorize" => {
+                    let name = value("'auto' or a combo such as 'up+a'")?;
+                    options.colorize = match name.as_str() {
+                        "auto" => Some(None),
+                        name => Some(Some(Combo::from_name(name).ok_or(format!("Invalid colorize combo '{}'", name))?)),
+                    };
+                }
+                "--title-palettes" => options.title_palettes = Some(PathBuf::from(value("a file")?)),
+                "--renderer" => {
+                    let name = value("a renderer")?;
+                    options.renderer = Some(Renderer::from_name(&name).ok_or(format!("Unknown renderer '{}'", name))?);
+                }
+                "--scale" => {
+                    options.scale = match value("a number")?.parse() {
+                        Ok(scale) if scale > 0 => Some(scale),
+                        _ => return Err("--scale requires a positive number".to_string()),
+                    };
+                }
+                "--fullscreen" => options.fullscreen = true,
+                "--sync" => {
+                    let name = value("video, audio or free")?;
+                    options.sync = SyncMode::from_name(&name).ok_or(format!("Unknown sync mode '{}'", name))?;
+                }
+                "--mute" => options.mute = true,
+                "--record" => options.record = Some(PathBuf::from(value("a movie file")?)),
+                "--play" => options.play = Some(PathBuf::from(value("a movie file")?)),
+                "--state" => options.state = Some(PathBuf::from(value("a save state")?)),
+                "--save-dir" => options.save_dir = Some(PathBuf::from(value("a directory")?)),
+                "--config" => options.config = Some(PathBuf::from(value("a file")?)),
+                "--headless" => options.headless = true,
+                "--frames" => {
+                    let frames = value("a number")?;
+                    options.frames = Some(frames.parse().map_err(|_| "--frames requires a number".to_string())?);
+                }
+                "--screenshot" => options.screenshot = Some(PathBuf::from(value("a PNG file")?)),
+                flag if flag.starts_with('-') => return Err(format!("Unknown option '{}'", flag)),
+                _ if rom.is_some() => return Err(format!("Unexpected argument '{}', the ROM was already given", arg)),
+                _ => rom = Some(PathBuf::from(arg)),
+            }
+        }
+
+        options.rom = rom.ok_or("No ROM given")?;
+
+        if options.headless && options.frames.is_none() {
+            return Err("--headless requires --frames".to_string());
+        }
+
+        if !options.headless && (options.frames.is_some() || options.screenshot.is_some()) {
+            return Err("--frames and --screenshot only work with --headless".to_string());
+        }
+
+        if options.record.is_some() && options.play.is_some() {
+            return Err("--record and --play can't be used together".to_string());
+        }
+
+        // Movies hold their own start
+        if options.state.is_some() && options.play.is_some() {
+            return Err("--state and --play can't be used together".to_string());
+        }
+
+        if options.headless && options.record.is_some() {
+            return Err("--record needs a window".to_string());
+        }
+
+        Ok(Some(options))
+    }
+
+    pub fn sgb(&self) -> bool {
+        self.sgb || self.model.is_sgb()
+    }
+
+    // Directory for the files written while playing
+    pub fn save_dir(&self) -> PathBuf {
+        match (&self.save_dir, self.rom.parent()) {
+            (Some(dir), _) => dir.clone(),
+            (None, Some(dir)) => dir.to_path_buf(),
+            (None, None) => PathBuf::new(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(args: &str) -> Result<Option<Options>, String> {
+        Options::parse(args.split_whitespace().map(String::from))
+    }
+
+    fn options(args: &str) -> Options {
+        match parse(args) {
+            Ok(Some(options)) => options,
+            other => panic!("{:?}", other),
+        }
+    }
+
+    #[test]
+    fn parse_options() {
+        let options = options("--model cgb --renderer fifo --scale 2 --headless --frames 60 --screenshot out.png roms/tetris.gb");
+        assert_eq!(options.rom, PathBuf::from("roms/tetris.gb"));
+        assert_eq!(options.model, Model::CGB);
+        assert_eq!(options.renderer, Some(Renderer::Fifo));
+        assert!(options.access_blocking);
+        assert!(!options.mute);
+        assert!(self::options("--mute tetris.gb").mute);
+        assert!(!self::options("--no-access-blocking tetris.gb").access_blocking);
+        assert_eq!(options.scale, Some(2));
+        assert_eq!(options.frames, Some(60));
+        assert_eq!(options.screenshot, Some(PathBuf::from("out.png")));
+        assert_eq!(options.save_dir(), PathBuf::from("roms"));
+
+        assert_eq!(parse("tetris.gb --help"), Ok(None));
+    }
+
+    #[test]
+    fn invalid_options() {
+        assert!(parse("").is_err());
+        assert!(parse("tetris.gb --scale").is_err());
+        assert!(parse("tetris.gb --scale 0").is_err());
+        assert!(parse("tetris.gb --model nes").is_err());
+        assert!(parse("tetris.gb --renderer gpu").is_err());
+        assert!(parse("tetris.gb --turbo").is_err());
+        assert!(parse("tetris.gb zelda.gb").is_err());
+        assert!(parse("tetris.gb --headless").is_err());
+        assert!(parse("tetris.gb --frames 10").is_err());
+        assert!(parse("tetris.gb --headless --frames 10 --record run.gbm").is_err());
+        assert!(parse("tetris.gb --state tetris.state1 --play run.gbm").is_err());
+    }
+}
@@ -0,0 +1,476 @@
+use crate::gamepad::{Button, Mapping};
+use core::io::input::Macro;
+use core::io::joypad::JoypadKey;
+use core::Renderer;
+use minifb::{Key, KeyRepeat, Window};
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Hotkey {
+    Quit,
+    Pause,
+    FrameAdvance,
+    // Held
+    Rewind,
+    // Held, runs unthrottled
+    FastForward,
+    SlowMotion,
+    NormalSpeed,
+    DoubleSpeed,
+    QuadrupleSpeed,
+    Unthrottled,
+    Screenshot,
+    SaveState,
+    LoadState,
+    NextSlot,
+    PreviousSlot,
+    // Held, press and release A or B every few frames
+    TurboA,
+    TurboB,
+    SoftReset,
+    HardReset,
+    NextCheat,
+    ToggleCheat,
+}
+
+// Names used in the config file, with the default bindings
+const JOYPAD_KEYS: [(&str, JoypadKey, Key); 8] = [
+    ("up", JoypadKey::Up, Key::Up),
+    ("down", JoypadKey::Down, Key::Down),
+    ("left", JoypadKey::Left, Key::Left),
+    ("right", JoypadKey::Right, Key::Right),
+    ("a", JoypadKey::A, Key::Z),
+    ("b", JoypadKey::B, Key::X),
+    ("start", JoypadKey::Start, Key::Enter),
+    ("select", JoypadKey::Select, Key::Space),
+];
+
+const HOTKEYS: [(&str, Hotkey, Key); 21] = [
+    ("quit", Hotkey::Quit, Key::Escape),
+    ("pause", Hotkey::Pause, Key::P),
+    ("frame_advance", Hotkey::FrameAdvance, Key::N),
+    ("rewind", Hotkey::Rewind, Key::Backspace),
+    ("fast_forward", Hotkey::FastForward, Key::Tab),
+    ("slow_motion", Hotkey::SlowMotion, Key::Key1),
+    ("normal_speed", Hotkey::NormalSpeed, Key::Key2),
+    ("double_speed", Hotkey::DoubleSpeed, Key::Key3),
+    ("quadruple_speed", Hotkey::QuadrupleSpeed, Key::Key4),
+    ("unthrottled", Hotkey::Unthrottled, Key::Key5),
+    ("screenshot", Hotkey::Screenshot, Key::F12),
+    ("save_state", Hotkey::SaveState, Key::F5),
+    ("load_state", Hotkey::LoadState, Key::F7),
+    ("next_slot", Hotkey::NextSlot, Key::F6),
+    ("previous_slot", Hotkey::PreviousSlot, Key::F4),
+    ("turbo_a", Hotkey::TurboA, Key::A),
+    ("turbo_b", Hotkey::TurboB, Key::S),
+    ("soft_reset", Hotkey::SoftReset, Key::F2),
+    ("hard_reset", Hotkey::HardReset, Key::F3),
+    ("next_cheat", Hotkey::NextCheat, Key::F10),
+    ("toggle_cheat", Hotkey::ToggleCheat, Key::F11),
+];
+
+// Frames between each press and release of the turbo keys
+const TURBO_RATE: u32 = 2;
+
+const KEYS: [(&str, Key); 106] = [
+    ("Key0", Key::Key0), ("Key1", Key::Key1), ("Key2", Key::Key2), ("Key3", Key::Key3), ("Key4", Key::Key4),
+    ("Key5", Key::Key5), ("Key6", Key::Key6), ("Key7", Key::Key7), ("Key8", Key::Key8), ("Key9", Key::Key9),
+    ("A", Key::A), ("B", Key::B), ("C", Key::C), ("D", Key::D), ("E", Key::E), ("F", Key::F), ("G", Key::G),
+    ("H", Key::H), ("I", Key::I), ("J", Key::J), ("K", Key::K), ("L", Key::L), ("M", Key::M), ("N", Key::N),
+    ("O", Key::O), ("P", Key::P), ("Q", Key::Q), ("R", Key::R), ("S", Key::S), ("T", Key::T), ("U", Key::U),
+    ("V", Key::V), ("W", Key::W), ("X", Key::X), ("Y", Key::Y), ("Z", Key::Z),
+    ("F1", Key::F1), ("F2", Key::F2), ("F3", Key::F3), ("F4", Key::F4), ("F5", Key::F5), ("F6", Key::F6),
+    ("F7", Key::F7), ("F8", Key::F8), ("F9", Key::F9), ("F10", Key::F10), ("F11", Key::F11), ("F12", Key::F12),
+    ("F13", Key::F13), ("F14", Key::F14), ("F15", Key::F15),
+    ("Down", Key::Down), ("Left", Key::Left), ("Right", Key::Right), ("Up", Key::Up),
+    ("Apostrophe", Key::Apostrophe), ("Backquote", Key::Backquote), ("Backslash", Key::Backslash),
+    ("Comma", Key::Comma), ("Equal", Key::Equal), ("LeftBracket", Key::LeftBracket), ("Minus", Key::Minus),
+    ("Period", Key::Period), ("RightBracket", Key::RightBracket), ("Semicolon", Key::Semicolon),
+    ("Slash", Key::Slash), ("Backspace", Key::Backspace), ("Delete", Key::Delete), ("End", Key::End),
+    ("Enter", Key::Enter), ("Escape", Key::Escape), ("Home", Key::Home), ("Insert", Key::Insert),
+    ("Menu", Key::Menu), ("PageDown", Key::PageDown), ("PageUp", Key::PageUp), ("Pause", Key::Pause),
+    ("Space", Key::Space), ("Tab", Key::Tab), ("NumLock", Key::NumLock), ("CapsLock", Key::CapsLock),
+    ("ScrollLock", Key::ScrollLock), ("LeftShift", Key::LeftShift), ("RightShift", Key::RightShift),
+    ("LeftCtrl", Key::LeftCtrl), ("RightCtrl", Key::RightCtrl),
+    ("NumPad0", Key::NumPad0), ("NumPad1", Key::NumPad1), ("NumPad2", Key::NumPad2), ("NumPad3", Key::NumPad3),
+    ("NumPad4", Key::NumPad4), ("NumPad5", Key::NumPad5), ("NumPad6", Key::NumPad6), ("NumPad7", Key::NumPad7),
+    ("NumPad8", Key::NumPad8), ("NumPad9", Key::NumPad9), ("NumPadDot", Key::NumPadDot),
+    ("NumPadSlash", Key::NumPadSlash), ("NumPadAsterisk", Key::NumPadAsterisk),
+    ("NumPadMinus", Key::NumPadMinus), ("NumPadPlus", Key::NumPadPlus), ("NumPadEnter", Key::NumPadEnter),
+    ("LeftAlt", Key::LeftAlt), ("RightAlt", Key::RightAlt), ("LeftSuper", Key::LeftSuper),
+    ("RightSuper", Key::RightSuper),
+];
+
+fn key_from_name(name: &str) -> Option<Key> {
+    KEYS.iter()
+        .find(|(key_name, _)| key_name.eq_ignore_ascii_case(name))
+        .map(|(_, key)| *key)
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct GamepadSection {
+    threshold: Option<f32>,
+    // Joypad key names to button names
+    buttons: BTreeMap<String, String>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct MacroSection {
+    // Steps, as parsed by Macro::parse
+    keys: String,
+    hotkey: String,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct Section {
+    palette: Option<String>,
+    scale: Option<usize>,
+    renderer: Option<String>,
+    access_blocking: Option<bool>,
+    turbo_rate: Option<u32>,
+    // Action names to key names
+    keys: BTreeMap<String, String>,
+    hotkeys: BTreeMap<String, String>,
+    gamepad: GamepadSection,
+    macros: BTreeMap<String, MacroSection>,
+}
+
+/// Settings read from `config.toml`, in the `gamebrust` directory of the
+/// user's config dir. Tables under `[rom."<name>"]` override the settings
+/// for the ROM with that title or file name:
+///
+/// ```toml
+/// palette = "pocket"
+/// scale = 4
+/// renderer = "fifo"
+/// access_blocking = false
+/// turbo_rate = 2
+///
+/// [keys]
+/// a = "Z"
+/// b = "X"
+///
+/// [hotkeys]
+/// save_state = "F5"
+/// load_state = "F7"
+///
+/// [gamepad]
+/// threshold = 0.5
+///
+/// [gamepad.buttons]
+/// a = "east"
+/// b = "south"
+///
+/// [macros.menu]
+/// keys = "start:2 wait:30 down a"
+/// hotkey = "F9"
+///
+/// [rom."TETRIS"]
+/// palette = "green"
+///
+/// [rom."TETRIS".keys]
+/// a = "Space"
+/// ```
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    palette: Option<String>,
+    scale: Option<usize>,
+    renderer: Option<String>,
+    access_blocking: Option<bool>,
+    turbo_rate: Option<u32>,
+    keys: BTreeMap<String, String>,
+    hotkeys: BTreeMap<String, String>,
+    gamepad: GamepadSection,
+    macros: BTreeMap<String, MacroSection>,
+    rom: BTreeMap<String, Section>,
+}
+
+// Settings for a given ROM
+#[derive(Debug, PartialEq)]
+pub struct Profile {
+    pub palette: Option<String>,
+    pub scale: Option<usize>,
+    pub renderer: Option<Renderer>,
+    pub access_blocking: Option<bool>,
+    pub keys: Vec<(Key, JoypadKey)>,
+    pub hotkeys: Vec<(Key, Hotkey)>,
+    pub gamepad: Mapping,
+    pub turbo_rate: u32,
+    pub macros: Vec<(Key, Macro)>,
+}
+
+impl Profile {
+    pub fn pressed(&self, window: &Window, hotkey: Hotkey, repeat: KeyRepeat) -> bool {
+        self.hotkeys.iter().any(|(key, h)| *h == hotkey && window.is_key_pressed(*key, repeat))
+    }
+
+    pub fn down(&self, window: &Window, hotkey: Hotkey) -> bool {
+        self.hotkeys.iter().any(|(key, h)| *h == hotkey && window.is_key_down(*key))
+    }
+
+    // Joypad keys held, one bit per JoypadKey
+    pub fn held(&self, window: &Window) -> u8 {
+        self.keys.iter()
+            .filter(|(key, _)| window.is_key_down(*key))
+            .fold(0, |acc, (_, joypad_key)| acc | 1 << *joypad_key as u8)
+    }
+}
+
+impl Config {
+    pub fn default_path() -> Option<PathBuf> {
+        dirs::config_dir().map(|dir| dir.join("gamebrust").join("config.toml"))
+    }
+
+    // Reads the given file, or the default one when it exists
+    pub fn load(path: Option<&Path>) -> Result<Self, Box<dyn std::error::Error>> {
+        let path = match path {
+            Some(path) => path.to_path_buf(),
+            None => match Config::default_path() {
+                Some(path) if path.exists() => path,
+                _ => return Ok(Config::default()),
+            },
+        };
+
+        let text = fs::read_to_string(&path)
+            .map_err(|e| format!("Can't read the config '{}': {}", path.display(), e))?;
+
+        Config::parse(&text).map_err(|e| format!("Invalid config '{}': {}", path.display(), e).into())
+    }
+
+    pub fn parse(text: &str) -> Result<Self, Box<dyn std::error::Error>> {
+        let config: Config = toml::from_str(text)?;
+
+        // Bad names are reported up front rather than for the ROM that uses them
+        config.profile(&[])?;
+        for section in config.rom.values() {
+            Config::renderer(section.renderer.as_ref())?;
+            Config::bindings(&JOYPAD_KEYS, &section.keys, &BTreeMap::new())?;
+            Config::bindings(&HOTKEYS, &section.hotkeys, &BTreeMap::new())?;
+            Config::gamepad(&section.gamepad, &GamepadSection::default())?;
+            Config::macros(&section.macros, &BTreeMap::new())?;
+        }
+
+        Ok(config)
+    }
+
+    // Settings for a ROM known by any of the given names, like its title
+    // and file name
+    pub fn profile(&self, names: &[&str]) -> Result<Profile, Box<dyn std::error::Error>> {
+        let section = names.iter().find_map(|name| self.rom.get(*name));
+        let empty = Section::default();
+        let section = section.unwrap_or(&empty);
+
+        Ok(Profile {
+            palette: section.palette.clone().or_else(|| self.palette.clone()),
+            scale: section.scale.or(self.scale),
+            renderer: Config::renderer(section.renderer.as_ref().or(self.renderer.as_ref()))?,
+            access_blocking: section.access_blocking.or(self.access_blocking),
+            keys: Config::bindings(&JOYPAD_KEYS, &self.keys, &section.keys)?,
+            hotkeys: Config::bindings(&HOTKEYS, &self.hotkeys, &section.hotkeys)?,
+            gamepad: Config::gamepad(&self.gamepad, &section.gamepad)?,
+            turbo_rate: Config::turbo_rate(section.turbo_rate.or(self.turbo_rate))?,
+            macros: Config::macros(&self.macros, &section.macros)?,
+        })
+    }
+
+    fn renderer(name: Option<&String>) -> Result<Option<Renderer>, Box<dyn std::error::Error>> {
+        match name {
+            Some(name) => match Renderer::from_name(name) {
+                Some(renderer) => Ok(Some(renderer)),
+                None => Err(format!("Unknown renderer '{}'", name).into()),
+            },
+            None => Ok(None),
+        }
+    }
+
+    fn turbo_rate(rate: Option<u32>) -> Result<u32, Box<dyn std::error::Error>> {
+        match rate {
+            Some(0) => Err("The turbo rate must be at least 1 frame".into()),
+            Some(rate) => Ok(rate),
+            None => Ok(TURBO_RATE),
+        }
+    }
+
+    // Macros of the ROM replace the ones with the same name
+    fn macros(
+        config: &BTreeMap<String, MacroSection>,
+        overrides: &BTreeMap<String, MacroSection>,
+    ) -> Result<Vec<(Key, Macro)>, Box<dyn std::error::Error>> {
+        let mut macros = BTreeMap::new();
+        macros.extend(config.iter());
+        macros.extend(overrides.iter());
+
+        macros.into_iter()
+            .map(|(name, section)| {
+                let key = key_from_name(&section.hotkey)
+                    .ok_or_else(|| format!("Unknown key '{}' for the macro '{}'", section.hotkey, name))?;
+                Ok((key, Macro::parse(name, &section.keys)?))
+            })
+            .collect()
+    }
+
+    // Keys without a button in the config keep their default ones, unless
+    // the config gave those buttons to other keys. A button configured for
+    // two keys is an error.
+    fn gamepad(config: &GamepadSection, overrides: &GamepadSection) -> Result<Mapping, Box<dyn std::error::Error>> {
+        let defaults = Mapping::default();
+        let mut buttons = vec![];
+        let mut unbound = vec![];
+
+        for name in config.buttons.keys().chain(overrides.buttons.keys()) {
+            if !JOYPAD_KEYS.iter().any(|(key, _, _)| key == name) {
+                return Err(format!("Unknown gamepad action '{}'", name).into());
+            }
+        }
+
+        for (name, key, _) in JOYPAD_KEYS.iter() {
+            match overrides.buttons.get(*name).or_else(|| config.buttons.get(*name)) {
+                Some(button_name) => {
+                    let button = Button::from_name(button_name)
+                        .ok_or_else(|| format!("Unknown gamepad button '{}' for '{}'", button_name, name))?;
+
+                    if buttons.iter().any(|(b, _)| *b == button) {
+                        return Err(format!("The gamepad button '{}' is bound to more than one key", button_name).into());
+                    }
+                    buttons.push((button, *key));
+                }
+                None => unbound.push(*key),
+            }
+        }
+
+        let kept: Vec<_> = defaults.buttons.iter()
+            .filter(|(button, key)| unbound.contains(key) && !buttons.iter().any(|(b, _)| b == button))
+            .copied()
+            .collect();
+        buttons.extend(kept);
+
+        let threshold = overrides.threshold.or(config.threshold).unwrap_or(defaults.threshold);
+
+        if threshold <= 0.0 || threshold > 1.0 {
+            return Err("The gamepad threshold must be between 0 and 1".into());
+        }
+
+        Ok(Mapping { buttons, threshold })
+    }
+
+    // Binds every action to its key in the override, the config or the
+    // defaults, in this order
+    fn bindings<T: Copy>(
+        actions: &[(&str, T, Key)],
+        config: &BTreeMap<String, String>,
+        overrides: &BTreeMap<String, String>,
+    ) -> Result<Vec<(Key, T)>, Box<dyn std::error::Error>> {
+        for name in config.keys().chain(overrides.keys()) {
+            if !actions.iter().any(|(action, _, _)| action == name) {
+                return Err(format!("Unknown action '{}'", name).into());
+            }
+        }
+
+        actions.iter()
+            .map(|(name, action, default)| {
+                match overrides.get(*name).or_else(|| config.get(*name)) {
+                    Some(key) => key_from_name(key)
+                        .map(|key| (key, *action))
+                        .ok_or_else(|| format!("Unknown key '{}' for '{}'", key, name).into()),
+                    None => Ok((*default, *action)),
+                }
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn key_for<T: PartialEq>(bindings: &[(Key, T)], action: T) -> Key {
+        bindings.iter().find(|(_, a)| *a == action).unwrap().0
+    }
+
+    #[test]
+    fn rom_overrides() {
+        let config = Config::parse(r#"
+            palette = "pocket"
+            scale = 4
+
+            [keys]
+            a = "s"
+            b = "A"
+
+            [hotkeys]
+            save_state = "F1"
+
+            [macros.menu]
+            keys = "start wait:10 a"
+            hotkey = "F9"
+
+            [rom."TETRIS"]
+            palette = "green"
+            renderer = "fifo"
+            access_blocking = false
+            turbo_rate = 4
+
+            [rom."TETRIS".macros.menu]
+            keys = "select"
+            hotkey = "F10"
+
+            [rom."TETRIS".keys]
+            a = "Space"
+
+            [rom."TETRIS".gamepad]
+            threshold = 0.8
+
+            [rom."TETRIS".gamepad.buttons]
+            a = "south"
+        "#).unwrap();
+
+        let profile = config.profile(&["ZELDA", "zelda"]).unwrap();
+        assert_eq!(profile.palette, Some("pocket".to_string()));
+        assert_eq!(profile.scale, Some(4));
+        assert_eq!(profile.renderer, None);
+        assert_eq!(profile.access_blocking, None);
+        assert_eq!(key_for(&profile.keys, JoypadKey::A), Key::S);
+        assert_eq!(key_for(&profile.keys, JoypadKey::Start), Key::Enter);
+        assert_eq!(key_for(&profile.hotkeys, Hotkey::SaveState), Key::F1);
+        assert_eq!(profile.turbo_rate, 2);
+        assert_eq!(profile.macros, vec![(Key::F9, Macro::parse("menu", "start wait:10 a").unwrap())]);
+
+        let profile = config.profile(&["TETRIS", "tetris"]).unwrap();
+        assert_eq!(profile.palette, Some("green".to_string()));
+        assert_eq!(profile.scale, Some(4));
+        assert_eq!(key_for(&profile.keys, JoypadKey::A), Key::Space);
+        assert_eq!(key_for(&profile.keys, JoypadKey::B), Key::A);
+        assert_eq!(profile.gamepad.threshold, 0.8);
+        assert!(profile.gamepad.buttons.contains(&(Button::South, JoypadKey::A)));
+        assert!(!profile.gamepad.buttons.contains(&(Button::East, JoypadKey::A)));
+        assert!(!profile.gamepad.buttons.contains(&(Button::South, JoypadKey::B)));
+        assert!(profile.gamepad.buttons.contains(&(Button::Start, JoypadKey::Start)));
+        assert_eq!(profile.turbo_rate, 4);
+        assert_eq!(profile.renderer, Some(Renderer::Fifo));
+        assert_eq!(profile.access_blocking, Some(false));
+        assert_eq!(profile.macros, vec![(Key::F10, Macro::parse("menu", "select").unwrap())]);
+    }
+
+    #[test]
+    fn invalid_configs() {
+        assert!(Config::parse("[keys]\nturbo = \"A\"").is_err());
+        assert!(Config::parse("[keys]\na = \"Joystick\"").is_err());
+        assert!(Config::parse("[rom.\"TETRIS\".hotkeys]\nquit = \"Nope\"").is_err());
+        assert!(Config::parse("volume = 3").is_err());
+        assert!(Config::parse("[gamepad]\nthreshold = 1.5").is_err());
+        assert!(Config::parse("[gamepad.buttons]\na = \"trigger\"").is_err());
+        assert!(Config::parse("[gamepad.buttons]\na = \"north\"\nb = \"north\"").is_err());
+        assert!(Config::parse("turbo_rate = 0").is_err());
+        assert!(Config::parse("[rom.\"TETRIS\"]\nrenderer = \"pixels\"").is_err());
+        assert!(Config::parse("[macros.menu]\nkeys = \"start:x\"\nhotkey = \"F9\"").is_err());
+        assert!(Config::parse("[macros.menu]\nkeys = \"start\"").is_err());
+        assert_eq!(Config::parse("").unwrap().profile(&[]).unwrap().keys.len(), 8);
+    }
+}
@@ -0,0 +1,325 @@
+use core::io::joypad::JoypadKey;
+use std::sync::mpsc::{self, Receiver, Sender};
+
+// Buttons named after their position, as on most modern pads
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Button {
+    South,
+    East,
+    North,
+    West,
+    LeftShoulder,
+    RightShoulder,
+    Start,
+    Select,
+    DPadUp,
+    DPadDown,
+    DPadLeft,
+    DPadRight,
+}
+
+impl Button {
+    pub fn from_name(name: &str) -> Option<Self> {
+        BUTTONS.iter()
+            .find(|(button_name, _)| button_name.eq_ignore_ascii_case(name))
+            .map(|(_, button)| *button)
+    }
+}
+
+const BUTTONS: [(&str, Button); 12] = [
+    ("south", Button::South),
+    ("east", Button::East),
+    ("north", Button::North),
+    ("west", Button::West),
+    ("left_shoulder", Button::LeftShoulder),
+    ("right_shoulder", Button::RightShoulder),
+    ("start", Button::Start),
+    ("select", Button::Select),
+    ("dpad_up", Button::DPadUp),
+    ("dpad_down", Button::DPadDown),
+    ("dpad_left", Button::DPadLeft),
+    ("dpad_right", Button::DPadRight),
+];
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Axis {
+    LeftStickX,
+    LeftStickY,
+    // Digital pads reported as a hat
+    HatX,
+    HatY,
+}
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Event {
+    Button(Button, bool),
+    // From -1.0 (left or up) to 1.0 (right or down)
+    Axis(Axis, f32),
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct Mapping {
+    pub buttons: Vec<(Button, JoypadKey)>,
+    // How far, from 0 to 1, the stick must be pushed to press a direction
+    pub threshold: f32,
+}
+
+impl Default for Mapping {
+    // A and B where they sit on a Game Boy: B on the left, A on the right
+    fn default() -> Self {
+        Self {
+            buttons: vec![
+                (Button::East, JoypadKey::A),
+                (Button::South, JoypadKey::B),
+                (Button::Start, JoypadKey::Start),
+                (Button::Select, JoypadKey::Select),
+                (Button::DPadUp, JoypadKey::Up),
+                (Button::DPadDown, JoypadKey::Down),
+                (Button::DPadLeft, JoypadKey::Left),
+                (Button::DPadRight, JoypadKey::Right),
+            ],
+            threshold: 0.5,
+        }
+    }
+}
+
+fn bit(key: JoypadKey) -> u8 {
+    1 << key as u8
+}
+
+// Turns gamepad events into joypad key presses and releases
+pub struct Gamepad {
+    mapping: Mapping,
+    buttons: u8,
+    stick: (f32, f32),
+    hat: (f32, f32),
+    held: u8,
+}
+
+impl Gamepad {
+    pub fn new(mapping: Mapping) -> Self {
+        Self {
+            mapping,
+            buttons: 0,
+            stick: (0.0, 0.0),
+            hat: (0.0, 0.0),
+            held: 0,
+        }
+    }
+
+    // Keys held, one bit per JoypadKey
+    pub fn held(&self) -> u8 {
+        self.held
+    }
+
+    // Returns the keys pressed (true) and released (false) by the event
+    pub fn handle(&mut self, event: Event) -> Vec<(JoypadKey, bool)> {
+        match event {
+            Event::Button(button, pressed) => {
+                for (_, key) in self.mapping.buttons.iter().filter(|(b, _)| *b == button) {
+                    if pressed {
+                        self.buttons |= bit(*key);
+                    } else {
+                        self.buttons &= !bit(*key);
+                    }
+                }
+            }
+            Event::Axis(Axis::LeftStickX, v) => self.stick.0 = v,
+            Event::Axis(Axis::LeftStickY, v) => self.stick.1 = v,
+            Event::Axis(Axis::HatX, v) => self.hat.0 = v,
+            Event::Axis(Axis::HatY, v) => self.hat.1 = v,
+        }
+
+        let held = self.buttons | directions(self.stick, self.mapping.threshold) | directions(self.hat, 0.5);
+        let changed = held ^ self.held;
+        self.held = held;
+
+        JoypadKey::ALL.iter()
+            .filter(|key| changed & bit(**key) != 0)
+            .map(|key| (*key, held & bit(*key) != 0))
+            .collect()
+    }
+}
+
+// Directions held by a stick or a hat
+fn directions((x, y): (f32, f32), threshold: f32) -> u8 {
+    let mut keys = 0;
+    if x <= -threshold { keys |= bit(JoypadKey::Left); }
+    if x >= threshold { keys |= bit(JoypadKey::Right); }
+    if y <= -threshold { keys |= bit(JoypadKey::Up); }
+    if y >= threshold { keys |= bit(JoypadKey::Down); }
+    keys
+}
+
+/// Gamepads connected to the first controller. Devices are read on their
+/// own threads and their events merged, so any of them can be used.
+pub struct Gamepads {
+    events_tx: Sender<Event>,
+    events_rx: Receiver<Event>,
+    gamepad: Gamepad,
+}
+
+impl Gamepads {
+    pub fn new(mapping: Mapping) -> Self {
+        let (events_tx, events_rx) = mpsc::channel();
+
+        Self {
+            events_tx,
+            events_rx,
+            gamepad: Gamepad::new(mapping),
+        }
+    }
+
+    // A device driven by sending it events
+    #[cfg(test)]
+    pub fn connect_virtual(&self) -> Sender<Event> {
+        self.events_tx.clone()
+    }
+
+    // Opens the gamepads under /dev/input. Returns their names.
+    #[cfg(target_os = "linux")]
+    pub fn connect_evdev(&self) -> Vec<String> {
+        evdev::enumerate()
+            .filter(|(_, device)| linux::is_gamepad(device))
+            .map(|(_, device)| {
+                let name = device.name().unwrap_or("Gamepad").to_string();
+                linux::spawn_reader(device, self.events_tx.clone());
+                name
+            })
+            .collect()
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    pub fn connect_evdev(&self) -> Vec<String> {
+        vec![]
+    }
+
+    // Handles the events received since the last call, returning the keys
+    // pressed and released
+    pub fn poll(&mut self) -> Vec<(JoypadKey, bool)> {
+        let events: Vec<_> = self.events_rx.try_iter().collect();
+        events.into_iter().flat_map(|event| self.gamepad.handle(event)).collect()
+    }
+
+    pub fn held(&self) -> u8 {
+        self.gamepad.held()
+    }
+}
+
+#[cfg(target_os = "linux")]
+mod linux {
+    use super::{Axis, Button, Event};
+    use evdev::{AbsoluteAxisType, Device, InputEventKind, Key};
+    use std::sync::mpsc::Sender;
+    use std::thread;
+
+    const BUTTONS: [(Key, Button); 12] = [
+        (Key::BTN_SOUTH, Button::South),
+        (Key::BTN_EAST, Button::East),
+        (Key::BTN_NORTH, Button::North),
+        (Key::BTN_WEST, Button::West),
+        (Key::BTN_TL, Button::LeftShoulder),
+        (Key::BTN_TR, Button::RightShoulder),
+        (Key::BTN_START, Button::Start),
+        (Key::BTN_SELECT, Button::Select),
+        (Key::BTN_DPAD_UP, Button::DPadUp),
+        (Key::BTN_DPAD_DOWN, Button::DPadDown),
+        (Key::BTN_DPAD_LEFT, Button::DPadLeft),
+        (Key::BTN_DPAD_RIGHT, Button::DPadRight),
+    ];
+
+    const AXES: [(AbsoluteAxisType, Axis); 4] = [
+        (AbsoluteAxisType::ABS_X, Axis::LeftStickX),
+        (AbsoluteAxisType::ABS_Y, Axis::LeftStickY),
+        (AbsoluteAxisType::ABS_HAT0X, Axis::HatX),
+        (AbsoluteAxisType::ABS_HAT0Y, Axis::HatY),
+    ];
+
+    pub fn is_gamepad(device: &Device) -> bool {
+        device.supported_keys().is_some_and(|keys| keys.contains(Key::BTN_SOUTH))
+    }
+
+    // Reads the device until it's unplugged or nobody listens anymore
+    pub fn spawn_reader(mut device: Device, events_tx: Sender<Event>) {
+        thread::spawn(move || {
+            // Axis ranges, to bring positions to -1..1
+            let ranges = match device.get_abs_state() {
+                Ok(state) => AXES.iter()
+                    .map(|(code, _)| {
+                        let info = state[code.0 as usize];
+                        (info.minimum, info.maximum)
+                    })
+                    .collect(),
+                Err(_) => vec![(-1, 1); AXES.len()],
+            };
+
+            loop {
+                let events = match device.fetch_events() {
+                    Ok(events) => events,
+                    Err(_) => return,
+                };
+
+                for event in events {
+                    let event = match event.kind() {
+                        InputEventKind::Key(key) => BUTTONS.iter()
+                            .find(|(k, _)| *k == key)
+                            .map(|(_, button)| Event::Button(*button, event.value() != 0)),
+                        InputEventKind::AbsAxis(code) => AXES.iter()
+                            .position(|(c, _)| *c == code)
+                            .map(|i| {
+                                let (min, max) = ranges[i];
+                                let span = (max - min).max(1) as f32;
+                                let v = (event.value() - min) as f32 / span * 2.0 - 1.0;
+                                Event::Axis(AXES[i].1, v)
+                            }),
+                        _ => None,
+                    };
+
+                    if let Some(event) = event {
+                        if events_tx.send(event).is_err() {
+                            return;
+                        }
+                    }
+                }
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn buttons_press_and_release_keys() {
+        let mut gamepads = Gamepads::new(Mapping::default());
+        let device = gamepads.connect_virtual();
+
+        device.send(Event::Button(Button::East, true)).unwrap();
+        device.send(Event::Button(Button::Start, true)).unwrap();
+        assert_eq!(gamepads.poll(), vec![(JoypadKey::A, true), (JoypadKey::Start, true)]);
+        assert_eq!(gamepads.held(), bit(JoypadKey::A) | bit(JoypadKey::Start));
+
+        // Unmapped buttons do nothing
+        device.send(Event::Button(Button::North, true)).unwrap();
+        device.send(Event::Button(Button::East, false)).unwrap();
+        assert_eq!(gamepads.poll(), vec![(JoypadKey::A, false)]);
+        assert_eq!(gamepads.poll(), vec![]);
+    }
+
+    #[test]
+    fn stick_directions_past_the_threshold() {
+        let mut gamepad = Gamepad::new(Mapping { threshold: 0.6, ..Mapping::default() });
+
+        assert_eq!(gamepad.handle(Event::Axis(Axis::LeftStickX, -0.5)), vec![]);
+        assert_eq!(gamepad.handle(Event::Axis(Axis::LeftStickX, -0.7)), vec![(JoypadKey::Left, true)]);
+        assert_eq!(gamepad.handle(Event::Axis(Axis::LeftStickY, 1.0)), vec![(JoypadKey::Down, true)]);
+
+        // The hat and the D-pad buttons hold the same keys as the stick
+        assert_eq!(gamepad.handle(Event::Axis(Axis::HatX, -1.0)), vec![]);
+        assert_eq!(gamepad.handle(Event::Axis(Axis::LeftStickX, 0.0)), vec![]);
+        assert_eq!(gamepad.handle(Event::Axis(Axis::HatX, 0.0)), vec![(JoypadKey::Left, false)]);
+        assert_eq!(gamepad.handle(Event::Button(Button::DPadDown, true)), vec![]);
+        assert_eq!(gamepad.held(), bit(JoypadKey::Down));
+    }
+}
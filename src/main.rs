@@ -1,19 +1,78 @@
 extern crate minifb;
 
+mod cli;
+mod config;
+mod gamepad;
+mod screenshot;
+mod timing;
+
 use std::thread;
 use std::sync::mpsc;
 use std::sync::mpsc::Sender;
 use std::rc::Rc;
 use std::cell::RefCell;
-use minifb::{Key, ScaleMode, Window, WindowOptions};
-use core::cartridge::Cartridge;
-use core::io::joypad::JoypadKey;
+use minifb::{KeyRepeat, Scale, ScaleMode, Window, WindowOptions};
+use cli::{Options, USAGE};
+use config::{Config, Hotkey, Profile};
+use gamepad::Gamepads;
+use timing::{Pacer, SyncMode};
+use core::cartridge::{patch, Cartridge};
 use core::Display;
-use core::System;
-use std::time::Instant;
-use std::path::Path;
+use core::io::input::{InputLayer, Macro};
+use core::io::joypad::JoypadKey;
+use core::{Renderer, ResetKind, Rewind, System, FRAME_TICKS};
+use core::BootRom;
+use core::cheats::Cheats;
+use core::movie::{Movie, Start};
+use core::{ColorScheme, PalettePreset, TitleTable, SGB_SCREEN_W, SGB_SCREEN_H};
+use std::io::Write;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+const STATE_SLOTS: usize = 10;
+
+// Sent by the UI thread to the emulation thread
+enum Input {
+    // Keys held, one bit per JoypadKey
+    Keys(u8),
+    // Turbo keys held, one bit per JoypadKey
+    Turbo(u8),
+    Macro(Macro),
+    // Held while the rewind key is down
+    Rewind(bool),
+    Speed(Speed),
+    // Toggles pause
+    Pause,
+    // Runs a single frame and pauses
+    Advance,
+    SaveState(PathBuf),
+    LoadState(PathBuf),
+    Reset(ResetKind),
+    Cheats(Cheats),
+    Quit,
+}
 
-const BATCH_TICKS: u32 = (16 as f64 * (4_194_304 as f64 / 1000_f64)) as u32;
+#[derive(Debug, PartialEq, Clone, Copy)]
+enum Speed {
+    Half,
+    Normal,
+    Double,
+    Quadruple,
+    Unthrottled,
+}
+
+impl Speed {
+    // None to run as fast as possible
+    fn multiplier(self) -> Option<f64> {
+        match self {
+            Speed::Half => Some(0.5),
+            Speed::Normal => Some(1.0),
+            Speed::Double => Some(2.0),
+            Speed::Quadruple => Some(4.0),
+            Speed::Unthrottled => None,
+        }
+    }
+}
 
 struct UI {
     frame_tx: Sender<Vec<u32>>
@@ -33,109 +92,598 @@ impl Display for UI {
     }
 }
 
-fn main() -> Result<(), Box<dyn std::error::Error>> {
-    let argv: Vec<_> = std::env::args().collect();
+// Keeps the last frame, when running without a window
+struct Capture {
+    frame: Rc<RefCell<Vec<u32>>>,
+}
+
+impl Display for Capture {
+    fn update(&mut self, buffer: &Vec<u32>) {
+        self.frame.borrow_mut().clone_from(buffer);
+    }
+}
+
+// Accepts either a preset name or the path of a palette file
+fn load_palette(arg: &str) -> Result<ColorScheme, Box<dyn std::error::Error>> {
+    match PalettePreset::from_name(arg) {
+        Some(preset) => Ok(ColorScheme::from_preset(preset)),
+        None => ColorScheme::from_path(Path::new(arg)),
+    }
+}
+
+// Everything read from the files given on the command line, loaded before
+// the emulation starts so that errors can be reported right away
+struct Setup {
+    cartridge: Cartridge,
+    bootrom: Option<BootRom>,
+    colors: ColorScheme,
+    renderer: Renderer,
+    access_blocking: bool,
+    titles: TitleTable,
+    cheats: Option<Cheats>,
+    // Where cheats turned on and off are saved
+    cheats_path: PathBuf,
+    play: Option<Movie>,
+    // Where the run starts, when no movie is played
+    start: Start,
+}
+
+impl Setup {
+    fn load(options: &Options) -> Result<Self, Box<dyn std::error::Error>> {
+        let patch = options.patch.clone().or_else(|| patch::find_patch(&options.rom));
+
+        let cartridge = Cartridge::open(&options.rom, options.entry.as_deref(), patch.as_deref())
+            .map_err(|e| format!("Can't load the ROM '{}': {}", options.rom.display(), e))?;
+
+        let bootrom = match &options.bootrom {
+            Some(path) => Some(BootRom::from_path(path)?),
+            None => None,
+        };
+
+        let colors = match &options.palette {
+            Some(palette) => load_palette(palette)?,
+            None => ColorScheme::default(),
+        };
+
+        let titles = match &options.title_palettes {
+            Some(path) => TitleTable::from_path(path)?,
+            None => TitleTable::default(),
+        };
+
+        // Without --cheats, the cheats of the game are kept in the save
+        // directory, named after its title
+        let cheats_path = match &options.cheats {
+            Some(path) => path.clone(),
+            None => Cheats::path_for(&options.save_dir(), cartridge.get_header().title()),
+        };
+
+        let cheats = if options.cheats.is_some() || cheats_path.exists() {
+            let cheats = Cheats::from_path(&cheats_path)
+                .map_err(|e| format!("Can't load the cheats '{}': {}", cheats_path.display(), e))?;
+            Some(cheats)
+        } else {
+            None
+        };
+
+        let play = match &options.play {
+            Some(path) => {
+                let movie = Movie::from_path(path)?;
+                movie.check(&cartridge.get_header(), options.model, bootrom.is_some())
+                    .map_err(|e| format!("Can't play the movie: {}", e))?;
+                Some(movie)
+            }
+            None => None,
+        };
+
+        let start = match &options.state {
+            Some(path) => Start::State(
+                fs::read(path).map_err(|e| format!("Can't load the state '{}': {}", path.display(), e))?,
+            ),
+            None => Start::PowerOn,
+        };
+
+        Ok(Self {
+            cartridge,
+            bootrom,
+            colors,
+            renderer: options.renderer.unwrap_or(Renderer::Scanline),
+            access_blocking: options.access_blocking,
+            titles,
+            cheats,
+            cheats_path,
+            play,
+            start,
+        })
+    }
+
+    // The settings of the config apply unless they were given on the
+    // command line
+    fn configure(&mut self, options: &Options, profile: &Profile) -> Result<(), Box<dyn std::error::Error>> {
+        if let (None, Some(palette)) = (&options.palette, &profile.palette) {
+            self.colors = load_palette(palette)?;
+        }
+
+        if let (None, Some(renderer)) = (options.renderer, profile.renderer) {
+            self.renderer = renderer;
+        }
+
+        if let (true, Some(enabled)) = (options.access_blocking, profile.access_blocking) {
+            self.access_blocking = enabled;
+        }
+
+        Ok(())
+    }
+
+    // Returns the system along with the movie to play, if any, both at the
+    // start of the run
+    fn system(
+        self,
+        options: &Options,
+        display: Box<dyn Display>,
+    ) -> Result<(System, Option<Movie>), Box<dyn std::error::Error>> {
+        let mut system = System::new(self.cartridge, display, options.model, self.bootrom);
+        system.set_colors(self.colors);
+        system.set_renderer(self.renderer);
+        system.set_access_blocking(self.access_blocking);
+
+        // Test ROMs print their results through the serial port
+        system.set_serial_hook(Some(Box::new(|byte| print!("{}", byte as char))));
+
+        if options.sgb() {
+            system.set_sgb(true);
+        }
+
+        if let Some(cheats) = &self.cheats {
+            system.set_cheats(cheats);
+        }
+
+        if let Some(combo) = options.colorize {
+            system.colorize(&self.titles, combo);
+        }
+
+        let start = match &self.play {
+            Some(movie) => &movie.start,
+            None => &self.start,
+        };
+
+        if let Start::State(state) = start {
+            system.load_state(state).map_err(|e| format!("Can't load the state: {}", e))?;
+        }
+
+        Ok((system, self.play))
+    }
+}
+
+// Settings of the config file for the ROM, found by title or file name
+fn load_profile(options: &Options, setup: &Setup) -> Result<Profile, Box<dyn std::error::Error>> {
+    let config = Config::load(options.config.as_deref())?;
+    let header = setup.cartridge.get_header();
+    let file_name = options.rom.file_stem().map(|name| name.to_string_lossy()).unwrap_or_default();
+
+    config.profile(&[header.title(), &file_name])
+}
+
+fn screen_size(options: &Options) -> (usize, usize) {
+    if options.sgb() { (SGB_SCREEN_W, SGB_SCREEN_H) } else { (160, 144) }
+}
 
-    if argv.len() < 2 {
-        println!("Usage: {} <rom-file>", argv[0]);
-        return Ok(());
+fn main() {
+    let options = match Options::parse(std::env::args().skip(1)) {
+        Ok(Some(options)) => options,
+        Ok(None) => {
+            println!("{}", USAGE);
+            return;
+        }
+        Err(e) => {
+            eprintln!("{}\nTry 'gamebrust --help' for more information.", e);
+            std::process::exit(2);
+        }
+    };
+
+    let result = if options.headless { run_headless(options) } else { run(options) };
+
+    if let Err(e) = result {
+        eprintln!("{}", e);
+        std::process::exit(1);
     }
+}
+
+// Runs the given frames as fast as possible, then saves the last one
+fn run_headless(options: Options) -> Result<(), Box<dyn std::error::Error>> {
+    let frame = Rc::new(RefCell::new(vec![]));
+    let display = Capture { frame: frame.clone() };
+    let mut setup = Setup::load(&options)?;
+    setup.configure(&options, &load_profile(&options, &setup)?)?;
 
-    let rompath = String::from(&argv[1]);
+    let (mut system, play) = setup.system(&options, Box::new(display))?;
+
+    for n in 0..options.frames.unwrap_or(0) as usize {
+        if let Some(keys) = play.as_ref().and_then(|movie| movie.frame(n)) {
+            system.set_keys(keys);
+        }
+        system.run_frame();
+    }
+
+    if let Some(path) = &options.screenshot {
+        let (width, height) = screen_size(&options);
+        screenshot::save(path, &frame.borrow(), width, height)
+            .map_err(|e| format!("Can't save the screenshot '{}': {}", path.display(), e))?;
+    }
+
+    Ok(())
+}
+
+fn print_cheat(cheats: &Cheats, index: usize) {
+    let cheat = &cheats.list()[index];
+    let state = if cheat.enabled { "on" } else { "off" };
+    println!("Cheat {}: {} {} ({})", index + 1, cheat.code, cheat.name, state);
+}
+
+fn run(options: Options) -> Result<(), Box<dyn std::error::Error>> {
+    let mut setup = Setup::load(&options)?;
+    let profile = load_profile(&options, &setup)?;
+    setup.configure(&options, &profile)?;
+
+    // Nothing plays sound yet, so there is no audio queue to sync on
+    let sync = match options.sync {
+        SyncMode::Audio => {
+            eprintln!("No audio output, audio sync falls back to video sync");
+            SyncMode::Video
+        }
+        sync => sync,
+    };
 
     let (frame_tx, frame_rx) = mpsc::channel();
     let (input_tx, input_rx) = mpsc::channel();
 
-    let mut window = Window::new(
-        "GameBRust",
-        160,
-        144,
-        WindowOptions {
-            resize: true,
-            scale_mode: ScaleMode::AspectRatioStretch,
-            ..WindowOptions::default()
-        },
-        )
-        .unwrap();
+    let (width, height) = screen_size(&options);
 
-    let cpu_thread = thread::spawn(move || {
-        let rompath = Path::new(&rompath);
-        let cartridge =
-            match Cartridge::from_path(rompath) {
-                Ok(cartridge) => cartridge,
-                _ => panic!("Error!"),
-            };
+    let window_options =
+        if options.fullscreen {
+            WindowOptions {
+                borderless: true,
+                title: false,
+                topmost: true,
+                scale: Scale::FitScreen,
+                scale_mode: ScaleMode::AspectRatioStretch,
+                ..WindowOptions::default()
+            }
+        } else {
+            WindowOptions {
+                resize: true,
+                scale_mode: ScaleMode::AspectRatioStretch,
+                ..WindowOptions::default()
+            }
+        };
+
+    let scale = options.scale.or(profile.scale).unwrap_or(3).max(1);
+    let (window_width, window_height) =
+        if options.fullscreen { (width, height) } else { (width * scale, height * scale) };
+
+    let mut window = Window::new("GameBRust", window_width, window_height, window_options)
+        .map_err(|e| format!("Can't open the window: {}", e))?;
+
+    if options.fullscreen {
+        window.set_position(0, 0);
+    }
 
+    let mut gamepads = Gamepads::new(profile.gamepad.clone());
+
+    for name in gamepads.connect_evdev() {
+        println!("Gamepad: {}", name);
+    }
+
+    let save_dir = options.save_dir();
+    let rom_name = options.rom.file_stem().map_or("screenshot".into(), |name| name.to_string_lossy());
+    let rom_name = rom_name.to_string();
+    let turbo_rate = profile.turbo_rate;
+
+    let start = setup.start.clone();
+    let mut cheats = setup.cheats.clone();
+    let cheats_path = setup.cheats_path.clone();
+    let movie = options.record.is_some() || options.play.is_some();
+    let (ready_tx, ready_rx) = mpsc::channel();
+
+    let cpu_thread = thread::spawn(move || {
         let display = UI::new(frame_tx);
-        let mut system = System::new(cartridge, Box::new(display), false);
 
-        let mut last_step = Instant::now();
+        // The display isn't Send, so the system is built here and errors
+        // are reported back to the main thread
+        let (mut system, play) = match setup.system(&options, Box::new(display)) {
+            Ok(system) => {
+                ready_tx.send(Ok(())).unwrap();
+                system
+            }
+            Err(e) => {
+                ready_tx.send(Err(e.to_string())).unwrap();
+                return;
+            }
+        };
+        let (model, record) = (options.model, options.record);
+        let bootrom_used = options.bootrom.is_some();
+        let mut layer = InputLayer::new(turbo_rate);
+
+        let mut recording = record.as_ref().map(|_| Movie::new(&system.get_header(), model, bootrom_used, start));
+        let mut frame = 0;
+        let mut keys = 0;
 
-        let mut ticks = 0;
+        // A state every 2 frames, 20 seconds back. Movies can't be rewound.
+        let mut rewind = Rewind::new(2, 600);
+        let mut rewinding = false;
+        let rewind_enabled = recording.is_none() && play.is_none();
 
+        let mut pacer = Pacer::new(sync);
+        let mut paused = false;
+
+        // Input only changes between frames, so runs can be replayed exactly
         loop {
-            last_step = Instant::now();
+            let mut inputs: Vec<_> = input_rx.try_iter().collect();
+
+            // Nothing runs while paused, so wait for input instead of spinning
+            if paused && inputs.is_empty() {
+                match input_rx.recv() {
+                    Ok(input) => inputs.push(input),
+                    Err(_) => return,
+                }
+            }
 
-            while ticks < BATCH_TICKS {
-                ticks += system.step();
+            let mut advance = false;
+
+            for input in inputs {
+                match input {
+                    Input::Keys(held) => keys = held,
+                    Input::Turbo(held) => layer.set_turbo(held),
+                    Input::Macro(m) => layer.play(m),
+                    Input::Rewind(held) => rewinding = held && rewind_enabled,
+                    Input::Speed(speed) => pacer.set_speed(speed.multiplier()),
+                    Input::Pause => {
+                        paused = !paused;
+                        pacer.reset();
+                    }
+                    Input::Advance => {
+                        paused = true;
+                        advance = true;
+                    }
+                    Input::SaveState(path) => {
+                        match fs::write(&path, system.save_state()) {
+                            Ok(()) => println!("Saved {}", path.display()),
+                            Err(e) => eprintln!("Can't save the state '{}': {}", path.display(), e),
+                        }
+                    }
+                    // A loaded state or a reset would break the movie
+                    Input::LoadState(_) if !rewind_enabled => eprintln!("States can't be loaded during a movie"),
+                    Input::LoadState(path) => {
+                        let result = fs::read(&path)
+                            .map_err(|e| e.into())
+                            .and_then(|state| system.load_state(&state));
+
+                        match result {
+                            Ok(()) => println!("Loaded {}", path.display()),
+                            Err(e) => eprintln!("Can't load the state '{}': {}", path.display(), e),
+                        }
+                    }
+                    Input::Reset(_) if !rewind_enabled => eprintln!("The system can't be reset during a movie"),
+                    Input::Reset(kind) => {
+                        system.reset(kind);
+                        println!("{:?} reset", kind);
+                    }
+                    Input::Cheats(cheats) => system.set_cheats(&cheats),
+                    Input::Quit => {
+                        if let (Some(movie), Some(path)) = (&recording, &record) {
+                            if let Err(e) = movie.save(path) {
+                                eprintln!("Can't save the movie: {}", e);
+                            }
+                        }
+                        return;
+                    }
+                }
             }
 
-            ticks -= BATCH_TICKS;
+            if paused && !advance {
+                continue;
+            }
 
-            while (last_step.elapsed().as_millis() as u32) < 16 {
-                let joypad = system.get_joypad_adapter();
+            // Loads one older state per frame instead of running, until the
+            // buffer runs out
+            let rewound = rewinding && rewind.rewind(&mut system);
+            let mut ticks = FRAME_TICKS;
 
-                match input_rx.try_recv() {
-                    Ok((key, true)) => { joypad.pressed(key); }
-                    Ok((key, false)) => { joypad.released(key); }
-                    _ => { } 
+            if !rewound {
+                // Live input takes over at the end of the movie. Turbo and
+                // macros go on top of it and are recorded as pressed.
+                let frame_keys = match &play {
+                    Some(movie) if frame < movie.len() => movie.frame(frame).unwrap(),
+                    Some(movie) => {
+                        if frame == movie.len() {
+                            println!("Movie finished after {} frames", frame);
+                        }
+                        layer.frame(keys)
+                    }
+                    None => layer.frame(keys),
                 };
+
+                system.set_keys(frame_keys);
+
+                if let Some(movie) = &mut recording {
+                    movie.push(frame_keys);
+                }
+
+                ticks = system.run_frame();
+                frame += 1;
+
+                if rewind_enabled {
+                    rewind.frame(&system);
+                }
+            }
+
+            if paused {
+                pacer.reset();
+            } else {
+                pacer.wait(ticks);
+            }
+
+            if let Some(stats) = pacer.take_report() {
+                print!("{:.1} FPS, {:.0}% speed   \r", stats.fps, stats.speed);
+                let _ = std::io::stdout().flush();
             }
         }
     });
 
-    let keys = vec![
-        (Key::Right, JoypadKey::Right),
-        (Key::Up, JoypadKey::Up),
-        (Key::Left, JoypadKey::Left),
-        (Key::Down, JoypadKey::Down),
-        (Key::Z, JoypadKey::A),
-        (Key::X, JoypadKey::B),
-        (Key::Space, JoypadKey::Select),
-        (Key::Enter, JoypadKey::Start),
+    if let Ok(Err(e)) = ready_rx.recv() {
+        cpu_thread.join().unwrap();
+        return Err(e.into());
+    }
+
+    let speeds = [
+        (Hotkey::SlowMotion, Speed::Half),
+        (Hotkey::NormalSpeed, Speed::Normal),
+        (Hotkey::DoubleSpeed, Speed::Double),
+        (Hotkey::QuadrupleSpeed, Speed::Quadruple),
+        (Hotkey::Unthrottled, Speed::Unthrottled),
     ];
 
-    let mut last_frame: Instant = Instant::now();
+    let mut last_frame = vec![];
+    let mut last_held = 0;
+    let mut last_turbo = 0;
+    let mut last_rewinding = false;
+    let mut speed = Speed::Normal;
+    let mut fast_forward = false;
+    let mut slot = 0;
+    let mut cheat = 0;
 
     while window.is_open() {
-
-        if window.is_key_down(minifb::Key::Escape) {
+        if profile.pressed(&window, Hotkey::Quit, KeyRepeat::No) {
             break;
         }
 
-            // }
-        match frame_rx.try_recv() {
-            Ok(frame) => {
-                window.update_with_buffer(frame.as_slice(), 160, 144) .unwrap(); 
+        // Only the newest frame is shown when the emulation runs faster
+        // than the window updates
+        if let Some(frame) = frame_rx.try_iter().last() {
+            window.update_with_buffer(frame.as_slice(), width, height).unwrap();
+            last_frame = frame;
+        }
 
-                let fps = 1000 / last_frame.elapsed().as_millis();
-                print!("Updates each {}FPS   \r", fps);
-                last_frame = Instant::now();
+        if profile.pressed(&window, Hotkey::Screenshot, KeyRepeat::No) {
+            let path = screenshot::next_path(&save_dir, &rom_name);
+
+            match screenshot::save(&path, &last_frame, width, height) {
+                Ok(()) => println!("Saved {}", path.display()),
+                Err(e) => eprintln!("Can't save the screenshot '{}': {}", path.display(), e),
             }
-            _ => { } 
-        };
+        }
 
-        for (k, s) in &keys {
-            if window.is_key_down(*k) {
-                input_tx.send((*s, true)).unwrap();
-            } else {
-                input_tx.send((*s, false)).unwrap();
+        // Presses and releases of the gamepads reach the joypad along with
+        // the keyboard's, on the next frame
+        gamepads.poll();
+        let held = profile.held(&window) | gamepads.held();
+
+        if held != last_held {
+            input_tx.send(Input::Keys(held)).unwrap();
+            last_held = held;
+        }
+
+        let turbo = [(Hotkey::TurboA, JoypadKey::A), (Hotkey::TurboB, JoypadKey::B)].iter()
+            .filter(|(hotkey, _)| profile.down(&window, *hotkey))
+            .fold(0, |acc, (_, key)| acc | 1 << *key as u8);
+
+        if turbo != last_turbo {
+            input_tx.send(Input::Turbo(turbo)).unwrap();
+            last_turbo = turbo;
+        }
+
+        for (key, m) in profile.macros.iter() {
+            if window.is_key_pressed(*key, KeyRepeat::No) {
+                input_tx.send(Input::Macro(m.clone())).unwrap();
+            }
+        }
+
+        for (hotkey, new_speed) in speeds.iter() {
+            if profile.pressed(&window, *hotkey, KeyRepeat::No) {
+                speed = *new_speed;
+                input_tx.send(Input::Speed(speed)).unwrap();
             }
         }
 
+        // Runs unthrottled while held, then back to the chosen speed
+        if profile.down(&window, Hotkey::FastForward) != fast_forward {
+            fast_forward = !fast_forward;
+            let speed = if fast_forward { Speed::Unthrottled } else { speed };
+            input_tx.send(Input::Speed(speed)).unwrap();
+        }
+
+        if profile.pressed(&window, Hotkey::Pause, KeyRepeat::No) {
+            input_tx.send(Input::Pause).unwrap();
+        }
+
+        if profile.pressed(&window, Hotkey::FrameAdvance, KeyRepeat::Yes) {
+            input_tx.send(Input::Advance).unwrap();
+        }
+
+        let rewinding = profile.down(&window, Hotkey::Rewind);
+
+        if rewinding != last_rewinding {
+            input_tx.send(Input::Rewind(rewinding)).unwrap();
+            last_rewinding = rewinding;
+        }
+
+        if profile.pressed(&window, Hotkey::NextSlot, KeyRepeat::No) {
+            slot = (slot + 1) % STATE_SLOTS;
+            println!("State slot {}", slot);
+        }
+
+        if profile.pressed(&window, Hotkey::PreviousSlot, KeyRepeat::No) {
+            slot = (slot + STATE_SLOTS - 1) % STATE_SLOTS;
+            println!("State slot {}", slot);
+        }
+
+        // Cheats are turned on and off one at a time, and saved right away
+        if let Some(cheats) = &mut cheats {
+            let count = cheats.list().len();
+
+            if profile.pressed(&window, Hotkey::NextCheat, KeyRepeat::No) && count > 0 {
+                cheat = (cheat + 1) % count;
+                print_cheat(cheats, cheat);
+            }
+
+            if profile.pressed(&window, Hotkey::ToggleCheat, KeyRepeat::No) && count > 0 {
+                if movie {
+                    eprintln!("Cheats can't be changed during a movie");
+                } else {
+                    cheats.set_enabled(cheat, !cheats.list()[cheat].enabled);
+                    input_tx.send(Input::Cheats(cheats.clone())).unwrap();
+                    print_cheat(cheats, cheat);
+
+                    if let Err(e) = cheats.save(&cheats_path) {
+                        eprintln!("Can't save the cheats '{}': {}", cheats_path.display(), e);
+                    }
+                }
+            }
+        }
+
+        let state_path = save_dir.join(format!("{}.state{}", rom_name, slot));
+
+        if profile.pressed(&window, Hotkey::SaveState, KeyRepeat::No) {
+            input_tx.send(Input::SaveState(state_path.clone())).unwrap();
+        }
+
+        if profile.pressed(&window, Hotkey::LoadState, KeyRepeat::No) {
+            input_tx.send(Input::LoadState(state_path)).unwrap();
+        }
+
+        if profile.pressed(&window, Hotkey::SoftReset, KeyRepeat::No) {
+            input_tx.send(Input::Reset(ResetKind::Soft)).unwrap();
+        }
+
+        if profile.pressed(&window, Hotkey::HardReset, KeyRepeat::No) {
+            input_tx.send(Input::Reset(ResetKind::Hard)).unwrap();
+        }
+
         window.update();
     }
 
+    input_tx.send(Input::Quit).unwrap();
+    cpu_thread.join().unwrap();
+
     Ok(())
     }
 
@@ -0,0 +1,31 @@
+use std::fs::File;
+use std::io::BufWriter;
+use std::path::{Path, PathBuf};
+
+// Saves a frame, with pixels as 0x00RRGGBB, as a PNG file
+pub fn save(path: &Path, frame: &[u32], width: usize, height: usize) -> Result<(), Box<dyn std::error::Error>> {
+    if frame.len() != width * height {
+        return Err("No frame to save".into());
+    }
+
+    let mut encoder = png::Encoder::new(BufWriter::new(File::create(path)?), width as u32, height as u32);
+    encoder.set_color(png::ColorType::Rgb);
+    encoder.set_depth(png::BitDepth::Eight);
+
+    let mut data = Vec::with_capacity(frame.len() * 3);
+
+    for pixel in frame.iter() {
+        data.extend_from_slice(&[(pixel >> 16) as u8, (pixel >> 8) as u8, *pixel as u8]);
+    }
+
+    encoder.write_header()?.write_image_data(&data)?;
+    Ok(())
+}
+
+// First free '<name>-<n>.png' in the directory
+pub fn next_path(dir: &Path, name: &str) -> PathBuf {
+    (1..)
+        .map(|n| dir.join(format!("{}-{}.png", name, n)))
+        .find(|path| !path.exists())
+        .unwrap()
+}
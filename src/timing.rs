@@ -0,0 +1,273 @@
+use core::{CLOCK_FREQUENCY, FRAME_RATE};
+use std::thread;
+use std::time::{Duration, Instant};
+
+// Frames late before the timeline restarts instead of catching up
+const MAX_LAG_FRAMES: u32 = 4;
+
+// Length of the window the FPS and speed are measured on
+const REPORT_WINDOW: Duration = Duration::from_secs(1);
+
+// Largest change to the audio rate used to keep the buffer at its target
+const MAX_RATE_ADJUST: f64 = 0.005;
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum SyncMode {
+    // Frames follow a wall clock timeline at the refresh rate of the LCD
+    Video,
+    // Frames wait for the audio device to drain the queued samples
+    Audio,
+    // As fast as possible
+    Free,
+}
+
+impl SyncMode {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name.to_lowercase().as_str() {
+            "video" => Some(SyncMode::Video),
+            "audio" => Some(SyncMode::Audio),
+            "free" => Some(SyncMode::Free),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct Stats {
+    // Frames emulated per second
+    pub fps: f64,
+    // Emulated time against real time, 100 at full speed
+    pub speed: f64,
+}
+
+/// Paces the emulation and measures how fast it runs.
+///
+/// In video sync frames are scheduled on a fixed timeline, so sleeping a bit
+/// late on one frame is made up on the next and the rate doesn't drift. In
+/// audio sync the time to wait comes from the samples queued on the audio
+/// device, and `audio_rate` gives the resampling ratio that keeps the queue
+/// at its target. Without an audio queue reported, audio sync behaves like
+/// video sync.
+pub struct Pacer {
+    mode: SyncMode,
+    // None when unthrottled
+    speed: Option<f64>,
+    next_frame: Option<Instant>,
+    audio_target: Duration,
+    audio_queued: Option<Duration>,
+    window_start: Option<Instant>,
+    window_frames: u32,
+    window_ticks: u64,
+    stats: Option<Stats>,
+    report: bool,
+}
+
+impl Pacer {
+    pub fn new(mode: SyncMode) -> Self {
+        Self {
+            mode,
+            speed: Some(1.0),
+            next_frame: None,
+            audio_target: Duration::from_millis(50),
+            audio_queued: None,
+            window_start: None,
+            window_frames: 0,
+            window_ticks: 0,
+            stats: None,
+            report: false,
+        }
+    }
+
+    // Speed multiplier, none to run unthrottled
+    pub fn set_speed(&mut self, speed: Option<f64>) {
+        self.speed = speed.filter(|speed| *speed > 0.0);
+        self.reset();
+    }
+
+    // Restarts the timeline, after a pause for instance
+    pub fn reset(&mut self) {
+        self.next_frame = None;
+        self.window_start = None;
+        self.window_frames = 0;
+        self.window_ticks = 0;
+    }
+
+    // Amount of audio the device has yet to play, and how much it should
+    // ideally hold. Nothing reports it until sound is emulated.
+    #[allow(dead_code)]
+    pub fn set_audio_queued(&mut self, queued: Duration, target: Duration) {
+        self.audio_queued = Some(queued);
+        self.audio_target = target;
+    }
+
+    // Time a frame takes at the current speed
+    pub fn frame_time(&self) -> Option<Duration> {
+        self.speed.map(|speed| Duration::from_secs_f64(1.0 / (FRAME_RATE * speed)))
+    }
+
+    // Ratio to resample the audio by, slightly above 1 when the queue runs
+    // low and below 1 when it fills up
+    #[allow(dead_code)]
+    pub fn audio_rate(&self) -> f64 {
+        match self.audio_queued {
+            Some(queued) if self.audio_target > Duration::from_secs(0) => {
+                let error = 1.0 - queued.as_secs_f64() / self.audio_target.as_secs_f64();
+                1.0 + error.clamp(-1.0, 1.0) * MAX_RATE_ADJUST
+            }
+            _ => 1.0,
+        }
+    }
+
+    // Called after each frame with the ticks it ran. Returns how long to
+    // wait before the next one.
+    pub fn schedule(&mut self, now: Instant, ticks: u32) -> Duration {
+        self.measure(now, ticks);
+
+        let frame_time = match self.frame_time() {
+            Some(frame_time) if self.mode != SyncMode::Free => frame_time,
+            _ => {
+                self.next_frame = None;
+                return Duration::from_secs(0);
+            }
+        };
+
+        // Audio only paces at normal speed, the device plays at a fixed rate
+        if let (SyncMode::Audio, Some(queued)) = (self.mode, self.audio_queued) {
+            if self.speed == Some(1.0) {
+                self.next_frame = None;
+                return queued.checked_sub(self.audio_target).unwrap_or_default();
+            }
+        }
+
+        let next_frame = self.next_frame.unwrap_or(now) + frame_time;
+
+        if next_frame > now {
+            self.next_frame = Some(next_frame);
+            next_frame - now
+        } else {
+            // Too late to catch up without running frames in a burst
+            if now - next_frame > frame_time * MAX_LAG_FRAMES {
+                self.next_frame = Some(now);
+            } else {
+                self.next_frame = Some(next_frame);
+            }
+            Duration::from_secs(0)
+        }
+    }
+
+    // Schedules the next frame and sleeps until it's due
+    pub fn wait(&mut self, ticks: u32) {
+        let rest = self.schedule(Instant::now(), ticks);
+
+        if rest > Duration::from_secs(0) {
+            thread::sleep(rest);
+        }
+    }
+
+    fn measure(&mut self, now: Instant, ticks: u32) {
+        let start = *self.window_start.get_or_insert(now);
+        self.window_frames += 1;
+        self.window_ticks += ticks as u64;
+
+        let elapsed = now - start;
+
+        if elapsed >= REPORT_WINDOW {
+            let secs = elapsed.as_secs_f64();
+
+            self.stats = Some(Stats {
+                fps: self.window_frames as f64 / secs,
+                speed: self.window_ticks as f64 / (CLOCK_FREQUENCY as f64 * secs) * 100.0,
+            });
+            self.report = true;
+
+            self.window_start = Some(now);
+            self.window_frames = 0;
+            self.window_ticks = 0;
+        }
+    }
+
+    // Latest measurement, none until a full window ran
+    #[allow(dead_code)]
+    pub fn stats(&self) -> Option<Stats> {
+        self.stats
+    }
+
+    // Returns the latest measurement once
+    pub fn take_report(&mut self) -> Option<Stats> {
+        if self.report {
+            self.report = false;
+            self.stats
+        } else {
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use core::FRAME_TICKS;
+
+    fn ms(ms: u64) -> Duration {
+        Duration::from_millis(ms)
+    }
+
+    #[test]
+    fn video_sync_keeps_the_timeline() {
+        let mut pacer = Pacer::new(SyncMode::Video);
+        let frame_time = pacer.frame_time().unwrap();
+        let start = Instant::now();
+
+        assert_eq!(pacer.schedule(start, FRAME_TICKS), frame_time);
+
+        // A frame that ends late gets a shorter wait
+        let late = start + frame_time + ms(5);
+        assert_eq!(pacer.schedule(late, FRAME_TICKS), frame_time * 2 - (late - start));
+
+        // Far behind, the timeline restarts
+        let stalled = start + ms(500);
+        assert_eq!(pacer.schedule(stalled, FRAME_TICKS), Duration::from_secs(0));
+        assert_eq!(pacer.schedule(stalled, FRAME_TICKS), frame_time);
+
+        pacer.set_speed(Some(2.0));
+        assert_eq!(pacer.frame_time(), Some(Duration::from_secs_f64(1.0 / (FRAME_RATE * 2.0))));
+
+        pacer.set_speed(None);
+        assert_eq!(pacer.schedule(stalled, FRAME_TICKS), Duration::from_secs(0));
+    }
+
+    #[test]
+    fn audio_sync_follows_the_queue() {
+        let mut pacer = Pacer::new(SyncMode::Audio);
+        let now = Instant::now();
+
+        pacer.set_audio_queued(ms(80), ms(50));
+        assert_eq!(pacer.schedule(now, FRAME_TICKS), ms(30));
+        assert!(pacer.audio_rate() < 1.0);
+
+        pacer.set_audio_queued(ms(20), ms(50));
+        assert_eq!(pacer.schedule(now, FRAME_TICKS), Duration::from_secs(0));
+        assert!(pacer.audio_rate() > 1.0 && pacer.audio_rate() <= 1.0 + MAX_RATE_ADJUST);
+    }
+
+    #[test]
+    fn stats_need_a_full_window() {
+        let mut pacer = Pacer::new(SyncMode::Free);
+        let start = Instant::now();
+
+        // Frames ending at the same instant don't divide by zero
+        pacer.schedule(start, FRAME_TICKS);
+        pacer.schedule(start, FRAME_TICKS);
+        assert_eq!(pacer.take_report(), None);
+
+        for i in 1..=120 {
+            pacer.schedule(start + Duration::from_secs_f64(i as f64 / 120.0), FRAME_TICKS);
+        }
+
+        let stats = pacer.take_report().unwrap();
+        assert_eq!(stats.fps.round(), 122.0);
+        assert_eq!(stats.speed.round(), (122.0 / FRAME_RATE * 100.0_f64).round());
+        assert_eq!(pacer.take_report(), None);
+        assert_eq!(pacer.stats(), Some(stats));
+    }
+}